@@ -0,0 +1,241 @@
+// Compares two commit logs in Spike's format (spike --log-commits, or riscvellina's
+// --trace-format spike), and shows the first instruction where they differ.
+//
+// tracediff [--start addr] log1 log2
+//
+// Lines that aren't commits, like what the program prints, are skipped. So are CSR writes,
+// riscvellina doesn't log them. Exits with 0 if the logs match, 1 if they don't.
+
+use std::env::args;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Lines};
+use std::process::exit;
+
+struct Commit {
+    // Line number in the file, and the line itself
+    line: usize,
+    text: String,
+    privilege: String,
+    pc: u64,
+    instr: String,
+    // x5 0x..., f1 0x..., mem 0x... [0x...]
+    records: Vec<String>
+}
+
+fn parse_hex(value: &str) -> Option<u64> {
+    u64::from_str_radix(value.trim_start_matches("0x"), 16).ok()
+}
+
+// core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000
+fn parse_commit(line: usize, text: &str) -> Option<Commit> {
+    let mut tokens = text.split_whitespace().peekable();
+
+    if tokens.next()? != "core" {
+        return None;
+    }
+
+    // The core number can be stuck to its colon or not
+    if !tokens.next()?.ends_with(':') && tokens.next()? != ":" {
+        return None;
+    }
+
+    let privilege = tokens.next()?.to_string();
+    let pc = parse_hex(tokens.next()?)?;
+    let instr = tokens.next()?.trim_matches(|c| c == '(' || c == ')').to_lowercase();
+
+    let mut records = vec![];
+    while let Some(token) = tokens.next() {
+        let kind = token.chars().next()?;
+        // Not on a character boundary if the line is something else
+        let number = token.get(1..)?;
+
+        if token == "mem" {
+            let addr = parse_hex(tokens.next()?)?;
+
+            // Stores have a value, loads don't
+            match tokens.peek() {
+                Some(value) if value.starts_with("0x") => {
+                    let value = tokens.next()?.to_lowercase();
+                    records.push(format!("mem {:016x} {}", addr, value));
+                }
+                _ => records.push(format!("mem {:016x}", addr))
+            }
+        } else if kind == 'c' && number.starts_with(|c: char| c.is_ascii_digit()) {
+            tokens.next();
+        } else if (kind == 'x' || kind == 'f') && number.parse::<u32>().is_ok() {
+            let value = parse_hex(tokens.next()?)?;
+            records.push(format!("{} {:016x}", token, value));
+        } else {
+            records.push(token.to_string());
+        }
+    }
+
+    Some(Commit { line, text: text.trim_end().to_string(), privilege, pc, instr, records })
+}
+
+struct Log {
+    lines: Lines<BufReader<File>>,
+    line: usize
+}
+
+impl Log {
+    fn open(path: &str) -> io::Result<Self> {
+        Ok(Self { lines: BufReader::new(File::open(path)?).lines(), line: 0 })
+    }
+
+    fn next_commit(&mut self) -> io::Result<Option<Commit>> {
+        for text in &mut self.lines {
+            let text = text?;
+            self.line += 1;
+
+            if let Some(commit) = parse_commit(self.line, &text) {
+                return Ok(Some(commit));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Skips what comes before the first commit at pc
+    fn skip_to(&mut self, pc: u64) -> io::Result<Option<Commit>> {
+        while let Some(commit) = self.next_commit()? {
+            if commit.pc == pc {
+                return Ok(Some(commit));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+// What's different between two commits, None if they're the same
+fn difference(a: &Commit, b: &Commit) -> Option<&'static str> {
+    if a.pc != b.pc {
+        Some("pc")
+    } else if a.instr != b.instr {
+        Some("instruction")
+    } else if a.privilege != b.privilege {
+        Some("privilege mode")
+    } else if a.records != b.records {
+        Some("registers or memory")
+    } else {
+        None
+    }
+}
+
+fn compare(paths: &[String], start: Option<u64>) -> io::Result<bool> {
+    let mut first = Log::open(&paths[0])?;
+    let mut second = Log::open(&paths[1])?;
+
+    let (mut a, mut b) = match start {
+        Some(start) => (first.skip_to(start)?, second.skip_to(start)?),
+        None => (first.next_commit()?, second.next_commit()?)
+    };
+
+    let mut count = 0;
+    let mut previous: Option<Commit> = None;
+
+    loop {
+        match (a, b) {
+            (None, None) => {
+                println!("The logs match, {} instructions", count);
+                return Ok(true);
+            }
+            (Some(a), Some(b)) => {
+                if let Some(what) = difference(&a, &b) {
+                    println!("The logs differ at instruction {} ({})", count + 1, what);
+                    if let Some(previous) = previous {
+                        println!("after   {}", previous.text);
+                    }
+                    println!("{}:{}: {}", paths[0], a.line, a.text);
+                    println!("{}:{}: {}", paths[1], b.line, b.text);
+
+                    return Ok(false);
+                }
+
+                previous = Some(a);
+            }
+            (Some(a), None) => {
+                println!("{} ends after {} instructions, {} goes on", paths[1], count, paths[0]);
+                println!("{}:{}: {}", paths[0], a.line, a.text);
+
+                return Ok(false);
+            }
+            (None, Some(b)) => {
+                println!("{} ends after {} instructions, {} goes on", paths[0], count, paths[1]);
+                println!("{}:{}: {}", paths[1], b.line, b.text);
+
+                return Ok(false);
+            }
+        }
+
+        count += 1;
+        a = first.next_commit()?;
+        b = second.next_commit()?;
+    }
+}
+
+fn main() {
+    let mut paths = vec![];
+    let mut start = None;
+
+    let mut args = args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--start" => start = Some(args.next().as_deref().and_then(parse_hex)
+                .expect("--start needs a hexadecimal address")),
+            _ => paths.push(arg)
+        }
+    }
+
+    if paths.len() != 2 {
+        eprintln!("usage: tracediff [--start addr] log1 log2");
+        exit(2);
+    }
+
+    match compare(&paths, start) {
+        Ok(true) => exit(0),
+        Ok(false) => exit(1),
+        Err(error) => {
+            eprintln!("Can't read the logs: {}", error);
+            exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit(text: &str) -> Commit {
+        parse_commit(1, text).unwrap()
+    }
+
+    #[test]
+    fn parsing() {
+        let store = commit("core   0: 3 0x0000000080000004 (0x0062A023) x6  0x5 mem 0x80001000 0x00000005");
+        assert_eq!((store.privilege.as_str(), store.pc, store.instr.as_str()), ("3", 0x80000004, "0x0062a023"));
+        assert_eq!(store.records, vec!["x6 0000000000000005", "mem 0000000080001000 0x00000005"]);
+
+        // Spike puts the core number and its colon together or not, and logs CSR writes
+        let load = commit("core 0 : 1 0x80000008 (0x4501) c768_mstatus 0x8 mem 0x0000000080001000");
+        assert_eq!(load.records, vec!["mem 0000000080001000"]);
+
+        // Whatever else is in the log
+        assert!(parse_commit(1, "bbl loader").is_none());
+        assert!(parse_commit(1, "core   0: exception trap_illegal_instruction, epc 0x80000000").is_none());
+        assert!(parse_commit(1, "core   0: 3 0x80000000 (0x00000013) é").is_none());
+        assert!(parse_commit(1, "core   0: 3 0x80000000 (0x00000013) x5").is_none());
+    }
+
+    #[test]
+    fn differences() {
+        let a = commit("core   0: 3 0x0000000080000004 (0x0062a023) x6  0x0000000000000005");
+
+        assert_eq!(difference(&a, &commit("core   0: 3 0x80000004 (0x0062a023) x6 0x5")), None);
+        assert_eq!(difference(&a, &commit("core   0: 3 0x80000008 (0x0062a023) x6 0x5")), Some("pc"));
+        assert_eq!(difference(&a, &commit("core   0: 3 0x80000004 (0x0062a013) x6 0x5")), Some("instruction"));
+        assert_eq!(difference(&a, &commit("core   0: 1 0x80000004 (0x0062a023) x6 0x5")), Some("privilege mode"));
+        assert_eq!(difference(&a, &commit("core   0: 3 0x80000004 (0x0062a023) x6 0x6")), Some("registers or memory"));
+    }
+}
@@ -1,84 +1,564 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use crate::devices::Device;
+use crate::devices::clint::{self, Clint};
+use crate::devices::dram::{self, Dram, Memory};
+use crate::devices::plic::{self, Plic};
+use crate::devices::rom::{self, Rom};
+use crate::devices::syscon::{self, PowerState, Syscon};
+use crate::devices::uart::{self, Uart};
+use crate::devices::virtio::{self, VirtioMmio};
+use crate::devices::virtio_blk::VirtioBlk;
+use crate::fdt::Fdt;
+use crate::trap::Exception;
+
 pub type BusSize = u64;
 
-#[derive(Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // Both
+    Access
+}
+
+// Stops the program when it accesses [addr, addr + size) of physical memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: BusSize,
+    pub size: BusSize,
+    pub kind: WatchKind
+}
+
+// The access that hit a watchpoint. Old values are only known for DRAM, reading them from
+// devices could change their state.
+#[derive(Debug, Clone, Copy)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    pub addr: BusSize,
+    pub size: BusSize,
+    pub write: bool,
+    pub old: Option<u64>,
+    pub new: u64
+}
+
+// A device and the range of physical addresses it answers to
+struct Region {
+    base: BusSize,
+    size: BusSize,
+    // PLIC source the device's interrupt line is wired to
+    irq: Option<u32>,
+    device: Box<dyn Device>
+}
+
+impl Region {
+    // The whole access has to fit in the region
+    fn contains(&self, addr: BusSize, size: BusSize) -> bool {
+        addr >= self.base && addr - self.base < self.size && size <= self.size - (addr - self.base)
+    }
+}
+
 pub struct Bus {
-    dram: Vec<u8>
+    regions: Vec<Region>,
+    // Index of the region of the last access, most accesses hit the same one (DRAM) again
+    last_region: usize,
+    // Doubleword reserved by the last LR
+    reservation: Option<BusSize>,
+    power: Rc<Cell<PowerState>>,
+    // Also handed to the devices that access guest memory directly
+    memory: Memory,
+    watchpoints: Vec<Watchpoint>,
+    // First hit since the debugger last looked
+    watch_hit: Option<WatchHit>
 }
 
 impl Bus {
     pub fn new(dram_size: usize) -> Bus {
-        Bus {
-            dram: vec![0; dram_size]
+        let power = Rc::new(Cell::new(PowerState::Running));
+        let memory = Memory::new(dram_size);
+        let mut bus = Bus {
+            regions: vec![],
+            last_region: 0,
+            reservation: None,
+            power: power.clone(),
+            memory: memory.clone(),
+            watchpoints: vec![],
+            watch_hit: None
+        };
+
+        // Those can't overlap
+        bus.attach(rom::ROM_BASE, rom::ROM_SIZE, None, Box::new(Rom::new())).unwrap();
+        bus.attach(dram::DRAM_BASE, memory.size(), None, Box::new(Dram::new(memory))).unwrap();
+        bus.attach(syscon::SYSCON_BASE, syscon::SYSCON_SIZE, None, Box::new(Syscon::new(power))).unwrap();
+        bus.attach(clint::CLINT_BASE, clint::CLINT_SIZE, None, Box::new(Clint::new())).unwrap();
+        bus.attach(plic::PLIC_BASE, plic::PLIC_SIZE, None, Box::new(Plic::new())).unwrap();
+        bus.attach(uart::UART_BASE, uart::UART_SIZE, Some(uart::UART_IRQ), Box::new(Uart::new())).unwrap();
+
+        bus
+    }
+
+    // Maps a device at [base, base + size), fails if it overlaps with another device
+    pub fn attach(&mut self, base: BusSize, size: BusSize, irq: Option<u32>, device: Box<dyn Device>)
+        -> Result<(), String> {
+        let end = base.checked_add(size).ok_or(format!("{} at {:x} doesn't fit in memory", device.name(), base))?;
+
+        if let Some(other) = self.regions.iter().find(|r| base < r.base + r.size && r.base < end) {
+            return Err(format!("{} at {:x}..{:x} overlaps with {} at {:x}..{:x}",
+                device.name(), base, end, other.device.name(), other.base, other.base + other.size));
+        }
+
+        self.regions.push(Region { base, size, irq, device });
+        Ok(())
+    }
+
+    // Puts a disk behind the virtio-mmio transport, with the legacy or the modern interface
+    pub fn attach_disk(&mut self, disk: VirtioBlk, legacy: bool) -> Result<(), String> {
+        let device = VirtioMmio::new(disk, self.memory.clone(), legacy);
+        self.attach(virtio::VIRTIO_BASE, virtio::VIRTIO_SIZE, Some(virtio::VIRTIO_IRQ), Box::new(device))
+    }
+
+    pub fn power_state(&self) -> PowerState {
+        self.power.get()
+    }
+
+    pub fn reset(&mut self) {
+        self.reservation = None;
+        self.power.set(PowerState::Running);
+
+        for region in self.regions.iter_mut() {
+            region.device.reset();
         }
     }
 
-    pub fn load_code(&mut self, value: Vec<u8>) {
-        self.dram.splice(..value.len(), value.iter().cloned());
+    pub fn tick(&mut self) {
+        let mut lines = 0;
+
+        for region in self.regions.iter_mut() {
+            region.device.tick();
+
+            if let Some(irq) = region.irq {
+                if region.device.interrupt() {
+                    lines |= 1 << irq;
+                }
+            }
+        }
+
+        for region in self.regions.iter_mut() {
+            region.device.set_interrupt_lines(lines);
+        }
     }
 
-    pub fn load8(&mut self, addr: BusSize) -> u8 {
-        match addr {
-            0x80000000..=0xFFFFFFFF => self.dram[(addr - 0x80000000) as usize],
-            _ => panic!("Bad memory access (Tried to load {:08x}", addr)
+    // The memory node, and the devices under /soc
+    pub fn device_tree(&self, fdt: &mut Fdt) {
+        fdt.begin_node(&format!("memory@{:x}", dram::DRAM_BASE));
+        fdt.property_string("device_type", "memory");
+        fdt.property_reg(dram::DRAM_BASE, self.memory.size());
+        fdt.end_node();
+
+        fdt.begin_node("soc");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_string("compatible", "simple-bus");
+        fdt.property_empty("ranges");
+
+        for region in self.regions.iter() {
+            region.device.device_tree(fdt, region.base, region.size, region.irq);
         }
+
+        fdt.end_node();
     }
 
-    pub fn load16(&mut self, addr: BusSize) -> u16 {
-        (self.load8(addr) as u16) |
-        (self.load8(addr + 1) as u16) << 8
+    pub fn local_interrupts(&self) -> u64 {
+        self.regions.iter().fold(0, |bits, region| bits | region.device.local_interrupts())
     }
 
-    pub fn load32(&mut self, addr: BusSize) -> u32 {
-        (self.load8(addr) as u32) |
-        (self.load8(addr + 1) as u32) << 8 |
-        (self.load8(addr + 2) as u32) << 16 |
-        (self.load8(addr + 3) as u32) << 24
+    // 0 if there's no timer on the bus
+    pub fn mtime(&self) -> u64 {
+        self.regions.iter().find_map(|region| region.device.mtime()).unwrap_or(0)
     }
 
-    pub fn load64(&mut self, addr: BusSize) -> u64 {
-        (self.load8(addr) as u64) |
-        (self.load8(addr + 1) as u64) << 8 |
-        (self.load8(addr + 2) as u64) << 16 |
-        (self.load8(addr + 3) as u64) << 24 |
-        (self.load8(addr + 4) as u64) << 32 |
-        (self.load8(addr + 5) as u64) << 40 |
-        (self.load8(addr + 6) as u64) << 48 |
-        (self.load8(addr + 7) as u64) << 56
+    pub fn reserve(&mut self, addr: BusSize) {
+        self.reservation = Some(addr & !0x7);
     }
 
-    pub fn store8(&mut self, addr: BusSize, value: u8) {
-        match addr {
-            0x80000000..=BusSize::MAX => { self.dram[(addr - 0x80000000) as usize] = value; },
-            _ => panic!("Bad memory access")
+    // Used by SC, the reservation is lost whether it succeeds or not
+    pub fn take_reservation(&mut self, addr: BusSize) -> bool {
+        self.reservation.take() == Some(addr & !0x7)
+    }
+
+    // Copies a buffer to memory, it has to fit in a single device
+    pub fn write_bytes(&mut self, addr: BusSize, data: &[u8]) -> Result<(), Exception> {
+        let region = self.region(addr, data.len() as BusSize).ok_or(Exception::StoreAccessFault(addr))?;
+        let offset = addr - region.base;
+
+        if region.device.write_bytes(offset, data) {
+            Ok(())
+        } else {
+            Err(Exception::StoreAccessFault(addr))
         }
     }
 
-    pub fn store16(&mut self, addr: BusSize, value: u16) {
-        self.store8(addr, value as u8);
-        self.store8(addr, (value >> 8) as u8);
+    fn region(&mut self, addr: BusSize, size: BusSize) -> Option<&mut Region> {
+        if !self.regions.get(self.last_region).is_some_and(|region| region.contains(addr, size)) {
+            self.last_region = self.regions.iter().position(|region| region.contains(addr, size))?;
+        }
+
+        Some(&mut self.regions[self.last_region])
     }
 
-    pub fn store32(&mut self, addr: BusSize, value: u32) {
-        self.store8(addr, value as u8);
-        self.store8(addr + 1, (value >> 8) as u8);
-        self.store8(addr + 2, (value >> 16) as u8);
-        self.store8(addr + 3, (value >> 24) as u8);
+    fn read(&mut self, addr: BusSize, size: BusSize) -> Result<u64, Exception> {
+        let region = self.region(addr, size).ok_or(Exception::LoadAccessFault(addr))?;
+        let offset = addr - region.base;
+
+        region.device.read(offset, size).ok_or(Exception::LoadAccessFault(addr))
+    }
+
+    fn write(&mut self, addr: BusSize, size: BusSize, value: u64) -> Result<(), Exception> {
+        // Any store to the reserved doubleword invalidates it. Inclusive ends, so that nothing
+        // overflows at the top of the address space.
+        if let Some(reservation) = self.reservation {
+            if addr <= reservation + 7 && reservation <= addr.saturating_add(size - 1) {
+                self.reservation = None;
+            }
+        }
+
+        let region = self.region(addr, size).ok_or(Exception::StoreAccessFault(addr))?;
+        let offset = addr - region.base;
+
+        if region.device.write(offset, size, value) {
+            Ok(())
+        } else {
+            Err(Exception::StoreAccessFault(addr))
+        }
     }
 
-    pub fn store64(&mut self, addr: BusSize, value: u64) {
-        self.store8(addr, value as u8);
-        self.store8(addr + 1, (value >> 8) as u8);
-        self.store8(addr + 2, (value >> 16) as u8);
-        self.store8(addr + 3, (value >> 24) as u8);
-        self.store8(addr + 4, (value >> 32) as u8);
-        self.store8(addr + 5, (value >> 40) as u8);
-        self.store8(addr + 6, (value >> 48) as u8);
-        self.store8(addr + 7, (value >> 56) as u8);
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        match self.watchpoints.iter().position(|&other| other == watchpoint) {
+            Some(index) => { self.watchpoints.remove(index); true }
+            None => false
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    fn watchpoint(&self, addr: BusSize, size: BusSize, write: bool) -> Option<Watchpoint> {
+        self.watchpoints.iter().copied().find(|watchpoint| {
+            let kind = match watchpoint.kind {
+                WatchKind::Read => !write,
+                WatchKind::Write => write,
+                WatchKind::Access => true
+            };
+
+            // Inclusive ends, so that nothing overflows at the top of the address space
+            kind && watchpoint.size > 0 && addr <= watchpoint.addr.saturating_add(watchpoint.size - 1)
+                && watchpoint.addr <= addr.saturating_add(size - 1)
+        })
+    }
+
+    fn hit(&mut self, watchpoint: Watchpoint, addr: BusSize, size: BusSize, write: bool, old: Option<u64>, new: u64) {
+        if self.watch_hit.is_none() {
+            self.watch_hit = Some(WatchHit { watchpoint, addr, size, write, old, new });
+        }
+    }
+
+    // The program's loads and stores, the only accesses watchpoints see. Instruction fetches,
+    // page table walks and the debuggers use the ones below.
+    pub fn load(&mut self, addr: BusSize, size: BusSize) -> Result<u64, Exception> {
+        let value = self.read(addr, size)?;
+
+        if let Some(watchpoint) = self.watchpoint(addr, size, false) {
+            self.hit(watchpoint, addr, size, false, Some(value), value);
+        }
+
+        Ok(value)
+    }
+
+    pub fn store(&mut self, addr: BusSize, size: BusSize, value: u64) -> Result<(), Exception> {
+        let watchpoint = self.watchpoint(addr, size, true);
+
+        let mut old = [0; 8];
+        let old = match watchpoint {
+            Some(_) if self.memory.read(addr, &mut old[..size as usize]) => Some(u64::from_le_bytes(old)),
+            _ => None
+        };
+
+        self.write(addr, size, value)?;
+
+        if let Some(watchpoint) = watchpoint {
+            let mask = if size == 8 { u64::MAX } else { (1 << (8 * size)) - 1 };
+            self.hit(watchpoint, addr, size, true, old, value & mask);
+        }
+
+        Ok(())
+    }
+
+    // For the debuggers: only memory, since reading or writing a device register can have side
+    // effects, like popping a byte from the UART's FIFO
+    pub fn debug_load8(&mut self, addr: BusSize) -> Result<u8, Exception> {
+        match self.region(addr, 1) {
+            Some(region) if region.device.is_memory() => self.load8(addr),
+            _ => Err(Exception::LoadAccessFault(addr))
+        }
+    }
+
+    pub fn debug_store8(&mut self, addr: BusSize, value: u8) -> Result<(), Exception> {
+        match self.region(addr, 1) {
+            Some(region) if region.device.is_memory() => self.store8(addr, value),
+            _ => Err(Exception::StoreAccessFault(addr))
+        }
+    }
+
+    pub fn load8(&mut self, addr: BusSize) -> Result<u8, Exception> {
+        self.read(addr, 1).map(|value| value as u8)
+    }
+
+    pub fn load16(&mut self, addr: BusSize) -> Result<u16, Exception> {
+        self.read(addr, 2).map(|value| value as u16)
+    }
+
+    pub fn load64(&mut self, addr: BusSize) -> Result<u64, Exception> {
+        self.read(addr, 8)
+    }
+
+    pub fn store8(&mut self, addr: BusSize, value: u8) -> Result<(), Exception> {
+        self.write(addr, 1, value as u64)
+    }
+
+    pub fn store64(&mut self, addr: BusSize, value: u64) -> Result<(), Exception> {
+        self.write(addr, 8, value)
     }
 }
 
 impl std::fmt::Debug for Bus {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Bus {{ DRAM[{:x}o] }}", self.dram.len())
+        let regions: Vec<String> = self.regions.iter()
+            .map(|r| format!("{}@{:x}..{:x}{}", r.device.name(), r.base, r.base + r.size,
+                if r.device.interrupt() { " (irq)" } else { "" }))
+            .collect();
+
+        write!(f, "Bus {{ {} }}", regions.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::csr::MIP_MEIP;
+
+    // Remembers the last access, reads back the offset
+    type Access = Rc<Cell<Option<(u64, u64)>>>;
+
+    struct Probe {
+        last: Access
+    }
+
+    impl Device for Probe {
+        fn name(&self) -> &'static str {
+            "probe"
+        }
+
+        fn read(&mut self, offset: u64, size: u64) -> Option<u64> {
+            self.last.set(Some((offset, size)));
+            Some(offset)
+        }
+
+        fn write(&mut self, offset: u64, size: u64, _value: u64) -> bool {
+            self.last.set(Some((offset, size)));
+            size == 4
+        }
+    }
+
+    fn probe() -> (Box<Probe>, Access) {
+        let last = Rc::new(Cell::new(None));
+        (Box::new(Probe { last: last.clone() }), last)
+    }
+
+    #[test]
+    fn memory_map() {
+        let mut bus = Bus::new(0x1000);
+        let (device, last) = probe();
+        bus.attach(0x2000_0000, 0x100, None, device).unwrap();
+
+        // Accesses get the offset in the device and their width
+        assert_eq!(bus.load16(0x2000_0010), Ok(0x10));
+        assert_eq!(last.get(), Some((0x10, 2)));
+        assert_eq!(bus.store(0x2000_00FC, 4, 0), Ok(()));
+        assert_eq!(bus.store8(0x2000_00FC, 0), Err(Exception::StoreAccessFault(0x2000_00FC)));
+
+        // Nothing there, or not all of the access is
+        assert_eq!(bus.load(0x2000_0100, 4), Err(Exception::LoadAccessFault(0x2000_0100)));
+        assert_eq!(bus.load64(0x2000_00FC), Err(Exception::LoadAccessFault(0x2000_00FC)));
+        assert_eq!(bus.load64(0x80000FFC), Err(Exception::LoadAccessFault(0x80000FFC)));
+        assert_eq!(bus.store64(u64::MAX - 3, 0), Err(Exception::StoreAccessFault(u64::MAX - 3)));
+
+        bus.store64(0x80000FF8, 0x0123_4567_89AB_CDEF).unwrap();
+        assert_eq!(bus.load(0x80000FFC, 4), Ok(0x0123_4567));
+        assert_eq!(bus.load8(0x80000FF8), Ok(0xEF));
+    }
+
+    #[test]
+    fn region_cache() {
+        let mut bus = Bus::new(0x1000);
+        let (device, last) = probe();
+        bus.attach(0x2000_0000, 0x100, None, device).unwrap();
+
+        // Going back and forth between regions always gets the right one
+        bus.store(0x80000000, 4, 0x1234).unwrap();
+        assert_eq!(bus.load(0x2000_0004, 4), Ok(4));
+        assert_eq!(bus.load(0x80000000, 4), Ok(0x1234));
+        assert_eq!(last.get(), Some((4, 4)));
+        assert_eq!(bus.load8(0x2000_00FF), Ok(0xFF));
+
+        // Including when the access is outside of the one that was used last
+        assert_eq!(bus.load16(0x2000_0100), Err(Exception::LoadAccessFault(0x2000_0100)));
+        assert_eq!(bus.load16(0x80000000), Ok(0x1234));
+    }
+
+    // An interrupt line the test controls
+    struct Line(Rc<Cell<bool>>);
+
+    impl Device for Line {
+        fn name(&self) -> &'static str {
+            "line"
+        }
+
+        fn read(&mut self, _offset: u64, _size: u64) -> Option<u64> {
+            None
+        }
+
+        fn write(&mut self, _offset: u64, _size: u64, _value: u64) -> bool {
+            false
+        }
+
+        fn interrupt(&self) -> bool {
+            self.0.get()
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn interrupt_routing() {
+        let mut bus = Bus::new(0x1000);
+        let line = Rc::new(Cell::new(false));
+        bus.attach(0x2000_0000, 0x100, Some(5), Box::new(Line(line.clone()))).unwrap();
+
+        // Source 5 enabled for M-mode in the PLIC
+        bus.store(plic::PLIC_BASE + 5 * 4, 4, 1).unwrap();
+        bus.store(plic::PLIC_BASE + 0x2000, 4, 1 << 5).unwrap();
+
+        bus.tick();
+        assert_eq!(bus.local_interrupts() & MIP_MEIP, 0);
+        line.set(true);
+        bus.tick();
+        assert_eq!(bus.local_interrupts() & MIP_MEIP, MIP_MEIP);
+        assert_eq!(bus.load(plic::PLIC_BASE + 0x200004, 4), Ok(5));
+    }
+
+    #[test]
+    fn attach() {
+        let mut bus = Bus::new(0x1000);
+
+        assert!(bus.attach(0x80000800, 0x1000, None, probe().0).is_err());
+        assert!(bus.attach(0x7FFFF000, 0x1001, None, probe().0).is_err());
+        assert!(bus.attach(u64::MAX - 0xFF, 0x200, None, probe().0).is_err());
+
+        // Right next to another device is fine
+        assert!(bus.attach(0x7FFFF000, 0x1000, None, probe().0).is_ok());
+        assert!(bus.attach(0x80001000, 0x1000, None, probe().0).is_ok());
+    }
+
+    #[test]
+    fn debug_accesses() {
+        let mut bus = Bus::new(0x1000);
+        let (device, last) = probe();
+        bus.attach(0x2000_0000, 0x100, None, device).unwrap();
+
+        bus.debug_store8(0x80000001, 0xAB).unwrap();
+        assert_eq!(bus.debug_load8(0x80000001), Ok(0xAB));
+
+        // Devices are never touched
+        assert_eq!(bus.debug_load8(0x2000_0004), Err(Exception::LoadAccessFault(0x2000_0004)));
+        assert_eq!(bus.debug_store8(0x2000_0004, 0), Err(Exception::StoreAccessFault(0x2000_0004)));
+        assert_eq!(last.get(), None);
+        assert_eq!(bus.debug_load8(0x1000_0000), Err(Exception::LoadAccessFault(0x1000_0000)));
+    }
+
+    #[test]
+    fn reservations() {
+        let mut bus = Bus::new(0x1000);
+
+        // Only a store that overlaps the reserved doubleword breaks it
+        bus.reserve(0x80000010);
+        bus.store64(0x80000008, 0).unwrap();
+        bus.store8(0x80000018, 0).unwrap();
+        assert!(bus.take_reservation(0x80000014));
+        assert!(!bus.take_reservation(0x80000010));
+
+        bus.reserve(0x80000010);
+        bus.store(0x80000016, 2, 0).unwrap();
+        assert!(!bus.take_reservation(0x80000010));
+
+        // At the very top of the address space
+        bus.reserve(u64::MAX);
+        assert!(bus.store8(u64::MAX, 0).is_err());
+        assert!(!bus.take_reservation(u64::MAX));
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut bus = Bus::new(0x1000);
+        let (device, _) = probe();
+        bus.attach(0x2000_0000, 0x100, None, device).unwrap();
+
+        let write = Watchpoint { addr: 0x80000010, size: 4, kind: WatchKind::Write };
+        let read = Watchpoint { addr: 0x2000_0000, size: 1, kind: WatchKind::Read };
+        bus.add_watchpoint(write);
+        bus.add_watchpoint(read);
+
+        // Overlapping is enough, the old value comes from DRAM
+        bus.store64(0x80000008, 0x1122_3344_5566_7788).unwrap();
+        assert!(bus.take_watch_hit().is_none());
+        bus.store(0x80000012, 4, 0xAABB_CCDD).unwrap();
+        let hit = bus.take_watch_hit().unwrap();
+        assert_eq!((hit.watchpoint, hit.addr, hit.size, hit.write, hit.old, hit.new), (write, 0x80000012, 4, true, Some(0), 0xAABB_CCDD));
+        bus.load(0x80000010, 8).unwrap();
+        assert!(bus.take_watch_hit().is_none());
+
+        bus.load(0x2000_0000, 4).unwrap();
+        let hit = bus.take_watch_hit().unwrap();
+        assert_eq!((hit.watchpoint, hit.old, hit.new), (read, Some(0), 0));
+
+        // Only accesses that went through count
+        assert!(bus.store(0x2000_0000, 1, 0).is_err());
+        assert!(bus.remove_watchpoint(read));
+        assert!(!bus.remove_watchpoint(read));
+        bus.add_watchpoint(Watchpoint { addr: 0x2000_0000, size: 1, kind: WatchKind::Access });
+        assert!(bus.store(0x2000_0000, 1, 0).is_err());
+        assert!(bus.take_watch_hit().is_none());
+    }
+
+    #[test]
+    fn watchpoints_at_the_top() {
+        let mut bus = Bus::new(0x1000);
+        let watchpoint = Watchpoint { addr: u64::MAX - 3, size: 0x10, kind: WatchKind::Access };
+        bus.add_watchpoint(watchpoint);
+
+        assert_eq!(bus.watchpoint(u64::MAX, 1, false), Some(watchpoint));
+        assert_eq!(bus.watchpoint(u64::MAX - 1, 8, true), Some(watchpoint));
+        assert_eq!(bus.watchpoint(u64::MAX - 7, 4, true), None);
+        assert_eq!(bus.watchpoint(0, 8, false), None);
+
+        // Nothing is mapped there
+        assert!(bus.store(u64::MAX, 1, 0).is_err());
+        assert!(bus.take_watch_hit().is_none());
+    }
+}
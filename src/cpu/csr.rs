@@ -0,0 +1,420 @@
+use super::mmu;
+
+// Floating-point CSRs
+pub const FFLAGS: u16 = 0x001;
+pub const FRM: u16 = 0x002;
+pub const FCSR: u16 = 0x003;
+
+// Supervisor trap setup
+pub const SSTATUS: u16 = 0x100;
+pub const SIE: u16 = 0x104;
+pub const STVEC: u16 = 0x105;
+pub const SCOUNTEREN: u16 = 0x106;
+
+// Supervisor trap handling
+pub const SSCRATCH: u16 = 0x140;
+pub const SEPC: u16 = 0x141;
+pub const SCAUSE: u16 = 0x142;
+pub const STVAL: u16 = 0x143;
+pub const SIP: u16 = 0x144;
+
+// Supervisor protection and translation
+pub const SATP: u16 = 0x180;
+
+// Machine information registers
+pub const MVENDORID: u16 = 0xF11;
+pub const MARCHID: u16 = 0xF12;
+pub const MIMPID: u16 = 0xF13;
+pub const MHARTID: u16 = 0xF14;
+pub const MCONFIGPTR: u16 = 0xF15;
+
+// Machine trap setup
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MEDELEG: u16 = 0x302;
+pub const MIDELEG: u16 = 0x303;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MCOUNTEREN: u16 = 0x306;
+
+// Machine trap handling
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+
+// Machine memory protection
+pub const PMPCFG0: u16 = 0x3A0;
+pub const PMPCFG15: u16 = 0x3AF;
+pub const PMPADDR0: u16 = 0x3B0;
+pub const PMPADDR63: u16 = 0x3EF;
+
+// Machine counters
+pub const MCYCLE: u16 = 0xB00;
+pub const MINSTRET: u16 = 0xB02;
+
+// Unprivileged counters (read-only shadows of the machine ones, time is the CLINT's mtime)
+pub const CYCLE: u16 = 0xC00;
+pub const TIME: u16 = 0xC01;
+pub const INSTRET: u16 = 0xC02;
+
+// mstatus fields
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP: u64 = 3 << 11;
+pub const MSTATUS_FS: u64 = 3 << 13;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM: u64 = 1 << 18;
+pub const MSTATUS_MXR: u64 = 1 << 19;
+pub const MSTATUS_TVM: u64 = 1 << 20;
+pub const MSTATUS_TW: u64 = 1 << 21;
+pub const MSTATUS_TSR: u64 = 1 << 22;
+const MSTATUS_UXL: u64 = 3 << 32;
+const MSTATUS_SD: u64 = 1 << 63;
+
+const MSTATUS_WRITABLE: u64 = MSTATUS_SIE | MSTATUS_MIE | MSTATUS_SPIE | MSTATUS_MPIE | MSTATUS_SPP |
+    MSTATUS_MPP | MSTATUS_FS | MSTATUS_MPRV | MSTATUS_SUM | MSTATUS_MXR | MSTATUS_TVM | MSTATUS_TW | MSTATUS_TSR;
+// sstatus is a restricted view of mstatus
+const SSTATUS_VISIBLE: u64 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_FS | MSTATUS_SUM | MSTATUS_MXR |
+    MSTATUS_UXL | MSTATUS_SD;
+const SSTATUS_WRITABLE: u64 = SSTATUS_VISIBLE & !(MSTATUS_UXL | MSTATUS_SD);
+
+// mie/mip fields
+pub const MIP_SSIP: u64 = 1 << 1;
+pub const MIP_MSIP: u64 = 1 << 3;
+pub const MIP_STIP: u64 = 1 << 5;
+pub const MIP_MTIP: u64 = 1 << 7;
+pub const MIP_SEIP: u64 = 1 << 9;
+pub const MIP_MEIP: u64 = 1 << 11;
+
+const SUPERVISOR_INTERRUPTS: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+const ALL_INTERRUPTS: u64 = SUPERVISOR_INTERRUPTS | MIP_MSIP | MIP_MTIP | MIP_MEIP;
+// SEIP should also be writable by M-mode, but we only let the PLIC drive it
+const HARDWARE_INTERRUPTS: u64 = MIP_MSIP | MIP_MTIP | MIP_SEIP | MIP_MEIP;
+
+// Every exception but environment calls from M-mode and the reserved ones can be delegated
+const DELEGABLE_EXCEPTIONS: u64 = 0xB3FF;
+
+// misa: MXL=2 (64 bits), A, C, D, F, I, M, S and U
+const MISA_VALUE: u64 = (2 << 62) | (1 << 0) | (1 << 2) | (1 << 3) | (1 << 5) | (1 << 8) | (1 << 12) | (1 << 18) | (1 << 20);
+
+pub struct Csr {
+    regs: Vec<u64>
+}
+
+impl Csr {
+    pub fn new() -> Self {
+        let mut regs = vec![0; 4096];
+
+        regs[MISA as usize] = MISA_VALUE;
+        // S-mode and U-mode are always 64 bits
+        regs[MSTATUS as usize] = (2 << 32) | (2 << 34);
+
+        Self { regs }
+    }
+
+    pub fn exists(addr: u16) -> bool {
+        match addr {
+            FFLAGS | FRM | FCSR |
+            SSTATUS | SIE | STVEC | SCOUNTEREN |
+            SSCRATCH | SEPC | SCAUSE | STVAL | SIP | SATP |
+            MVENDORID | MARCHID | MIMPID | MHARTID | MCONFIGPTR |
+            MSTATUS | MISA | MEDELEG | MIDELEG | MIE | MTVEC | MCOUNTEREN |
+            MSCRATCH | MEPC | MCAUSE | MTVAL | MIP |
+            PMPADDR0..=PMPADDR63 |
+            MCYCLE | MINSTRET | CYCLE | TIME | INSTRET => true,
+            // Odd pmpcfg registers don't exist on RV64
+            PMPCFG0..=PMPCFG15 => addr & 1 == 0,
+            _ => false
+        }
+    }
+
+    pub fn read_only(addr: u16) -> bool {
+        (addr >> 10) == 0b11
+    }
+
+    // Lowest privilege level allowed to access the CSR
+    pub fn privilege(addr: u16) -> u64 {
+        ((addr >> 8) & 0x3) as u64
+    }
+
+    pub fn read(&self, addr: u16) -> u64 {
+        match addr {
+            FFLAGS => self.regs[FCSR as usize] & 0x1F,
+            FRM => (self.regs[FCSR as usize] >> 5) & 0x7,
+            MSTATUS => self.mstatus(),
+            SSTATUS => self.mstatus() & SSTATUS_VISIBLE,
+            SIE => self.regs[MIE as usize] & self.regs[MIDELEG as usize],
+            SIP => self.regs[MIP as usize] & self.regs[MIDELEG as usize],
+            CYCLE => self.regs[MCYCLE as usize],
+            INSTRET => self.regs[MINSTRET as usize],
+            _ => self.regs[addr as usize]
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u64) {
+        let mideleg = self.regs[MIDELEG as usize];
+
+        match addr {
+            FFLAGS => { self.write_masked(FCSR, value, 0x1F); self.set_fs_dirty() }
+            FRM => { self.write_masked(FCSR, value << 5, 0xE0); self.set_fs_dirty() }
+            FCSR => { self.regs[addr as usize] = value & 0xFF; self.set_fs_dirty() }
+            SSTATUS => { self.write_masked(MSTATUS, value, SSTATUS_WRITABLE) }
+            SIE => { self.write_masked(MIE, value, mideleg & ALL_INTERRUPTS) }
+            // Only the software interrupt can be set or cleared from S-mode
+            SIP => { self.write_masked(MIP, value, mideleg & MIP_SSIP) }
+            STVEC => { self.regs[addr as usize] = value & !0b10 }
+            SEPC => { self.regs[addr as usize] = value & !0b1 }
+            SATP => {
+                if mmu::satp_mode_supported(value) {
+                    self.regs[addr as usize] = value;
+                }
+            }
+            MSTATUS => {
+                let mut value = value;
+
+                // MPP is WARL, 2 is reserved so we keep the old value
+                if value & MSTATUS_MPP == 2 << 11 {
+                    value = (value & !MSTATUS_MPP) | (self.regs[addr as usize] & MSTATUS_MPP);
+                }
+
+                self.write_masked(MSTATUS, value, MSTATUS_WRITABLE);
+            }
+            // misa is WARL, and we don't allow extensions to be disabled
+            MISA => {}
+            MEDELEG => { self.regs[addr as usize] = value & DELEGABLE_EXCEPTIONS }
+            MIDELEG => { self.regs[addr as usize] = value & SUPERVISOR_INTERRUPTS }
+            MIE => { self.regs[addr as usize] = value & ALL_INTERRUPTS }
+            // The other bits are set by hardware only
+            MIP => { self.write_masked(MIP, value, SUPERVISOR_INTERRUPTS & !HARDWARE_INTERRUPTS) }
+            // Modes 2 and 3 are reserved, so only keep the low bit
+            MTVEC => { self.regs[addr as usize] = value & !0b10 }
+            MEPC => { self.regs[addr as usize] = value & !0b1 }
+            MCOUNTEREN | SCOUNTEREN => { self.regs[addr as usize] = value & 0xFFFFFFFF }
+            PMPADDR0..=PMPADDR63 => { self.regs[addr as usize] = value & 0x003F_FFFF_FFFF_FFFF }
+            _ => { self.regs[addr as usize] = value }
+        }
+    }
+
+    // SD summarizes whether FS is dirty
+    fn mstatus(&self) -> u64 {
+        let mstatus = self.regs[MSTATUS as usize];
+
+        if mstatus & MSTATUS_FS == MSTATUS_FS { mstatus | MSTATUS_SD } else { mstatus }
+    }
+
+    pub fn fs_enabled(&self) -> bool {
+        self.regs[MSTATUS as usize] & MSTATUS_FS != 0
+    }
+
+    pub fn set_fs_dirty(&mut self) {
+        self.regs[MSTATUS as usize] |= MSTATUS_FS;
+    }
+
+    pub fn accrue_fflags(&mut self, flags: u64) {
+        if flags != 0 {
+            self.regs[FCSR as usize] |= flags & 0x1F;
+            self.set_fs_dirty();
+        }
+    }
+
+    // The bits of mip set by hardware only, software can't change them
+    pub fn set_interrupts_pending(&mut self, bits: u64) {
+        self.write_masked(MIP, bits, HARDWARE_INTERRUPTS);
+    }
+
+    // There's no mtime CSR, time follows the CLINT's register
+    pub fn set_time(&mut self, mtime: u64) {
+        self.regs[TIME as usize] = mtime;
+    }
+
+    fn write_masked(&mut self, addr: u16, value: u64, mask: u64) {
+        let reg = &mut self.regs[addr as usize];
+        *reg = (*reg & !mask) | (value & mask);
+    }
+
+    // Called once per retired instruction
+    pub fn tick(&mut self) {
+        self.regs[MCYCLE as usize] = self.regs[MCYCLE as usize].wrapping_add(1);
+        self.regs[MINSTRET as usize] = self.regs[MINSTRET as usize].wrapping_add(1);
+    }
+}
+
+impl Default for Csr {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for Csr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f,
+            "mstatus={:016x} mtvec={:016x} mepc={:016x} mcause={:016x} mtval={:016x}\n\
+            \tstvec={:016x} sepc={:016x} scause={:016x} stval={:016x} satp={:016x}",
+            self.read(MSTATUS), self.read(MTVEC), self.read(MEPC), self.read(MCAUSE), self.read(MTVAL),
+            self.read(STVEC), self.read(SEPC), self.read(SCAUSE), self.read(STVAL), self.read(SATP))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // UXL and SXL, read-only
+    const XLEN: u64 = (2 << 32) | (2 << 34);
+
+    #[test]
+    fn warl_fields() {
+        let mut csr = Csr::new();
+
+        // Extensions can't be turned off
+        csr.write(MISA, 0);
+        assert_eq!(csr.read(MISA), MISA_VALUE);
+
+        // Only the interrupts that exist can be enabled, and only the supervisor ones made pending,
+        // except for SEIP which comes from the PLIC
+        csr.write(MIE, u64::MAX);
+        assert_eq!(csr.read(MIE), ALL_INTERRUPTS);
+        csr.write(MIP, u64::MAX);
+        assert_eq!(csr.read(MIP), MIP_SSIP | MIP_STIP);
+        csr.set_interrupts_pending(MIP_SEIP | MIP_MTIP);
+        assert_eq!(csr.read(MIP), MIP_SSIP | MIP_STIP | MIP_SEIP | MIP_MTIP);
+
+        csr.write(MEDELEG, u64::MAX);
+        assert_eq!(csr.read(MEDELEG), DELEGABLE_EXCEPTIONS);
+        csr.write(MIDELEG, u64::MAX);
+        assert_eq!(csr.read(MIDELEG), SUPERVISOR_INTERRUPTS);
+
+        // Vectored mode is kept, the reserved modes aren't
+        csr.write(MTVEC, 0x80000003);
+        assert_eq!(csr.read(MTVEC), 0x80000001);
+        csr.write(STVEC, 0x80000003);
+        assert_eq!(csr.read(STVEC), 0x80000001);
+
+        // With C, instructions only have to be 2-byte aligned
+        csr.write(MEPC, 0x80000007);
+        assert_eq!(csr.read(MEPC), 0x80000006);
+        csr.write(SEPC, 0x80000007);
+        assert_eq!(csr.read(SEPC), 0x80000006);
+
+        csr.write(MCOUNTEREN, u64::MAX);
+        assert_eq!(csr.read(MCOUNTEREN), 0xFFFFFFFF);
+
+        csr.write(PMPADDR0, u64::MAX);
+        assert_eq!(csr.read(PMPADDR0), 0x003F_FFFF_FFFF_FFFF);
+    }
+
+    #[test]
+    fn mstatus() {
+        let mut csr = Csr::new();
+        assert_eq!(csr.read(MSTATUS), XLEN);
+
+        // SD comes with a dirty FS
+        csr.write(MSTATUS, u64::MAX);
+        assert_eq!(csr.read(MSTATUS), MSTATUS_WRITABLE | XLEN | MSTATUS_SD);
+
+        // MPP can't be 2, it keeps the mode it had
+        csr.write(MSTATUS, 1 << 11);
+        csr.write(MSTATUS, 2 << 11);
+        assert_eq!(csr.read(MSTATUS) & MSTATUS_MPP, 1 << 11);
+    }
+
+    #[test]
+    fn sstatus_is_a_view_of_mstatus() {
+        let mut csr = Csr::new();
+
+        csr.write(MSTATUS, MSTATUS_MIE | MSTATUS_SIE | MSTATUS_MPP);
+        assert_eq!(csr.read(SSTATUS), MSTATUS_SIE | (2 << 32));
+
+        // The machine fields are out of reach
+        csr.write(SSTATUS, u64::MAX);
+        assert_eq!(csr.read(MSTATUS), MSTATUS_MIE | MSTATUS_MPP | SSTATUS_WRITABLE | XLEN | MSTATUS_SD);
+        assert_eq!(csr.read(SSTATUS), SSTATUS_WRITABLE | (2 << 32) | MSTATUS_SD);
+
+        csr.write(SSTATUS, 0);
+        assert_eq!(csr.read(MSTATUS), MSTATUS_MIE | MSTATUS_MPP | XLEN);
+    }
+
+    #[test]
+    fn sie_and_sip_follow_mideleg() {
+        let mut csr = Csr::new();
+        csr.write(MIE, MIP_MTIP | MIP_STIP);
+        csr.write(MIP, MIP_STIP | MIP_SSIP);
+
+        // Nothing delegated, nothing to see
+        assert_eq!(csr.read(SIE), 0);
+        assert_eq!(csr.read(SIP), 0);
+        csr.write(SIE, MIP_SEIP);
+        assert_eq!(csr.read(MIE), MIP_MTIP | MIP_STIP);
+
+        csr.write(MIDELEG, MIP_STIP | MIP_SSIP);
+        assert_eq!(csr.read(SIE), MIP_STIP);
+        assert_eq!(csr.read(SIP), MIP_STIP | MIP_SSIP);
+
+        // S-mode can only clear its software interrupt
+        csr.write(SIP, 0);
+        assert_eq!(csr.read(MIP), MIP_STIP);
+    }
+
+    #[test]
+    fn fcsr() {
+        let mut csr = Csr::new();
+        assert!(!csr.fs_enabled());
+
+        // fflags and frm are fields of fcsr
+        csr.write(FCSR, 0xFFF);
+        assert_eq!((csr.read(FCSR), csr.read(FFLAGS), csr.read(FRM)), (0xFF, 0x1F, 0x7));
+        csr.write(FRM, 0x1);
+        csr.write(FFLAGS, 0x3);
+        assert_eq!(csr.read(FCSR), 0x23);
+
+        // Writing them makes the state dirty
+        assert_eq!(csr.read(MSTATUS) & (MSTATUS_FS | MSTATUS_SD), MSTATUS_FS | MSTATUS_SD);
+
+        csr.write(MSTATUS, 1 << 13);
+        csr.accrue_fflags(0);
+        assert_eq!(csr.read(MSTATUS) & MSTATUS_FS, 1 << 13);
+        csr.accrue_fflags(0x10);
+        assert_eq!(csr.read(FFLAGS), 0x13);
+        assert_eq!(csr.read(MSTATUS) & MSTATUS_FS, MSTATUS_FS);
+    }
+
+    #[test]
+    fn counters() {
+        let mut csr = Csr::new();
+
+        csr.tick();
+        csr.tick();
+        assert_eq!((csr.read(MCYCLE), csr.read(MINSTRET)), (2, 2));
+        assert_eq!((csr.read(CYCLE), csr.read(INSTRET)), (2, 2));
+
+        csr.write(MCYCLE, 100);
+        assert_eq!(csr.read(CYCLE), 100);
+
+        // time is only set from the timer
+        csr.set_time(42);
+        assert_eq!(csr.read(TIME), 42);
+        assert!(Csr::exists(TIME) && Csr::read_only(TIME));
+    }
+
+    #[test]
+    fn existing_registers() {
+        assert!(Csr::exists(MSTATUS));
+        assert!(Csr::exists(PMPCFG0));
+        // pmpcfg1 is RV32 only
+        assert!(!Csr::exists(PMPCFG0 + 1));
+        assert!(!Csr::exists(0x7C0));
+
+        assert!(Csr::read_only(MHARTID));
+        assert!(Csr::read_only(CYCLE));
+        assert!(!Csr::read_only(MSCRATCH));
+
+        assert_eq!(Csr::privilege(SSTATUS), 1);
+        assert_eq!(Csr::privilege(MSTATUS), 3);
+        assert_eq!(Csr::privilege(CYCLE), 0);
+    }
+}
@@ -0,0 +1,237 @@
+use super::CPU;
+use super::csr;
+use super::softfloat::{self, Format, Softfloat, SINGLE, DOUBLE};
+use crate::trap::Exception;
+
+// Single-precision values live in the low half of the registers, with the high half set to 1
+const NAN_BOX: u64 = 0xFFFFFFFF_00000000;
+
+impl CPU {
+    // Improperly NaN-boxed single-precision values read as the canonical NaN
+    fn read_float(&self, fmt: Format, reg: u32) -> u64 {
+        let value = self.fregs.read_reg(reg);
+
+        if fmt == DOUBLE {
+            value
+        } else if value & NAN_BOX == NAN_BOX {
+            value & !NAN_BOX
+        } else {
+            SINGLE.canonical_nan()
+        }
+    }
+
+    fn write_float(&mut self, fmt: Format, reg: u32, value: u64) {
+        self.fregs.write_reg(reg, if fmt == SINGLE { value | NAN_BOX } else { value });
+        self.csr.set_fs_dirty();
+    }
+
+    // The rm field, or frm if it's dynamic. Reserved values are illegal.
+    fn rounding_mode(&self, instr: u32) -> Result<u64, Exception> {
+        let rm = match (instr >> 12) & 0x7 {
+            0x7 => self.csr.read(csr::FRM),
+            rm => rm as u64
+        };
+
+        if rm > softfloat::RMM {
+            return Err(Exception::IllegalInstruction(instr));
+        }
+
+        Ok(rm)
+    }
+
+    pub(super) fn execute_float(&mut self, instr: u32) -> Result<(), Exception> {
+        if !self.csr.fs_enabled() {
+            return Err(Exception::IllegalInstruction(instr));
+        }
+
+        let opcode = instr & 0x7F;
+        let funct3 = (instr >> 12) & 0x7;
+        let rd = (instr >> 7) & 0x1F;
+        let rs1 = (instr >> 15) & 0x1F;
+        let rs2 = (instr >> 20) & 0x1F;
+
+        // Format of the operation, in the same place for OP-FP and the fused multiply-adds
+        let fmt = match (instr >> 25) & 0x3 {
+            0x0 => SINGLE,
+            0x1 => DOUBLE,
+            _ if opcode == 0x07 || opcode == 0x27 => SINGLE,
+            _ => return Err(Exception::IllegalInstruction(instr))
+        };
+
+        match opcode {
+            0x07 => {
+                // Floating-point loads
+                let imm = ((instr as i32 as i64) >> 20) as u64;
+                let addr = self.iregs.read_reg(rs1).wrapping_add(imm);
+
+                match funct3 {
+                    // FLW
+                    0x2 => { let value = self.load(addr, 4)?; self.write_float(SINGLE, rd, value) }
+                    // FLD
+                    0x3 => { let value = self.load(addr, 8)?; self.write_float(DOUBLE, rd, value) }
+                    _ => return Err(Exception::IllegalInstruction(instr))
+                }
+            }
+            0x27 => {
+                // Floating-point stores, FSW stores the low bits even if they aren't NaN-boxed
+                let imm = (((instr & 0xfe000000) as i32 as i64) >> 20) as u64 | ((instr >> 7) & 0x1F) as u64;
+                let addr = self.iregs.read_reg(rs1).wrapping_add(imm);
+                let value = self.fregs.read_reg(rs2);
+
+                match funct3 {
+                    // FSW
+                    0x2 => { self.store(addr, 4, value)? }
+                    // FSD
+                    0x3 => { self.store(addr, 8, value)? }
+                    _ => return Err(Exception::IllegalInstruction(instr))
+                }
+            }
+            0x43 | 0x47 | 0x4B | 0x4F => {
+                // Fused multiply-adds
+                let rs3 = instr >> 27;
+                let mut sf = Softfloat::new(self.rounding_mode(instr)?);
+                let (a, b, c) = (self.read_float(fmt, rs1), self.read_float(fmt, rs2), self.read_float(fmt, rs3));
+
+                let value = match opcode {
+                    // FMADD
+                    0x43 => sf.fma(fmt, a, b, c, false, false),
+                    // FMSUB
+                    0x47 => sf.fma(fmt, a, b, c, false, true),
+                    // FNMSUB
+                    0x4B => sf.fma(fmt, a, b, c, true, false),
+                    // FNMADD
+                    _ => sf.fma(fmt, a, b, c, true, true)
+                };
+
+                self.write_float(fmt, rd, value);
+                self.csr.accrue_fflags(sf.flags);
+            }
+            0x53 => {
+                // OP-FP
+                let funct5 = instr >> 27;
+                let (a, b) = (self.read_float(fmt, rs1), self.read_float(fmt, rs2));
+                // Operations that don't round ignore the rm field, so don't check it for them
+                let mut sf = Softfloat::new(softfloat::RNE);
+
+                match (funct5, funct3, rs2) {
+                    // FADD, FSUB, FMUL, FDIV, FSQRT
+                    (0x00, _, _) | (0x01, _, _) | (0x02, _, _) | (0x03, _, _) | (0x0B, _, 0) => {
+                        sf = Softfloat::new(self.rounding_mode(instr)?);
+
+                        let value = match funct5 {
+                            0x00 => sf.add(fmt, a, b),
+                            0x01 => sf.sub(fmt, a, b),
+                            0x02 => sf.mul(fmt, a, b),
+                            0x03 => sf.div(fmt, a, b),
+                            _ => sf.sqrt(fmt, a)
+                        };
+
+                        self.write_float(fmt, rd, value);
+                    }
+                    // FSGNJ, FSGNJN, FSGNJX
+                    (0x04, 0x0..=0x2, _) => {
+                        let sign = fmt.sign_bit();
+                        let new_sign = match funct3 {
+                            0x0 => b & sign,
+                            0x1 => !b & sign,
+                            _ => (a ^ b) & sign
+                        };
+
+                        self.write_float(fmt, rd, (a & !sign) | new_sign);
+                    }
+                    // FMIN, FMAX
+                    (0x05, 0x0..=0x1, _) => {
+                        let value = sf.min_max(fmt, a, b, funct3 == 0x1);
+                        self.write_float(fmt, rd, value);
+                    }
+                    // FCVT.S.D
+                    (0x08, _, 1) if fmt == SINGLE => {
+                        sf = Softfloat::new(self.rounding_mode(instr)?);
+                        let value = sf.convert(DOUBLE, SINGLE, self.read_float(DOUBLE, rs1));
+                        self.write_float(SINGLE, rd, value);
+                    }
+                    // FCVT.D.S, it's exact
+                    (0x08, _, 0) if fmt == DOUBLE => {
+                        let value = sf.convert(SINGLE, DOUBLE, self.read_float(SINGLE, rs1));
+                        self.write_float(DOUBLE, rd, value);
+                    }
+                    // FLE, FLT, FEQ
+                    (0x14, 0x0..=0x2, _) => {
+                        let value = match funct3 {
+                            0x0 => sf.lt(fmt, a, b, true),
+                            0x1 => sf.lt(fmt, a, b, false),
+                            _ => sf.eq(fmt, a, b)
+                        };
+
+                        self.iregs.write_reg(rd, value as u64);
+                    }
+                    // FCVT.W, FCVT.WU, FCVT.L, FCVT.LU
+                    (0x18, _, 0..=3) => {
+                        sf = Softfloat::new(self.rounding_mode(instr)?);
+                        let bits = if rs2 & 0x2 != 0 { 64 } else { 32 };
+                        let value = sf.float_to_int(fmt, a, rs2 & 0x1 == 0, bits);
+
+                        self.iregs.write_reg(rd, value);
+                    }
+                    // FCVT from W, WU, L, LU
+                    (0x1A, _, 0..=3) => {
+                        sf = Softfloat::new(self.rounding_mode(instr)?);
+                        let source = self.iregs.read_reg(rs1);
+                        let source = match rs2 {
+                            0 => source as i32 as i64 as u64,
+                            1 => source as u32 as u64,
+                            _ => source
+                        };
+                        let value = sf.int_to_float(fmt, source, rs2 & 0x1 == 0);
+
+                        self.write_float(fmt, rd, value);
+                    }
+                    // FMV.X.W, FMV.X.D, they move the raw bits
+                    (0x1C, 0x0, 0) => {
+                        let value = self.fregs.read_reg(rs1);
+                        let value = if fmt == SINGLE { value as i32 as i64 as u64 } else { value };
+
+                        self.iregs.write_reg(rd, value);
+                    }
+                    // FCLASS
+                    (0x1C, 0x1, 0) => { self.iregs.write_reg(rd, softfloat::classify(fmt, a)) }
+                    // FMV.W.X, FMV.D.X
+                    (0x1E, 0x0, 0) => {
+                        let value = self.iregs.read_reg(rs1);
+                        let value = if fmt == SINGLE { value & !NAN_BOX } else { value };
+
+                        self.write_float(fmt, rd, value);
+                    }
+                    _ => return Err(Exception::IllegalInstruction(instr))
+                }
+
+                self.csr.accrue_fflags(sf.flags);
+            }
+            _ => return Err(Exception::IllegalInstruction(instr))
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nan_boxing() {
+        let mut cpu = CPU::new();
+
+        // Singles are written NaN-boxed, doubles as they are
+        cpu.write_float(SINGLE, 1, 0x3F800000);
+        assert_eq!(cpu.fregs.read_reg(1), 0xFFFFFFFF_3F800000);
+        assert_eq!(cpu.read_float(SINGLE, 1), 0x3F800000);
+        assert_eq!(cpu.read_float(DOUBLE, 1), 0xFFFFFFFF_3F800000);
+
+        // A double read as a single isn't properly boxed
+        cpu.write_float(DOUBLE, 2, 0x3FF0000000000000);
+        assert_eq!(cpu.read_float(SINGLE, 2), SINGLE.canonical_nan());
+        cpu.fregs.write_reg(3, 0x7FFFFFFF_3F800000);
+        assert_eq!(cpu.read_float(SINGLE, 3), SINGLE.canonical_nan());
+    }
+}
@@ -0,0 +1,143 @@
+// The hart's state as the debuggers see it. Memory goes through the MMU, so addresses are
+// the virtual addresses the program uses. Looking doesn't change anything the guest can see,
+// the TLBs and the A and D bits of its page tables stay as they are.
+
+use super::{CPU, State};
+use super::csr::{self, Csr};
+use super::mmu::Access;
+use crate::bus::{Watchpoint, WatchHit};
+use crate::debug::symbols::Symbols;
+use crate::devices::syscon::PowerState;
+
+impl CPU {
+    pub fn pc(&self) -> u64 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u64) {
+        self.pc = pc;
+    }
+
+    pub fn xreg(&self, reg: u32) -> u64 {
+        self.iregs.read_reg(reg)
+    }
+
+    pub fn set_xreg(&mut self, reg: u32, value: u64) {
+        self.iregs.write_reg(reg, value);
+    }
+
+    pub fn freg(&self, reg: u32) -> u64 {
+        self.fregs.read_reg(reg)
+    }
+
+    pub fn set_freg(&mut self, reg: u32, value: u64) {
+        self.fregs.write_reg(reg, value);
+    }
+
+    // Same as in the CPU's Debug output
+    pub fn format_xregs(&self) -> String {
+        format!("{:?}", self.iregs)
+    }
+
+    pub fn format_fregs(&self) -> String {
+        format!("{:?}", self.fregs)
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn read_csr(&self, addr: u16) -> Option<u64> {
+        if Csr::exists(addr) { Some(self.csr.read(addr)) } else { None }
+    }
+
+    pub fn write_csr(&mut self, addr: u16, value: u64) -> bool {
+        if !Csr::exists(addr) || Csr::read_only(addr) {
+            return false;
+        }
+
+        self.csr.write(addr, value);
+
+        // The program would do an SFENCE.VMA after that, the debugger can't
+        if addr == csr::SATP {
+            self.sfence_vma(None, None);
+        }
+
+        true
+    }
+
+    // Hits and misses of the iTLB, then of the dTLB
+    pub fn tlb_stats(&self) -> [(u64, u64); 2] {
+        [self.itlb.stats(), self.dtlb.stats()]
+    }
+
+    // 0 for U-mode, 1 for S-mode, 3 for M-mode
+    pub fn privilege(&self) -> u64 {
+        self.state as u64
+    }
+
+    pub fn set_privilege(&mut self, privilege: u64) {
+        self.state = State::from_bits(privilege);
+    }
+
+    // Byte by byte, so that nothing past a fault is accessed. Returns false on a fault, or if
+    // the address isn't in RAM or ROM.
+    pub fn read_memory(&mut self, addr: u64, data: &mut [u8]) -> bool {
+        for (i, byte) in data.iter_mut().enumerate() {
+            let vaddr = addr.wrapping_add(i as u64);
+
+            match self.translate_quietly(vaddr, Access::Load).and_then(|paddr| self.bus.debug_load8(paddr)) {
+                Ok(value) => *byte = value,
+                Err(_) => return false
+            }
+        }
+
+        true
+    }
+
+    pub fn write_memory(&mut self, addr: u64, data: &[u8]) -> bool {
+        for (i, &byte) in data.iter().enumerate() {
+            let vaddr = addr.wrapping_add(i as u64);
+
+            if self.translate_quietly(vaddr, Access::Store).and_then(|paddr| self.bus.debug_store8(paddr, byte)).is_err() {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    // Where the hart's loads go, watchpoints are on physical addresses
+    pub fn physical_addr(&mut self, addr: u64) -> Option<u64> {
+        self.translate(addr, Access::Load).ok()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.bus.add_watchpoint(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        self.bus.remove_watchpoint(watchpoint)
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        self.bus.watchpoints()
+    }
+
+    // The watchpoint the last instruction hit, with the address of that instruction
+    pub fn take_watch_hit(&mut self) -> Option<(u64, WatchHit)> {
+        self.bus.take_watch_hit().map(|hit| (self.instr_pc, hit))
+    }
+
+    // Runs one instruction, or does what the syscon device asked for. Returns the exit code
+    // once the machine is powered off.
+    pub fn step(&mut self) -> Option<i32> {
+        match self.power_state() {
+            PowerState::Running => self.run_instr(),
+            PowerState::Reset => self.reset(),
+            PowerState::PowerOff(code) => return Some(code)
+        }
+
+        None
+    }
+}
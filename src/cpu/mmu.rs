@@ -0,0 +1,470 @@
+use super::{CPU, State};
+use super::csr;
+use crate::trap::Exception;
+
+const PAGE_SIZE: u64 = 4096;
+
+// satp fields
+const SATP_MODE_BARE: u64 = 0;
+const SATP_MODE_SV39: u64 = 8;
+const SATP_MODE_SV48: u64 = 9;
+const SATP_MODE_SV57: u64 = 10;
+
+// PTE fields
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_G: u64 = 1 << 5;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+// Bits 63-54 are reserved for extensions we don't implement
+const PTE_RESERVED: u64 = 0xFFC0_0000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Instruction,
+    Load,
+    Store
+}
+
+impl Access {
+    fn page_fault(self, vaddr: u64) -> Exception {
+        match self {
+            Access::Instruction => Exception::InstructionPageFault(vaddr),
+            Access::Load => Exception::LoadPageFault(vaddr),
+            Access::Store => Exception::StorePageFault(vaddr)
+        }
+    }
+
+    fn access_fault(self, vaddr: u64) -> Exception {
+        match self {
+            Access::Instruction => Exception::InstructionAccessFault(vaddr),
+            Access::Load => Exception::LoadAccessFault(vaddr),
+            Access::Store => Exception::StoreAccessFault(vaddr)
+        }
+    }
+}
+
+// Number of page table levels for each paging mode, None if it isn't supported
+fn levels(satp: u64) -> Option<u64> {
+    match satp >> 60 {
+        SATP_MODE_BARE => Some(0),
+        SATP_MODE_SV39 => Some(3),
+        SATP_MODE_SV48 => Some(4),
+        SATP_MODE_SV57 => Some(5),
+        _ => None
+    }
+}
+
+// satp is WARL, writes with an unsupported mode are ignored
+pub fn satp_mode_supported(satp: u64) -> bool {
+    levels(satp).is_some()
+}
+
+// Checks the permissions of a leaf PTE for an access done in a given mode
+fn leaf_allowed(pte: u64, access: Access, state: State, mstatus: u64) -> bool {
+    let allowed = match access {
+        Access::Instruction => pte & PTE_X != 0,
+        Access::Load => pte & PTE_R != 0 || (mstatus & csr::MSTATUS_MXR != 0 && pte & PTE_X != 0),
+        Access::Store => pte & PTE_W != 0
+    };
+
+    let privilege_ok = match state {
+        State::User => pte & PTE_U != 0,
+        // S-mode can access U pages with SUM, but never execute them
+        State::Supervisor => pte & PTE_U == 0 ||
+            (access != Access::Instruction && mstatus & csr::MSTATUS_SUM != 0),
+        State::Machine => true
+    };
+
+    allowed && privilege_ok
+}
+
+fn physical_address(pte: u64, level: u64, vaddr: u64) -> u64 {
+    let offset_mask = (1 << (12 + 9 * level)) - 1;
+
+    (((pte >> 10) << 12) & !offset_mask) | (vaddr & offset_mask)
+}
+
+impl CPU {
+    // MPRV makes loads and stores behave as if we were in MPP mode
+    fn effective_state(&self, access: Access, mstatus: u64) -> State {
+        if access != Access::Instruction && mstatus & csr::MSTATUS_MPRV != 0 {
+            State::from_bits((mstatus & csr::MSTATUS_MPP) >> 11)
+        } else {
+            self.state
+        }
+    }
+
+    pub(super) fn translate(&mut self, vaddr: u64, access: Access) -> Result<u64, Exception> {
+        let satp = self.csr.read(csr::SATP);
+        let mstatus = self.csr.read(csr::MSTATUS);
+        let state = self.effective_state(access, mstatus);
+
+        // satp never holds an unsupported mode, so this can't fail
+        let levels = levels(satp).unwrap_or(0);
+
+        if state == State::Machine || levels == 0 {
+            return Ok(vaddr);
+        }
+
+        let asid = ((satp >> 44) & 0xFFFF) as u16;
+        let tlb = if access == Access::Instruction { &mut self.itlb } else { &mut self.dtlb };
+
+        // Entries are only cached with A set, but a store to a clean page has to walk again to set D
+        let hit = tlb.lookup(vaddr, asid, |entry| {
+            (access != Access::Store || entry.pte & PTE_D != 0) && leaf_allowed(entry.pte, access, state, mstatus)
+        });
+
+        if let Some(entry) = hit {
+            return Ok(physical_address(entry.pte, entry.level, vaddr));
+        }
+
+        let (pte, level, global) = self.walk(vaddr, access, state, mstatus, satp, levels, true)?;
+
+        let tlb = if access == Access::Instruction { &mut self.itlb } else { &mut self.dtlb };
+        tlb.insert(vaddr, asid, global, level, pte);
+
+        Ok(physical_address(pte, level, vaddr))
+    }
+
+    // Same as translate, but for debuggers: the guest doesn't see it. The TLBs are left alone,
+    // and A and D aren't set in the page tables.
+    pub(super) fn translate_quietly(&mut self, vaddr: u64, access: Access) -> Result<u64, Exception> {
+        let satp = self.csr.read(csr::SATP);
+        let mstatus = self.csr.read(csr::MSTATUS);
+        let state = self.effective_state(access, mstatus);
+        let levels = levels(satp).unwrap_or(0);
+
+        if state == State::Machine || levels == 0 {
+            return Ok(vaddr);
+        }
+
+        let (pte, level, _) = self.walk(vaddr, access, state, mstatus, satp, levels, false)?;
+
+        Ok(physical_address(pte, level, vaddr))
+    }
+
+    // Walks the page table, returns the leaf PTE, its level, and whether the mapping is global.
+    // update_ad is false when only looking, A and D are set by the hart's own accesses.
+    #[allow(clippy::too_many_arguments)]
+    fn walk(&mut self, vaddr: u64, access: Access, state: State, mstatus: u64, satp: u64, levels: u64, update_ad: bool)
+        -> Result<(u64, u64, bool), Exception> {
+        let va_bits = 12 + 9 * levels;
+
+        // Every bit above the VA must be a copy of its highest bit
+        if (((vaddr << (64 - va_bits)) as i64) >> (64 - va_bits)) as u64 != vaddr {
+            return Err(access.page_fault(vaddr));
+        }
+
+        let mut table = (satp & 0xFFF_FFFF_FFFF) * PAGE_SIZE;
+        let mut global = false;
+
+        for level in (0..levels).rev() {
+            let vpn = (vaddr >> (12 + 9 * level)) & 0x1FF;
+            let pte_addr = table + vpn * 8;
+            let mut pte = self.bus.load64(pte_addr).map_err(|_| access.access_fault(vaddr))?;
+            let ppn = (pte >> 10) & 0xFFF_FFFF_FFFF;
+
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || pte & PTE_RESERVED != 0 {
+                return Err(access.page_fault(vaddr));
+            }
+
+            // A global pointer makes the whole subtree global
+            global |= pte & PTE_G != 0;
+
+            if pte & (PTE_R | PTE_X) == 0 {
+                // Pointer to the next level, A, D and U are reserved here
+                if pte & (PTE_A | PTE_D | PTE_U) != 0 {
+                    return Err(access.page_fault(vaddr));
+                }
+
+                table = ppn * PAGE_SIZE;
+                continue;
+            }
+
+            // We found a leaf, superpages must be aligned
+            let misaligned = ppn & ((1 << (9 * level)) - 1) != 0;
+
+            if !leaf_allowed(pte, access, state, mstatus) || misaligned {
+                return Err(access.page_fault(vaddr));
+            }
+
+            // Update A and D in hardware
+            let new_pte = pte | PTE_A | if access == Access::Store { PTE_D } else { 0 };
+            if update_ad && new_pte != pte {
+                pte = new_pte;
+                self.bus.store64(pte_addr, pte).map_err(|_| access.access_fault(vaddr))?;
+            }
+
+            return Ok((pte, level, global));
+        }
+
+        Err(access.page_fault(vaddr))
+    }
+
+    pub(super) fn sfence_vma(&mut self, vaddr: Option<u64>, asid: Option<u16>) {
+        self.itlb.flush(vaddr, asid);
+        self.dtlb.flush(vaddr, asid);
+    }
+
+    pub(super) fn load(&mut self, vaddr: u64, size: u64) -> Result<u64, Exception> {
+        // Accesses crossing a page boundary can map to two different pages
+        if (vaddr & (PAGE_SIZE - 1)) + size > PAGE_SIZE {
+            let mut value = 0;
+            for i in 0..size {
+                value |= self.load(vaddr.wrapping_add(i), 1)? << (8 * i);
+            }
+
+            return Ok(value);
+        }
+
+        let paddr = self.translate(vaddr, Access::Load)?;
+
+        self.load_physical(vaddr, paddr, size).map_err(|_| Exception::LoadAccessFault(vaddr))
+    }
+
+    pub(super) fn store(&mut self, vaddr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if (vaddr & (PAGE_SIZE - 1)) + size > PAGE_SIZE {
+            // Translate every byte first so that a fault doesn't leave a partial write
+            for i in 0..size {
+                self.translate(vaddr.wrapping_add(i), Access::Store)?;
+            }
+
+            for i in 0..size {
+                self.store(vaddr.wrapping_add(i), 1, value >> (8 * i))?;
+            }
+
+            return Ok(());
+        }
+
+        let paddr = self.translate(vaddr, Access::Store)?;
+
+        self.store_physical(vaddr, paddr, size, value).map_err(|_| Exception::StoreAccessFault(vaddr))
+    }
+
+    // The program's own loads and stores, once translated. vaddr is what the trace shows.
+    pub(super) fn load_physical(&mut self, vaddr: u64, paddr: u64, size: u64) -> Result<u64, Exception> {
+        let value = self.bus.load(paddr, size)?;
+        self.tracer.access(vaddr, size, value, false);
+
+        Ok(value)
+    }
+
+    pub(super) fn store_physical(&mut self, vaddr: u64, paddr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        self.bus.store(paddr, size, value)?;
+        self.tracer.access(vaddr, size, value & (u64::MAX >> (64 - 8 * size)), true);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Root table, and the two below it for the 4 KiB pages with Sv39
+    const ROOT: u64 = 0x80100000;
+    const L1: u64 = 0x80101000;
+    const L0: u64 = 0x80102000;
+    // VPN[i] of the addresses the tests translate, from level 0 up
+    const VPNS: [u64; 5] = [3, 2, 1, 5, 6];
+    // With Sv39
+    const VADDR: u64 = 0x40403123;
+    const PAGE: u64 = 0x80200000;
+
+    fn pte(paddr: u64, flags: u64) -> u64 {
+        ((paddr >> 12) << 10) | flags | PTE_V
+    }
+
+    fn vaddr(levels: u64) -> u64 {
+        (0..levels).fold(0x123, |vaddr, level| vaddr | VPNS[level as usize] << (12 + 9 * level))
+    }
+
+    // Maps vaddr(levels) to PAGE with the leaf flags, tables follow each other from ROOT.
+    // The CPU is in S-mode.
+    fn paging(mode: u64, flags: u64) -> CPU {
+        let levels = levels(mode << 60).unwrap();
+        let mut cpu = CPU::new();
+        cpu.csr.write(csr::SATP, (mode << 60) | (ROOT >> 12));
+        cpu.state = State::Supervisor;
+
+        for level in (0..levels).rev() {
+            let table = ROOT + (levels - 1 - level) * PAGE_SIZE;
+            let entry = if level == 0 { pte(PAGE, flags) } else { pte(table + PAGE_SIZE, 0) };
+
+            cpu.bus.store64(table + 8 * VPNS[level as usize], entry).unwrap();
+        }
+
+        cpu
+    }
+
+    fn sv39(flags: u64) -> CPU {
+        paging(SATP_MODE_SV39, flags)
+    }
+
+    fn leaf(cpu: &mut CPU) -> u64 {
+        cpu.bus.load64(L0 + 3 * 8).unwrap()
+    }
+
+    #[test]
+    fn walk() {
+        let mut cpu = sv39(PTE_R | PTE_W | PTE_X);
+
+        assert_eq!(cpu.translate(VADDR, Access::Load), Ok(PAGE + 0x123));
+        assert_eq!(cpu.translate(VADDR, Access::Store), Ok(PAGE + 0x123));
+        assert_eq!(cpu.translate(VADDR, Access::Instruction), Ok(PAGE + 0x123));
+        assert_eq!(cpu.translate(VADDR + 0x1000, Access::Load), Err(Exception::LoadPageFault(VADDR + 0x1000)));
+
+        // M-mode doesn't translate, unless MPRV says otherwise for loads and stores
+        cpu.state = State::Machine;
+        assert_eq!(cpu.translate(VADDR, Access::Load), Ok(VADDR));
+        cpu.csr.write(csr::MSTATUS, csr::MSTATUS_MPRV | (1 << 11));
+        assert_eq!(cpu.translate(VADDR, Access::Load), Ok(PAGE + 0x123));
+        assert_eq!(cpu.translate(VADDR, Access::Instruction), Ok(VADDR));
+    }
+
+    #[test]
+    fn accessed_and_dirty() {
+        let mut cpu = sv39(PTE_R | PTE_W);
+
+        cpu.translate(VADDR, Access::Load).unwrap();
+        assert_eq!(leaf(&mut cpu) & (PTE_A | PTE_D), PTE_A);
+
+        cpu.translate(VADDR, Access::Store).unwrap();
+        assert_eq!(leaf(&mut cpu) & (PTE_A | PTE_D), PTE_A | PTE_D);
+
+        // A fault doesn't set them
+        let mut cpu = sv39(PTE_R);
+        assert!(cpu.translate(VADDR, Access::Store).is_err());
+        assert_eq!(leaf(&mut cpu) & (PTE_A | PTE_D), 0);
+    }
+
+    #[test]
+    fn permissions() {
+        let fault = |flags: u64, state: State, mstatus: u64, access: Access| {
+            let mut cpu = sv39(flags);
+            cpu.state = state;
+            cpu.csr.write(csr::MSTATUS, mstatus);
+            cpu.translate(VADDR, access).is_err()
+        };
+
+        assert!(fault(PTE_R, State::Supervisor, 0, Access::Store));
+        assert!(fault(PTE_R, State::Supervisor, 0, Access::Instruction));
+        assert!(fault(PTE_X, State::Supervisor, 0, Access::Load));
+        // Write-only is reserved
+        assert!(fault(PTE_W, State::Supervisor, 0, Access::Store));
+        assert!(!fault(PTE_X, State::Supervisor, csr::MSTATUS_MXR, Access::Load));
+
+        // U-mode only gets U pages, S-mode only touches them with SUM and never runs them
+        assert!(fault(PTE_R, State::User, 0, Access::Load));
+        assert!(!fault(PTE_R | PTE_U, State::User, 0, Access::Load));
+        assert!(fault(PTE_R | PTE_U, State::Supervisor, 0, Access::Load));
+        assert!(!fault(PTE_R | PTE_U, State::Supervisor, csr::MSTATUS_SUM, Access::Load));
+        assert!(fault(PTE_X | PTE_U, State::Supervisor, csr::MSTATUS_SUM, Access::Instruction));
+    }
+
+    #[test]
+    fn superpages() {
+        let mut cpu = sv39(PTE_R);
+
+        // A 2 MiB page where the 4 KiB pages' table was
+        cpu.bus.store64(L1 + 2 * 8, pte(0x80400000, PTE_R)).unwrap();
+        cpu.sfence_vma(None, None);
+        assert_eq!(cpu.translate(VADDR, Access::Load), Ok(0x80403123));
+
+        // Its physical address has to be aligned too
+        cpu.bus.store64(L1 + 2 * 8, pte(0x80401000, PTE_R)).unwrap();
+        cpu.sfence_vma(None, None);
+        assert_eq!(cpu.translate(VADDR, Access::Load), Err(Exception::LoadPageFault(VADDR)));
+
+        // 1 GiB
+        cpu.bus.store64(ROOT + 8, pte(0x80000000, PTE_R)).unwrap();
+        cpu.sfence_vma(None, None);
+        assert_eq!(cpu.translate(VADDR, Access::Load), Ok(0x80403123));
+        cpu.bus.store64(ROOT + 8, pte(0x80200000, PTE_R)).unwrap();
+        cpu.sfence_vma(None, None);
+        assert_eq!(cpu.translate(VADDR, Access::Load), Err(Exception::LoadPageFault(VADDR)));
+    }
+
+    #[test]
+    fn cached_translations() {
+        let mut cpu = sv39(PTE_R | PTE_W | PTE_A);
+        cpu.translate(VADDR, Access::Load).unwrap();
+
+        // Until SFENCE.VMA, the old mapping is still used
+        cpu.bus.store64(L0 + 3 * 8, pte(PAGE + 0x1000, PTE_R | PTE_W)).unwrap();
+        assert_eq!(cpu.translate(VADDR, Access::Load), Ok(PAGE + 0x123));
+        cpu.sfence_vma(Some(VADDR), None);
+        assert_eq!(cpu.translate(VADDR, Access::Load), Ok(PAGE + 0x1123));
+
+        // The first store walks again to set D
+        assert_eq!(leaf(&mut cpu) & PTE_D, 0);
+        cpu.translate(VADDR, Access::Store).unwrap();
+        assert_eq!(leaf(&mut cpu) & PTE_D, PTE_D);
+
+        // The permissions are checked again on hits, the mode can be different
+        cpu.state = State::User;
+        assert_eq!(cpu.translate(VADDR, Access::Load), Err(Exception::LoadPageFault(VADDR)));
+
+        // Fetches have their own TLB
+        cpu.state = State::Supervisor;
+        cpu.translate(VADDR, Access::Load).unwrap();
+        assert_eq!(cpu.itlb.stats().0, 0);
+    }
+
+    #[test]
+    fn malformed_entries() {
+        let mut cpu = sv39(PTE_R);
+
+        // Bits above the VA have to copy bit 38
+        assert_eq!(cpu.translate(VADDR | 1 << 40, Access::Load), Err(Exception::LoadPageFault(VADDR | 1 << 40)));
+
+        // A, D and U are reserved in pointers to the next level
+        cpu.bus.store64(L1 + 2 * 8, pte(L0, PTE_A)).unwrap();
+        assert!(cpu.translate(VADDR, Access::Load).is_err());
+
+        cpu.bus.store64(L1 + 2 * 8, pte(L0, 0) | 1 << 60).unwrap();
+        assert!(cpu.translate(VADDR, Access::Load).is_err());
+
+        // A table outside of memory is an access fault
+        cpu.bus.store64(L1 + 2 * 8, pte(0x2000_0000, 0)).unwrap();
+        assert_eq!(cpu.translate(VADDR, Access::Load), Err(Exception::LoadAccessFault(VADDR)));
+    }
+
+    #[test]
+    fn sv48_and_sv57() {
+        for &(mode, levels) in &[(SATP_MODE_SV48, 4), (SATP_MODE_SV57, 5)] {
+            let mut cpu = paging(mode, PTE_R);
+            let vaddr = vaddr(levels);
+            assert_eq!(cpu.translate(vaddr, Access::Load), Ok(PAGE + 0x123), "mode {}", mode);
+
+            // The VA is wider, the bits above it still copy its highest bit
+            let va_bits = 12 + 9 * levels;
+            let high = vaddr | 1 << (va_bits - 1);
+            assert_eq!(cpu.translate(high, Access::Load), Err(Exception::LoadPageFault(high)), "mode {}", mode);
+        }
+
+        // The same address is too wide for Sv39
+        let mut cpu = sv39(PTE_R);
+        assert_eq!(cpu.translate(vaddr(4), Access::Load), Err(Exception::LoadPageFault(vaddr(4))));
+    }
+
+    #[test]
+    fn satp_modes() {
+        let mut cpu = sv39(PTE_R);
+        let satp = cpu.csr.read(csr::SATP);
+
+        // Mode 11 is reserved, the write is ignored
+        cpu.csr.write(csr::SATP, 11 << 60);
+        assert_eq!(cpu.csr.read(csr::SATP), satp);
+
+        cpu.csr.write(csr::SATP, SATP_MODE_SV57 << 60);
+        assert_eq!(cpu.csr.read(csr::SATP), SATP_MODE_SV57 << 60);
+
+        cpu.csr.write(csr::SATP, 0);
+        assert_eq!(cpu.translate(VADDR, Access::Load), Ok(VADDR));
+    }
+}
@@ -0,0 +1,1449 @@
+pub mod csr;
+mod fpu;
+mod inspect;
+mod mmu;
+pub mod rvc;
+mod softfloat;
+mod tlb;
+
+use crate::bus::{Bus};
+use crate::debug::symbols::Symbols;
+use crate::debug::trace::Tracer;
+use crate::devices::dram::DRAM_BASE;
+use crate::devices::rom::{Rom, ROM_BASE};
+use crate::devices::syscon::PowerState;
+use crate::devices::virtio_blk::VirtioBlk;
+use crate::elf::{self, Elf, Segment};
+use crate::fdt;
+use crate::trap::Exception;
+use self::csr::Csr;
+use self::mmu::Access;
+use self::tlb::Tlb;
+use std::io::{Error, ErrorKind, Read};
+
+const DRAM_SIZE: usize = 1024 * 1024 * 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum State {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3
+}
+
+impl State {
+    fn from_bits(bits: u64) -> Self {
+        match bits & 0x3 {
+            0 => State::User,
+            1 => State::Supervisor,
+            _ => State::Machine
+        }
+    }
+}
+
+#[derive(Default)]
+struct IRegisters {
+    regs: [u64; 32],
+    // Last register written, for the trace
+    written: Option<u32>
+}
+
+impl IRegisters {
+    pub fn read_reg(&self, reg: u32) -> u64 {
+        if reg == 0 {
+            0
+        } else {
+            self.regs[reg as usize]
+        }
+    }
+
+    pub fn write_reg(&mut self, reg: u32, value: u64) {
+        if reg != 0 {
+            self.regs[reg as usize] = value;
+            self.written = Some(reg);
+        }
+    }
+}
+
+impl std::fmt::Debug for IRegisters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let abi = [
+            "zero", " ra ", " sp ", " gp ", " tp ", " t0 ", " t1 ", " t2 ", " s0 ", " s1 ", " a0 ",
+            " a1 ", " a2 ", " a3 ", " a4 ", " a5 ", " a6 ", " a7 ", " s2 ", " s3 ", " s4 ", " s5 ",
+            " s6 ", " s7 ", " s8 ", " s9 ", " s10", " s11", " t3 ", " t4 ", " t5 ", " t6 ",
+        ];
+
+        let mut output = String::new();
+        for i in (0..32).step_by(4) {
+            output = format!("{}\n\tx{:02}({})={:016x} x{:02}({})={:016x} x{:02}({})={:016x} x{:02}({})={:016x}",
+                output,
+                i, abi[i], self.read_reg(i as u32),
+                i + 1, abi[i + 1], self.read_reg(i as u32 + 1),
+                i + 2, abi[i + 2], self.read_reg(i as u32 + 2),
+                i + 3, abi[i + 3], self.read_reg(i as u32 + 3),
+            )
+        }
+
+        write!(f, "{}", output.trim_start())
+    }
+}
+
+#[derive(Default)]
+struct FRegisters {
+    regs: [u64; 32],
+    written: Option<u32>
+}
+
+impl FRegisters {
+    pub fn read_reg(&self, reg: u32) -> u64 {
+        self.regs[reg as usize]
+    }
+
+    pub fn write_reg(&mut self, reg: u32, value: u64) {
+        self.regs[reg as usize] = value;
+        self.written = Some(reg);
+    }
+}
+
+impl std::fmt::Debug for FRegisters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let abi = [
+            " ft0", " ft1", " ft2", " ft3", " ft4", " ft5", " ft6", " ft7", " fs0", " fs1", " fa0",
+            " fa1", " fa2", " fa3", " fa4", " fa5", " fa6", " fa7", " fs2", " fs3", " fs4", " fs5",
+            " fs6", " fs7", " fs8", " fs9", "fs10", "fs11", " ft8", " ft9", "ft10", "ft11",
+        ];
+
+        let mut output = String::new();
+        for i in (0..32).step_by(4) {
+            output = format!("{}\n\tf{:02}({})={:016x} f{:02}({})={:016x} f{:02}({})={:016x} f{:02}({})={:016x}",
+                output,
+                i, abi[i], self.read_reg(i as u32),
+                i + 1, abi[i + 1], self.read_reg(i as u32 + 1),
+                i + 2, abi[i + 2], self.read_reg(i as u32 + 2),
+                i + 3, abi[i + 3], self.read_reg(i as u32 + 3),
+            )
+        }
+
+        write!(f, "{}", output.trim_start())
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+pub struct CPU {
+    pc: u64,
+    // Address of the instruction being executed, pc already points to the next one
+    instr_pc: u64,
+    iregs: IRegisters,
+    fregs: FRegisters,
+    csr: Csr,
+    itlb: Tlb,
+    dtlb: Tlb,
+    bus: Bus,
+    state: State,
+    // Entry points of the firmware and of the kernel it boots, if they're loaded
+    firmware: Option<u64>,
+    kernel: Option<u64>,
+    // Start and end of the initramfs
+    initrd: Option<(u64, u64)>,
+    symbols: Symbols,
+    tracer: Tracer,
+    // Stopped by WFI until an interrupt is pending
+    waiting: bool
+}
+
+impl CPU {
+    pub fn new() -> Self {
+        Self {
+            pc: ROM_BASE,
+            instr_pc: ROM_BASE,
+            iregs: Default::default(),
+            fregs: Default::default(),
+            csr: Csr::new(),
+            itlb: Tlb::new(),
+            dtlb: Tlb::new(),
+            bus: Bus::new(DRAM_SIZE),
+            state: State::Machine,
+            firmware: None,
+            kernel: None,
+            initrd: None,
+            symbols: Symbols::new(),
+            tracer: Tracer::off(),
+            waiting: false
+        }
+    }
+
+    // Loads an ELF file where its segments say, or a raw binary at addr. Returns the entry point.
+    fn load_image(&mut self, mut file: std::fs::File, addr: u64) -> std::io::Result<u64> {
+        let mut code = vec![];
+        file.read_to_end(&mut code)?;
+
+        if !elf::is_elf(&code) {
+            self.bus.write_bytes(addr, &code).map_err(|_| Error::new(ErrorKind::InvalidData,
+                format!("The image doesn't fit in memory at {:x}", addr)))?;
+            return Ok(addr);
+        }
+
+        let elf = Elf::parse(&code)?;
+        self.symbols.add_elf(&elf);
+
+        for Segment { paddr, mut data, mem_size } in elf.segments {
+            let missing = || Error::new(ErrorKind::InvalidData,
+                format!("ELF: segment at {:x}..{:x} isn't in memory", paddr, paddr.wrapping_add(mem_size)));
+
+            // Nothing bigger than DRAM fits anywhere, and this avoids zeroing a huge buffer
+            if mem_size > DRAM_SIZE as u64 {
+                return Err(missing());
+            }
+
+            // The rest of the segment is BSS
+            data.resize(mem_size as usize, 0);
+            self.bus.write_bytes(paddr, &data).map_err(|_| missing())?;
+        }
+
+        Ok(elf.entry)
+    }
+
+    // The firmware, or a bare program, raw binaries go at the start of DRAM
+    pub fn load_code(&mut self, file: std::fs::File) -> std::io::Result<()> {
+        self.firmware = Some(self.load_image(file, DRAM_BASE)?);
+        Ok(())
+    }
+
+    // The payload the firmware boots, raw binaries go at addr
+    pub fn load_kernel(&mut self, file: std::fs::File, addr: u64) -> std::io::Result<()> {
+        self.kernel = Some(self.load_image(file, addr)?);
+        Ok(())
+    }
+
+    pub fn load_initrd(&mut self, mut file: std::fs::File, addr: u64) -> std::io::Result<()> {
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+
+        self.bus.write_bytes(addr, &data).map_err(|_| Error::new(ErrorKind::InvalidData,
+            format!("The initrd doesn't fit in memory at {:x}", addr)))?;
+        self.initrd = Some((addr, addr + data.len() as u64));
+
+        Ok(())
+    }
+
+    // Symbols for a raw binary, from the ELF file it was made from
+    pub fn load_symbols(&mut self, mut file: std::fs::File) -> std::io::Result<()> {
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+        self.symbols.add_elf(&Elf::parse(&data)?);
+
+        Ok(())
+    }
+
+    pub fn has_symbols(&self) -> bool {
+        !self.symbols.is_empty()
+    }
+
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = tracer;
+    }
+
+    // A trace file is buffered, it has to be flushed before exiting
+    pub fn flush_trace(&mut self) -> std::io::Result<()> {
+        self.tracer.flush()
+    }
+
+    // Once everything is loaded: generates the device tree for the machine as it is now, puts
+    // it at the end of DRAM, and sets up the boot ROM to jump to the firmware (or straight to
+    // the kernel without one). Returns the device tree.
+    pub fn prepare_boot(&mut self) -> Vec<u8> {
+        let misa = self.csr.read(csr::MISA);
+        let extensions: String = "imafdqc".chars().filter(|&letter| misa & (1 << (letter as u8 - b'a')) != 0).collect();
+        let dtb = fdt::generate(&self.bus, &format!("rv64{}_zicsr_zifencei", extensions), self.initrd);
+
+        // 2 MiB aligned, like QEMU
+        let dtb_addr = (DRAM_BASE + DRAM_SIZE as u64 - dtb.len() as u64) & !0x1FFFFF;
+        self.bus.write_bytes(dtb_addr, &dtb).expect("The device tree doesn't fit in memory");
+
+        let entry = self.firmware.or(self.kernel).unwrap_or(DRAM_BASE);
+        let reset_vector = Rom::reset_vector(entry, dtb_addr, self.kernel.unwrap_or(0));
+        self.bus.write_bytes(ROM_BASE, &reset_vector).expect("The reset vector doesn't fit in the ROM");
+
+        dtb
+    }
+
+    pub fn attach_disk(&mut self, disk: VirtioBlk, legacy: bool) -> Result<(), String> {
+        self.bus.attach_disk(disk, legacy)
+    }
+
+    pub fn power_state(&self) -> PowerState {
+        self.bus.power_state()
+    }
+
+    // Resets the hart and the devices, memory and the ROM are kept as is so the loaded program runs again
+    pub fn reset(&mut self) {
+        self.pc = ROM_BASE;
+        self.instr_pc = ROM_BASE;
+        self.iregs = Default::default();
+        self.fregs = Default::default();
+        self.csr = Csr::new();
+        self.itlb = Tlb::new();
+        self.dtlb = Tlb::new();
+        self.state = State::Machine;
+        self.waiting = false;
+        self.bus.reset();
+    }
+
+    fn fetch(&mut self) -> Result<u32, Exception> {
+        let pc = self.pc;
+        let paddr = self.translate(pc, Access::Instruction)?;
+        let low = self.bus.load16(paddr).map_err(|_| Exception::InstructionAccessFault(pc))? as u32;
+        self.instr_pc = pc;
+
+        // Anything but 0b11 in the low bits is a compressed instruction
+        if low & 0x3 != 0x3 {
+            self.pc = pc.wrapping_add(2);
+
+            return Ok(low);
+        }
+
+        // The upper half can be on the next page
+        let high_addr = pc.wrapping_add(2);
+        let high_paddr = if high_addr & 0xFFF == 0 { self.translate(high_addr, Access::Instruction)? } else { paddr + 2 };
+        let high = self.bus.load16(high_paddr).map_err(|_| Exception::InstructionAccessFault(high_addr))? as u32;
+        let instr = (high << 16) | low;
+
+        self.pc = pc.wrapping_add(4);
+
+        Ok(instr)
+    }
+
+    fn execute(&mut self, instr: u32) -> Result<(), Exception> {
+        // Compressed instructions run as their 32-bit equivalent, but traps report the original bits
+        if instr & 0x3 != 0x3 {
+            let expanded = rvc::expand(instr as u16).ok_or(Exception::IllegalInstruction(instr))?;
+
+            return self.execute(expanded).map_err(|exception| match exception {
+                Exception::IllegalInstruction(_) => Exception::IllegalInstruction(instr),
+                exception => exception
+            });
+        }
+
+        let opcode = instr & 0x7F;
+        let funct3 = (instr >> 12) & 0x7;
+        let rd = (instr >> 7) & 0x1F;
+        let rs1 = (instr >> 15) & 0x1F;
+
+        match opcode {
+            0x03 => {
+                // RV32/64I load functions
+                let imm = ((instr as i32 as i64) >> 20) as u64;
+                let addr = self.iregs.read_reg(rs1).wrapping_add(imm);
+
+                match funct3 {
+                    // LB
+                    0x0 => { let value = self.load(addr, 1)? as i8 as i64 as u64; self.iregs.write_reg(rd, value) }
+                    // LH
+                    0x1 => { let value = self.load(addr, 2)? as i16 as i64 as u64; self.iregs.write_reg(rd, value) }
+                    // LW
+                    0x2 => { let value = self.load(addr, 4)? as i32 as i64 as u64; self.iregs.write_reg(rd, value) }
+                    // LD
+                    0x3 => { let value = self.load(addr, 8)?; self.iregs.write_reg(rd, value) }
+                    // LBU
+                    0x4 => { let value = self.load(addr, 1)?; self.iregs.write_reg(rd, value) }
+                    // LHU
+                    0x5 => { let value = self.load(addr, 2)?; self.iregs.write_reg(rd, value) }
+                    // LWU
+                    0x6 => { let value = self.load(addr, 4)?; self.iregs.write_reg(rd, value) }
+                    _ => return Err(Exception::IllegalInstruction(instr))
+                }
+            },
+            0x07 | 0x27 | 0x43 | 0x47 | 0x4B | 0x4F | 0x53 => {
+                // RV32/64F and RV32/64D
+                self.execute_float(instr)?;
+            }
+            0x0F => {
+                // FENCE and FENCE.I, there is a single hart and no cache so they do nothing
+            }
+            0x13 => {
+                // Immediate functions
+                let imm = ((instr as i32 as i64) >> 20) as u64;
+                let funct7 = (instr >> 30) & 3;
+                let shamt = (instr >> 20) & 0x3F;
+
+                match (funct3, funct7) {
+                    // ADDI
+                    (0x0, _) => { self.iregs.write_reg(rd, self.iregs.read_reg(rs1).wrapping_add(imm)) }
+                    // SLLI
+                    (0x1, _) => { self.iregs.write_reg(rd, self.iregs.read_reg(rs1) << shamt) }
+                    // SLTI
+                    (0x2, _) => { self.iregs.write_reg(rd, ((self.iregs.read_reg(rs1) as i64) < imm as i64) as u64) }
+                    // SLTIU
+                    (0x3, _) => { self.iregs.write_reg(rd, (self.iregs.read_reg(rs1) < imm) as u64)},
+                    // XORI
+                    (0x4, _) => { self.iregs.write_reg(rd, self.iregs.read_reg(rs1) ^ imm)}
+                    // SRLI
+                    (0x5, 0x0) => { self.iregs.write_reg(rd, self.iregs.read_reg(rs1) >> shamt) }
+                    // SRAI
+                    (0x5, 0x1) => { self.iregs.write_reg(rd, (self.iregs.read_reg(rs1) as i64 >> shamt) as u64) }
+                    // ORI
+                    (0x6, _) => { self.iregs.write_reg(rd, self.iregs.read_reg(rs1) | imm) }
+                    // ANDI
+                    (0x7, _) => { self.iregs.write_reg(rd, self.iregs.read_reg(rs1) & imm) }
+                    _ => return Err(Exception::IllegalInstruction(instr))
+                }
+            },
+            0x17 => {
+                // AUIPC
+                let imm = (instr & 0xFFFFF000) as i32 as i64 as u64;
+                let value = self.instr_pc.wrapping_add(imm);
+
+                self.iregs.write_reg(rd, value);
+            }
+            0x1B => {
+                // RV64I immediates
+                let imm = (instr as i32 as i64 >> 20) as u64;
+                let funct7 = (instr >> 30) & 3;
+                let shamt = (instr >> 20) & 0x1F;
+
+                match (funct3, funct7) {
+                    // ADDIW
+                    (0x0, _) => { self.iregs.write_reg(rd, self.iregs.read_reg(rs1).wrapping_add(imm as i64 as i32 as u64))}
+                    // SLLIW
+                    (0x1, _) => { self.iregs.write_reg(rd, (self.iregs.read_reg(rs1) << shamt) as i32 as u64) }
+                    // SRLIW
+                    (0x5, 0x0) => { self.iregs.write_reg(rd, (self.iregs.read_reg(rs1) >> shamt) as i32 as u32 as u64) }
+                    // SRAIW
+                    (0x5, 0x1) => { self.iregs.write_reg(rd, ((self.iregs.read_reg(rs1) as i32) >> shamt) as u32 as u64) }
+                    _ => return Err(Exception::IllegalInstruction(instr))
+                }
+            },
+            0x23 => {
+                // RV32/64I store instructions
+                let imm = (((instr & 0xfe000000) as i32 as i64) >> 20) as u64 | ((instr >> 7) & 0x1F) as u64;
+                let rs2 = (instr >> 20) & 0x1F;
+                let addr = self.iregs.read_reg(rs1).wrapping_add(imm);
+                let value = self.iregs.read_reg(rs2);
+
+                match funct3 {
+                    // SB
+                    0x0 => { self.store(addr, 1, value)? }
+                    // SH
+                    0x1 => { self.store(addr, 2, value)? }
+                    // SW
+                    0x2 => { self.store(addr, 4, value)? }
+                    // SD
+                    0x3 => { self.store(addr, 8, value)? }
+                    _ => return Err(Exception::IllegalInstruction(instr))
+                }
+            }
+            0x2F => {
+                // RV32/64A atomic instructions
+                let rs2 = (instr >> 20) & 0x1F;
+                let funct5 = instr >> 27;
+                let addr = self.iregs.read_reg(rs1);
+
+                // The aq and rl bits don't matter as there is a single hart executing in order
+                let size = match funct3 {
+                    0x2 => 4,
+                    0x3 => 8,
+                    _ => return Err(Exception::IllegalInstruction(instr))
+                };
+
+                // Atomics have to be naturally aligned
+                if addr & (size - 1) != 0 {
+                    return Err(if funct5 == 0x02 {
+                        Exception::LoadAddressMisaligned(addr)
+                    } else {
+                        Exception::StoreAddressMisaligned(addr)
+                    });
+                }
+
+                // Sign-extends words, leaves doublewords as is
+                let extend = |value: u64| if size == 4 { value as i32 as i64 as u64 } else { value };
+
+                match funct5 {
+                    // LR
+                    0x02 if rs2 == 0 => {
+                        let paddr = self.translate(addr, Access::Load)?;
+                        let value = self.load_physical(addr, paddr, size).map_err(|_| Exception::LoadAccessFault(addr))?;
+
+                        self.bus.reserve(paddr);
+                        self.iregs.write_reg(rd, extend(value));
+                    }
+                    // SC
+                    0x03 => {
+                        let paddr = self.translate(addr, Access::Store)?;
+
+                        if self.bus.take_reservation(paddr) {
+                            self.store_physical(addr, paddr, size, self.iregs.read_reg(rs2))
+                                .map_err(|_| Exception::StoreAccessFault(addr))?;
+                            self.iregs.write_reg(rd, 0);
+                        } else {
+                            self.iregs.write_reg(rd, 1);
+                        }
+                    }
+                    // AMOs
+                    0x00 | 0x01 | 0x04 | 0x08 | 0x0C | 0x10 | 0x14 | 0x18 | 0x1C => {
+                        // AMOs fault as stores, even for the read part
+                        let paddr = self.translate(addr, Access::Store)?;
+                        let old = extend(self.load_physical(addr, paddr, size).map_err(|_| Exception::StoreAccessFault(addr))?);
+                        let src = extend(self.iregs.read_reg(rs2));
+
+                        let value = match funct5 {
+                            // AMOADD
+                            0x00 => old.wrapping_add(src),
+                            // AMOSWAP
+                            0x01 => src,
+                            // AMOXOR
+                            0x04 => old ^ src,
+                            // AMOOR
+                            0x08 => old | src,
+                            // AMOAND
+                            0x0C => old & src,
+                            // AMOMIN
+                            0x10 => (old as i64).min(src as i64) as u64,
+                            // AMOMAX
+                            0x14 => (old as i64).max(src as i64) as u64,
+                            // AMOMINU, words are compared as 32-bit unsigned values
+                            0x18 => if size == 4 { (old as u32).min(src as u32) as u64 } else { old.min(src) },
+                            // AMOMAXU
+                            0x1C => if size == 4 { (old as u32).max(src as u32) as u64 } else { old.max(src) },
+                            _ => unreachable!()
+                        };
+
+                        self.store_physical(addr, paddr, size, value).map_err(|_| Exception::StoreAccessFault(addr))?;
+                        self.iregs.write_reg(rd, old);
+                    }
+                    _ => return Err(Exception::IllegalInstruction(instr))
+                }
+            }
+            0x37 => {
+                // LUI
+                let imm = (instr & 0xFFFFF000) as i32 as i64 as u64;
+                self.iregs.write_reg(rd, imm as i32 as i64 as u64);
+            }
+            0x33 => {
+                // RV32/64I Register-to-register functions
+                let rs2 = (instr >> 20) & 0x1F;
+                let funct7 = instr >> 25;
+
+                match (funct3, funct7) {
+                    // ADD
+                    (0x0, 0) => { self.iregs.write_reg(rd, self.iregs.read_reg(rs1).wrapping_add(self.iregs.read_reg(rs2))) }
+                    // SUB
+                    (0x0, 0x20) => { self.iregs.write_reg(rd, self.iregs.read_reg(rs1).wrapping_sub(self.iregs.read_reg(rs2))) }
+                    // SLL
+                    (0x1, 0) => { self.iregs.write_reg(rd, self.iregs.read_reg(rs1) << (self.iregs.read_reg(rs2) & 0x3F)) }
+                    // SLT
+                    (0x2, 0) => { self.iregs.write_reg(rd, ((self.iregs.read_reg(rs1) as i64) < (self.iregs.read_reg(rs2) as i64)) as u64)}
+                    // SLTU
+                    (0x3, 0) => { self.iregs.write_reg(rd, (self.iregs.read_reg(rs1) < self.iregs.read_reg(rs2)) as u64) }
+                    // XOR
+                    (0x4, 0) => { self.iregs.write_reg(rd, self.iregs.read_reg(rs1) ^ self.iregs.read_reg(rs2)) }
+                    // SRL
+                    (0x5, 0) => { self.iregs.write_reg(rd, self.iregs.read_reg(rs1) >> (self.iregs.read_reg(rs2) & 0x3F)) }
+                    // SRA
+                    (0x5, 0x20) => { self.iregs.write_reg(rd, ((self.iregs.read_reg(rs1) as i64) >> (self.iregs.read_reg(rs2) & 0x3F)) as u64) }
+                    // OR
+                    (0x6, 0) => { self.iregs.write_reg(rd, self.iregs.read_reg(rs1) | self.iregs.read_reg(rs2)) }
+                    // AND
+                    (0x7, 0) => { self.iregs.write_reg(rd, self.iregs.read_reg(rs1) & self.iregs.read_reg(rs2)) }
+
+                    // M extension begins here
+                    // MUL
+                    (0x0, 1) => { self.iregs.write_reg(rd, self.iregs.read_reg(rs1).wrapping_mul(self.iregs.read_reg(rs2))) }
+                    // MULH
+                    (0x1, 1) => { self.iregs.write_reg(
+                        rd, 
+                        (((self.iregs.read_reg(rs1) as i64 as i128).wrapping_mul(self.iregs.read_reg(rs2) as i64 as i128)) >> 64) as u64)
+                    }
+                    // MULHSU
+                    (0x2, 1) => { self.iregs.write_reg(
+                        rd, 
+                        (((self.iregs.read_reg(rs1) as i64 as i128).wrapping_mul(self.iregs.read_reg(rs2) as i128)) >> 64) as u64)
+                    }
+                    // MULHU
+                    (0x3, 1) => { self.iregs.write_reg(
+                        rd, 
+                        (((self.iregs.read_reg(rs1) as u128).wrapping_mul(self.iregs.read_reg(rs2) as u128)) >> 64) as u64)
+                    }
+                    // Division by zero doesn't trap: the quotient has all bits set and the remainder
+                    // is the dividend. The overflow of DIV and REM wraps.
+                    // DIV
+                    (0x4, 1) => {
+                        let (a, b) = (self.iregs.read_reg(rs1) as i64, self.iregs.read_reg(rs2) as i64);
+                        self.iregs.write_reg(rd, if b == 0 { u64::MAX } else { a.wrapping_div(b) as u64 })
+                    }
+                    // DIVU
+                    (0x5, 1) => {
+                        let (a, b) = (self.iregs.read_reg(rs1), self.iregs.read_reg(rs2));
+                        self.iregs.write_reg(rd, a.checked_div(b).unwrap_or(u64::MAX))
+                    }
+                    // REM
+                    (0x6, 1) => {
+                        let (a, b) = (self.iregs.read_reg(rs1) as i64, self.iregs.read_reg(rs2) as i64);
+                        self.iregs.write_reg(rd, if b == 0 { a as u64 } else { a.wrapping_rem(b) as u64 })
+                    }
+                    // REMU
+                    (0x7, 1) => {
+                        let (a, b) = (self.iregs.read_reg(rs1), self.iregs.read_reg(rs2));
+                        self.iregs.write_reg(rd, a.checked_rem(b).unwrap_or(a))
+                    }
+                    _ => return Err(Exception::IllegalInstruction(instr))
+                }
+            },
+            0x3B => {
+                // RV64I register-to-register
+                let rs2 = (instr >> 20) & 0x1f;
+                let funct7 = instr >> 25;
+
+                match (funct3, funct7) {
+                    // ADD
+                    (0x0, 0) => { self.iregs.write_reg(rd, self.iregs.read_reg(rs1).wrapping_add(self.iregs.read_reg(rs2) as i64 as i32 as u64)) }
+                    // SUB
+                    (0x0, 0x20) => { self.iregs.write_reg(rd, self.iregs.read_reg(rs1).wrapping_sub(self.iregs.read_reg(rs2) as i64 as i32 as u64)) }
+                    // SLLW
+                    (0x1, 0) => { self.iregs.write_reg(rd, (self.iregs.read_reg(rs1) << (self.iregs.read_reg(rs2) & 0x1f)) as i32 as u64) }
+                    // SRLW
+                    (0x5, 0) => { self.iregs.write_reg(rd, (self.iregs.read_reg(rs1) >> (self.iregs.read_reg(rs2) & 0x1f)) as i32 as u32 as u64) }
+                    // SRAW
+                    (0x5, 0x20) => { self.iregs.write_reg(rd, ((self.iregs.read_reg(rs1) as i32) >> (self.iregs.read_reg(rs2) & 0x1f)) as u32 as u64) }
+                    // Here begins the M extension
+                    // MULW
+                    (0x0, 1) => { self.iregs.write_reg(
+                        rd, 
+                        (self.iregs.read_reg(rs1) as u32 as i32).wrapping_mul(self.iregs.read_reg(rs2) as u32 as i32) as i64 as u64)
+                    }
+                    // Same as the 64-bit ones for division by zero and overflow, on 32 bits
+                    // DIVW
+                    (0x4, 1) => {
+                        let (a, b) = (self.iregs.read_reg(rs1) as i32, self.iregs.read_reg(rs2) as i32);
+                        self.iregs.write_reg(rd, if b == 0 { -1 } else { a.wrapping_div(b) } as i64 as u64)
+                    }
+                    // DIVUW
+                    (0x5, 1) => {
+                        let (a, b) = (self.iregs.read_reg(rs1) as u32, self.iregs.read_reg(rs2) as u32);
+                        self.iregs.write_reg(rd, a.checked_div(b).unwrap_or(u32::MAX) as i32 as i64 as u64)
+                    }
+                    // REMW
+                    (0x6, 1) => {
+                        let (a, b) = (self.iregs.read_reg(rs1) as i32, self.iregs.read_reg(rs2) as i32);
+                        self.iregs.write_reg(rd, if b == 0 { a } else { a.wrapping_rem(b) } as i64 as u64)
+                    }
+                    // REMUW
+                    (0x7, 1) => {
+                        let (a, b) = (self.iregs.read_reg(rs1) as u32, self.iregs.read_reg(rs2) as u32);
+                        self.iregs.write_reg(rd, a.checked_rem(b).unwrap_or(a) as i32 as i64 as u64)
+                    }
+                    _ => return Err(Exception::IllegalInstruction(instr))
+                }
+            },
+            0x63 => {
+                // RV32/64I branch instructions
+                let offset = (((instr & 0x80000000) as i32 as i64) >> 19) as u64 |
+                    ((instr & 0x80) << 4) as u64 |
+                    ((instr >> 20) & 0x7e0) as u64 |
+                    ((instr >> 7) & 0x1e) as u64;
+                let addr = self.instr_pc.wrapping_add(offset);
+                let rs2 = (instr >> 20) & 0x1f;
+
+                let (a, b) = (self.iregs.read_reg(rs1), self.iregs.read_reg(rs2));
+
+                let taken = match funct3 {
+                    // BEQ
+                    0x0 => a == b,
+                    // BNE
+                    0x1 => a != b,
+                    // BLT
+                    0x4 => (a as i64) < b as i64,
+                    // BGE
+                    0x5 => a as i64 >= b as i64,
+                    // BLTU
+                    0x6 => a < b,
+                    // BGEU
+                    0x7 => a >= b,
+                    _ => return Err(Exception::IllegalInstruction(instr))
+                };
+
+                if taken { self.jump(addr)?; }
+            }
+            0x67 => {
+                // JALR
+                let offset = (instr as i32) >> 20;
+                let old_pc = self.pc;
+                let target = (self.iregs.read_reg(rs1).wrapping_add(offset as u32 as u64)) & !1;
+
+                self.jump(target)?;
+                self.iregs.write_reg(rd, old_pc);
+            },
+            0x6F => {
+                // JAL
+                // Ok so getting the offset is complicated………
+                let offset = ((instr & 0x80000000) as i32 as i64 >> 20) as u64 | // This is the bit sign 
+                (instr & 0xff000) as u64 |
+                ((instr >> 9) & 0x800) as u64 |
+                ((instr >> 20) & 0x7fe) as u64;
+
+                let old_pc = self.pc;
+
+                self.jump(self.instr_pc.wrapping_add(offset))?;
+                self.iregs.write_reg(rd, old_pc);
+            },
+            0x73 => {
+                // Environment calls and breakpoints, trap return, and Zicsr
+                let csr = (instr >> 20) as u16;
+
+                match funct3 {
+                    0x0 if rd == 0 && instr >> 25 == 0x09 => {
+                        // SFENCE.VMA
+                        if self.state == State::User ||
+                            (self.state == State::Supervisor && self.csr.read(csr::MSTATUS) & csr::MSTATUS_TVM != 0) {
+                            return Err(Exception::IllegalInstruction(instr));
+                        }
+
+                        let rs2 = (instr >> 20) & 0x1F;
+                        let vaddr = if rs1 != 0 { Some(self.iregs.read_reg(rs1)) } else { None };
+                        let asid = if rs2 != 0 { Some(self.iregs.read_reg(rs2) as u16) } else { None };
+
+                        self.sfence_vma(vaddr, asid);
+                    }
+                    0x0 if rd == 0 && rs1 == 0 => {
+                        match csr {
+                            // ECALL
+                            0x000 => return Err(match self.state {
+                                State::User => Exception::EnvironmentCallFromUMode,
+                                State::Supervisor => Exception::EnvironmentCallFromSMode,
+                                State::Machine => Exception::EnvironmentCallFromMMode
+                            }),
+                            // EBREAK
+                            0x001 => return Err(Exception::Breakpoint(self.instr_pc)),
+                            // SRET
+                            0x102 => {
+                                let mstatus = self.csr.read(csr::MSTATUS);
+
+                                if self.state < State::Supervisor ||
+                                    (self.state == State::Supervisor && mstatus & csr::MSTATUS_TSR != 0) {
+                                    return Err(Exception::IllegalInstruction(instr));
+                                }
+
+                                let sie = if mstatus & csr::MSTATUS_SPIE != 0 { csr::MSTATUS_SIE } else { 0 };
+                                let spp = (mstatus & csr::MSTATUS_SPP) >> 8;
+
+                                // SPP can't be M-mode, so MPRV is always cleared
+                                self.csr.write(csr::MSTATUS,
+                                    (mstatus & !(csr::MSTATUS_SIE | csr::MSTATUS_SPP | csr::MSTATUS_MPRV)) | sie | csr::MSTATUS_SPIE);
+                                self.state = State::from_bits(spp);
+                                self.pc = self.csr.read(csr::SEPC);
+                            }
+                            // MRET
+                            0x302 => {
+                                if self.state < State::Machine {
+                                    return Err(Exception::IllegalInstruction(instr));
+                                }
+
+                                let mstatus = self.csr.read(csr::MSTATUS);
+                                let mie = if mstatus & csr::MSTATUS_MPIE != 0 { csr::MSTATUS_MIE } else { 0 };
+                                let mpp = State::from_bits((mstatus & csr::MSTATUS_MPP) >> 11);
+                                let mprv = if mpp == State::Machine { mstatus & csr::MSTATUS_MPRV } else { 0 };
+
+                                self.csr.write(csr::MSTATUS,
+                                    (mstatus & !(csr::MSTATUS_MIE | csr::MSTATUS_MPP | csr::MSTATUS_MPRV)) | mie | mprv | csr::MSTATUS_MPIE);
+                                self.state = mpp;
+                                self.pc = self.csr.read(csr::MEPC);
+                            }
+                            // WFI. A NOP would be a legal implementation too, pending interrupts are
+                            // taken at the next instruction anyway, but this way an idle guest
+                            // doesn't run its idle loop over and over.
+                            0x105 => {
+                                if self.state < State::Machine && self.csr.read(csr::MSTATUS) & csr::MSTATUS_TW != 0 {
+                                    return Err(Exception::IllegalInstruction(instr));
+                                }
+
+                                self.waiting = true;
+                            }
+                            _ => return Err(Exception::IllegalInstruction(instr))
+                        }
+                    }
+                    0x1..=0x3 | 0x5..=0x7 => {
+                        if !self.csr_accessible(csr) {
+                            return Err(Exception::IllegalInstruction(instr));
+                        }
+
+                        // The immediate variants use the rs1 field as a 5-bit zero-extended value
+                        let source = if funct3 & 0x4 != 0 { rs1 as u64 } else { self.iregs.read_reg(rs1) };
+                        let old = self.csr.read(csr);
+
+                        let new = match funct3 & 0x3 {
+                            // CSRRW(I)
+                            0x1 => Some(source),
+                            // CSRRS(I), only writes if the source isn't x0/zero
+                            0x2 => if rs1 != 0 { Some(old | source) } else { None },
+                            // CSRRC(I), same
+                            0x3 => if rs1 != 0 { Some(old & !source) } else { None },
+                            _ => unreachable!()
+                        };
+
+                        if let Some(value) = new {
+                            if Csr::read_only(csr) {
+                                return Err(Exception::IllegalInstruction(instr));
+                            }
+
+                            self.csr.write(csr, value);
+                        }
+
+                        self.iregs.write_reg(rd, old);
+                    }
+                    _ => return Err(Exception::IllegalInstruction(instr))
+                }
+            }
+            _ => return Err(Exception::IllegalInstruction(instr))
+        }
+
+        Ok(())
+    }
+
+    // With C, instructions only need to be aligned on 2 bytes
+    fn jump(&mut self, target: u64) -> Result<(), Exception> {
+        if target & 0x1 != 0 {
+            return Err(Exception::InstructionAddressMisaligned(target));
+        }
+
+        self.pc = target;
+        Ok(())
+    }
+
+    fn csr_accessible(&self, csr: u16) -> bool {
+        if !Csr::exists(csr) || (self.state as u64) < Csr::privilege(csr) {
+            return false;
+        }
+
+        // The floating-point CSRs are unavailable while FS is off
+        if (csr::FFLAGS..=csr::FCSR).contains(&csr) && !self.csr.fs_enabled() {
+            return false;
+        }
+
+        // TVM traps satp accesses from S-mode
+        if csr == csr::SATP && self.state == State::Supervisor && self.csr.read(csr::MSTATUS) & csr::MSTATUS_TVM != 0 {
+            return false;
+        }
+
+        // Counters are only visible in lower privilege modes if enabled by the upper ones
+        if (csr::CYCLE..=csr::INSTRET).contains(&csr) {
+            let bit = 1 << (csr - csr::CYCLE);
+
+            if self.state < State::Machine && self.csr.read(csr::MCOUNTEREN) & bit == 0 {
+                return false;
+            }
+
+            if self.state < State::Supervisor && self.csr.read(csr::SCOUNTEREN) & bit == 0 {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn take_trap(&mut self, epc: u64, cause: u64, value: u64, interrupt: bool) {
+        let deleg = self.csr.read(if interrupt { csr::MIDELEG } else { csr::MEDELEG });
+        let mstatus = self.csr.read(csr::MSTATUS);
+
+        if self.state <= State::Supervisor && (deleg >> cause) & 1 == 1 {
+            // Handled in S-mode
+            let stvec = self.csr.read(csr::STVEC);
+            let base = stvec & !0x3;
+            let spie = if mstatus & csr::MSTATUS_SIE != 0 { csr::MSTATUS_SPIE } else { 0 };
+            let spp = (self.state as u64) << 8;
+
+            self.pc = if interrupt && stvec & 0x1 == 1 { base.wrapping_add(4 * cause) } else { base };
+
+            self.csr.write(csr::SEPC, epc);
+            self.csr.write(csr::SCAUSE, ((interrupt as u64) << 63) | cause);
+            self.csr.write(csr::STVAL, value);
+            self.csr.write(csr::MSTATUS, (mstatus & !(csr::MSTATUS_SIE | csr::MSTATUS_SPIE | csr::MSTATUS_SPP)) | spie | spp);
+            self.state = State::Supervisor;
+        } else {
+            let mtvec = self.csr.read(csr::MTVEC);
+            let base = mtvec & !0x3;
+            let mpie = if mstatus & csr::MSTATUS_MIE != 0 { csr::MSTATUS_MPIE } else { 0 };
+            let mpp = (self.state as u64) << 11;
+
+            // In vectored mode, interrupts go to BASE + 4 * cause, exceptions always go to BASE
+            self.pc = if interrupt && mtvec & 0x1 == 1 { base.wrapping_add(4 * cause) } else { base };
+
+            self.csr.write(csr::MEPC, epc);
+            self.csr.write(csr::MCAUSE, ((interrupt as u64) << 63) | cause);
+            self.csr.write(csr::MTVAL, value);
+            self.csr.write(csr::MSTATUS, (mstatus & !(csr::MSTATUS_MIE | csr::MSTATUS_MPIE | csr::MSTATUS_MPP)) | mpie | mpp);
+            self.state = State::Machine;
+        }
+    }
+
+    // Highest priority interrupt that is pending, enabled, and not masked in the current mode
+    fn pending_interrupt(&self) -> Option<u64> {
+        let pending = self.csr.read(csr::MIP) & self.csr.read(csr::MIE);
+        if pending == 0 {
+            return None;
+        }
+
+        let mstatus = self.csr.read(csr::MSTATUS);
+        let mideleg = self.csr.read(csr::MIDELEG);
+
+        // Interrupts targeting a more privileged mode are always enabled, the ones targeting
+        // a less privileged mode never are
+        let m_enabled = self.state < State::Machine || mstatus & csr::MSTATUS_MIE != 0;
+        let s_enabled = self.state < State::Supervisor ||
+            (self.state == State::Supervisor && mstatus & csr::MSTATUS_SIE != 0);
+
+        let priority = [csr::MIP_MEIP, csr::MIP_MSIP, csr::MIP_MTIP, csr::MIP_SEIP, csr::MIP_SSIP, csr::MIP_STIP];
+
+        priority.iter()
+            .filter(|&&bit| pending & bit != 0)
+            .find(|&&bit| if mideleg & bit != 0 { s_enabled } else { m_enabled })
+            .map(|bit| bit.trailing_zeros() as u64)
+    }
+
+    fn end_trace(&mut self, pc: u64, instr: Option<u32>, exception: Option<Exception>) {
+        if let Some(reg) = self.iregs.written {
+            self.tracer.xreg(reg, self.iregs.read_reg(reg));
+        }
+
+        if let Some(reg) = self.fregs.written {
+            self.tracer.freg(reg, self.fregs.read_reg(reg));
+        }
+
+        self.tracer.end(pc, instr, exception, &self.symbols);
+    }
+
+    pub fn run_instr(&mut self) {
+        // Devices drive some bits of mip directly, and the time CSR
+        self.csr.set_interrupts_pending(self.bus.local_interrupts());
+        self.csr.set_time(self.bus.mtime());
+
+        // WFI wakes up on any interrupt that's enabled in mie, even if it isn't taken. Until
+        // then only the devices run, time goes on for the timer.
+        if self.waiting {
+            if self.csr.read(csr::MIP) & self.csr.read(csr::MIE) == 0 {
+                self.bus.tick();
+                return;
+            }
+
+            self.waiting = false;
+        }
+
+        if let Some(cause) = self.pending_interrupt() {
+            self.tracer.interrupt(self.pc, self.state as u64, cause);
+            self.take_trap(self.pc, cause, 0, true);
+        } else {
+            let pc = self.pc;
+            self.tracer.begin(pc, self.state as u64);
+            self.iregs.written = None;
+            self.fregs.written = None;
+
+            let instr = self.fetch();
+            let result = instr.and_then(|instr| self.execute(instr));
+            self.end_trace(pc, instr.ok(), result.err());
+
+            match result {
+                Ok(()) => self.csr.tick(),
+                Err(exception) => self.take_trap(pc, exception.code(), exception.value(), false)
+            }
+        }
+
+        self.bus.tick();
+    }
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for CPU {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, 
+            "CPU {{\n\
+            \tPC: {:016x}, State: {:?}, Bus: {:?}\n\
+            \t{:?}\n\
+            \t{:?}\n\
+            \t{:?}\n\
+            \tiTLB: {:?}, dTLB: {:?}\n\
+            }}", self.pc, self.state, self.bus, self.iregs, self.fregs, self.csr, self.itlb, self.dtlb)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ECALL: u32 = 0x00000073;
+    const EBREAK: u32 = 0x00100073;
+    const MRET: u32 = 0x30200073;
+    const SRET: u32 = 0x10200073;
+    const WFI: u32 = 0x10500073;
+    // csrrw a0, mscratch, a1 and csrr a0, cycle
+    const CSRRW_MSCRATCH: u32 = 0x34059573;
+    const RDCYCLE: u32 = 0xc0002573;
+    const RDTIME: u32 = 0xc0102573;
+    // lr.d a0, (a1), sc.d a0, a2, (a1), and the same for words
+    const LR_D: u32 = 0x1005b52f;
+    const SC_D: u32 = 0x18c5b52f;
+    const LR_W: u32 = 0x1005a52f;
+    const SC_W: u32 = 0x18c5a52f;
+    // sd a2, 0(a3) and sb a2, 0(a3)
+    const SD: u32 = 0x00c6b023;
+    const SB: u32 = 0x00c68023;
+    // amoadd.w, amomaxu.w and amomin.d a0, a2, (a1)
+    const AMOADD_W: u32 = 0x00c5a52f;
+    const AMOMAXU_W: u32 = 0xe0c5a52f;
+    const AMOMIN_D: u32 = 0x80c5b52f;
+    // ld a0, 0(a1)
+    const LD: u32 = 0x0005b503;
+    // div, divu, rem, remu a0, a1, a2, and their W versions with 0x3b as opcode
+    const DIV: u32 = 0x02c5c533;
+    const DIVU: u32 = 0x02c5d533;
+    const REM: u32 = 0x02c5e533;
+    const REMU: u32 = 0x02c5f533;
+    const W: u32 = 0x3b ^ 0x33;
+
+    // A hart that starts in DRAM rather than in the boot ROM
+    fn cpu() -> CPU {
+        let mut cpu = CPU::new();
+        cpu.pc = DRAM_BASE;
+        cpu
+    }
+
+    // Runs a single instruction from the current pc
+    fn exec(cpu: &mut CPU, instr: u32) {
+        cpu.bus.store(cpu.pc, 4, instr as u64).unwrap();
+        cpu.run_instr();
+    }
+
+    #[test]
+    fn exceptions_go_through_mtvec() {
+        let mut cpu = cpu();
+        cpu.csr.write(csr::MTVEC, 0x80001000);
+        cpu.csr.write(csr::MSTATUS, csr::MSTATUS_MIE);
+
+        exec(&mut cpu, ECALL);
+        assert_eq!(cpu.pc, 0x80001000);
+        assert_eq!(cpu.csr.read(csr::MEPC), 0x80000000);
+        assert_eq!(cpu.csr.read(csr::MCAUSE), 11);
+        assert_eq!(cpu.csr.read(csr::MSTATUS) & (csr::MSTATUS_MIE | csr::MSTATUS_MPIE), csr::MSTATUS_MPIE);
+        // Nothing retired
+        assert_eq!(cpu.csr.read(csr::MINSTRET), 0);
+
+        exec(&mut cpu, MRET);
+        assert_eq!(cpu.pc, 0x80000000);
+        assert_eq!(cpu.csr.read(csr::MSTATUS) & (csr::MSTATUS_MIE | csr::MSTATUS_MPIE), csr::MSTATUS_MIE | csr::MSTATUS_MPIE);
+    }
+
+    #[test]
+    fn trap_values() {
+        let mut cpu = cpu();
+        cpu.csr.write(csr::MTVEC, 0x80001000);
+
+        cpu.pc = 0x80000000;
+        exec(&mut cpu, 0xFFFFFFFF);
+        assert_eq!((cpu.csr.read(csr::MCAUSE), cpu.csr.read(csr::MTVAL)), (2, 0xFFFFFFFF));
+
+        cpu.pc = 0x80000000;
+        exec(&mut cpu, EBREAK);
+        assert_eq!((cpu.csr.read(csr::MCAUSE), cpu.csr.read(csr::MTVAL)), (3, 0x80000000));
+
+        // Nothing is mapped at 0
+        cpu.pc = 0x80000000;
+        cpu.iregs.write_reg(11, 0x8);
+        exec(&mut cpu, LD);
+        assert_eq!((cpu.csr.read(csr::MCAUSE), cpu.csr.read(csr::MTVAL)), (5, 0x8));
+    }
+
+    #[test]
+    fn vectored_mtvec() {
+        let mut cpu = cpu();
+        cpu.csr.write(csr::MTVEC, 0x80001001);
+
+        // Only interrupts use the vector
+        exec(&mut cpu, ECALL);
+        assert_eq!(cpu.pc, 0x80001000);
+
+        cpu.take_trap(0x80000000, 7, 0, true);
+        assert_eq!(cpu.pc, 0x80001000 + 4 * 7);
+        assert_eq!(cpu.csr.read(csr::MCAUSE), (1 << 63) | 7);
+    }
+
+    #[test]
+    fn division() {
+        let mut cpu = cpu();
+        let mut divide = |instr: u32, a: u64, b: u64| {
+            cpu.pc = 0x80000000;
+            cpu.iregs.write_reg(11, a);
+            cpu.iregs.write_reg(12, b);
+            exec(&mut cpu, instr);
+            cpu.iregs.read_reg(10)
+        };
+
+        assert_eq!(divide(DIV, -7i64 as u64, 2), -3i64 as u64);
+        assert_eq!(divide(REM, -7i64 as u64, 2), -1i64 as u64);
+
+        // By zero
+        assert_eq!(divide(DIV, 7, 0), u64::MAX);
+        assert_eq!(divide(DIVU, 7, 0), u64::MAX);
+        assert_eq!(divide(REM, 7, 0), 7);
+        assert_eq!(divide(REMU, 7, 0), 7);
+        assert_eq!(divide(DIV ^ W, 0x1_0000_0007, 0x1_0000_0000), u64::MAX);
+        assert_eq!(divide(DIVU ^ W, 7, 0), u64::MAX);
+        assert_eq!(divide(REM ^ W, 0xFFFF_FFF9, 0), -7i64 as u64);
+        assert_eq!(divide(REMU ^ W, 0xFFFF_FFF9, 0), -7i64 as u64);
+
+        // Overflow
+        assert_eq!(divide(DIV, 1 << 63, u64::MAX), 1 << 63);
+        assert_eq!(divide(REM, 1 << 63, u64::MAX), 0);
+        assert_eq!(divide(DIV ^ W, 0x8000_0000, u64::MAX), 0xFFFF_FFFF_8000_0000);
+        assert_eq!(divide(REM ^ W, 0x8000_0000, u64::MAX), 0);
+    }
+
+    // Runs the instruction at 0x80000000 and says whether it trapped, and with what cause
+    fn cause(cpu: &mut CPU, instr: u32) -> Option<u64> {
+        let instret = cpu.csr.read(csr::MINSTRET);
+
+        cpu.pc = 0x80000000;
+        exec(cpu, instr);
+        if cpu.csr.read(csr::MINSTRET) != instret { None } else { Some(cpu.csr.read(csr::MCAUSE)) }
+    }
+
+    #[test]
+    fn delegated_traps_go_to_s_mode() {
+        let mut cpu = cpu();
+        cpu.csr.write(csr::MTVEC, 0x80001000);
+        cpu.csr.write(csr::STVEC, 0x80002000);
+        cpu.csr.write(csr::MEDELEG, 1 << 8);
+        cpu.csr.write(csr::MSTATUS, csr::MSTATUS_SIE);
+        cpu.state = State::User;
+
+        exec(&mut cpu, ECALL);
+        assert_eq!(cpu.pc, 0x80002000);
+        assert_eq!(cpu.state, State::Supervisor);
+        assert_eq!((cpu.csr.read(csr::SCAUSE), cpu.csr.read(csr::SEPC)), (8, 0x80000000));
+        let mstatus = cpu.csr.read(csr::MSTATUS);
+        assert_eq!(mstatus & (csr::MSTATUS_SIE | csr::MSTATUS_SPIE | csr::MSTATUS_SPP), csr::MSTATUS_SPIE);
+
+        // An ecall from S-mode isn't delegated
+        exec(&mut cpu, ECALL);
+        assert_eq!(cpu.pc, 0x80001000);
+        assert_eq!(cpu.state, State::Machine);
+        assert_eq!((cpu.csr.read(csr::MCAUSE), cpu.csr.read(csr::MEPC)), (9, 0x80002000));
+        assert_eq!(cpu.csr.read(csr::MSTATUS) & csr::MSTATUS_MPP, 1 << 11);
+    }
+
+    #[test]
+    fn machine_traps_stay_in_m_mode() {
+        let mut cpu = cpu();
+        cpu.csr.write(csr::MTVEC, 0x80001000);
+        cpu.csr.write(csr::STVEC, 0x80002000);
+        cpu.csr.write(csr::MEDELEG, 1 << 2);
+
+        exec(&mut cpu, 0xFFFFFFFF);
+        assert_eq!(cpu.pc, 0x80001000);
+        assert_eq!(cpu.state, State::Machine);
+        assert_eq!(cpu.csr.read(csr::MCAUSE), 2);
+    }
+
+    #[test]
+    fn trap_returns() {
+        let mut cpu = cpu();
+        cpu.csr.write(csr::MEPC, 0x80003000);
+        cpu.csr.write(csr::SEPC, 0x80004000);
+        cpu.csr.write(csr::MSTATUS, (1 << 11) | csr::MSTATUS_MPRV | csr::MSTATUS_SPIE | csr::MSTATUS_SPP);
+
+        // To S-mode, MPP goes back to U-mode and MPRV is cleared since it's not M-mode
+        exec(&mut cpu, MRET);
+        assert_eq!((cpu.pc, cpu.state), (0x80003000, State::Supervisor));
+        assert_eq!(cpu.csr.read(csr::MSTATUS) & (csr::MSTATUS_MPP | csr::MSTATUS_MPRV), 0);
+
+        // SPP was S-mode
+        exec(&mut cpu, SRET);
+        assert_eq!((cpu.pc, cpu.state), (0x80004000, State::Supervisor));
+        let mstatus = cpu.csr.read(csr::MSTATUS);
+        assert_eq!(mstatus & (csr::MSTATUS_SIE | csr::MSTATUS_SPIE | csr::MSTATUS_SPP), csr::MSTATUS_SIE | csr::MSTATUS_SPIE);
+
+        exec(&mut cpu, SRET);
+        assert_eq!(cpu.state, State::User);
+    }
+
+    #[test]
+    fn privileged_instructions() {
+        let mut cpu = cpu();
+        cpu.csr.write(csr::MTVEC, 0x80001000);
+        cpu.csr.write(csr::STVEC, 0x80002000);
+
+        cpu.state = State::User;
+        assert_eq!(cause(&mut cpu, CSRRW_MSCRATCH), Some(2));
+        cpu.state = State::User;
+        assert_eq!(cause(&mut cpu, MRET), Some(2));
+        cpu.state = State::User;
+        assert_eq!(cause(&mut cpu, SRET), Some(2));
+        cpu.state = State::Supervisor;
+        assert_eq!(cause(&mut cpu, WFI), None);
+        cpu.waiting = false;
+
+        cpu.csr.write(csr::MSTATUS, csr::MSTATUS_TSR | csr::MSTATUS_TW);
+        cpu.state = State::Supervisor;
+        assert_eq!(cause(&mut cpu, SRET), Some(2));
+        cpu.state = State::Supervisor;
+        assert_eq!(cause(&mut cpu, WFI), Some(2));
+        assert_eq!(cause(&mut cpu, WFI), None);
+    }
+
+    #[test]
+    fn counter_enables() {
+        let mut cpu = cpu();
+        cpu.csr.write(csr::MTVEC, 0x80001000);
+
+        cpu.state = State::User;
+        assert_eq!(cause(&mut cpu, RDCYCLE), Some(2));
+
+        // Both levels have to let it through to U-mode
+        cpu.csr.write(csr::MCOUNTEREN, 1);
+        cpu.state = State::Supervisor;
+        assert_eq!(cause(&mut cpu, RDCYCLE), None);
+        cpu.state = State::User;
+        assert_eq!(cause(&mut cpu, RDCYCLE), Some(2));
+
+        cpu.csr.write(csr::SCOUNTEREN, 1);
+        cpu.state = State::User;
+        assert_eq!(cause(&mut cpu, RDCYCLE), None);
+
+        // time has its own bit
+        cpu.state = State::User;
+        assert_eq!(cause(&mut cpu, RDTIME), Some(2));
+        cpu.csr.write(csr::MCOUNTEREN, 0x3);
+        cpu.csr.write(csr::SCOUNTEREN, 0x2);
+        cpu.state = State::User;
+        assert_eq!(cause(&mut cpu, RDTIME), None);
+    }
+
+    #[test]
+    fn time() {
+        let mut cpu = cpu();
+        cpu.bus.store64(0x200BFF8, 1000).unwrap();
+
+        // Read before the instruction, and the CLINT ticks after it
+        exec(&mut cpu, RDTIME);
+        assert_eq!(cpu.iregs.read_reg(10), 1000);
+        exec(&mut cpu, RDTIME);
+        assert_eq!(cpu.iregs.read_reg(10), 1001);
+    }
+
+    #[test]
+    fn timer_interrupts() {
+        let mut cpu = cpu();
+        cpu.csr.write(csr::MTVEC, 0x80001000);
+        cpu.csr.write(csr::MIE, csr::MIP_MTIP);
+        cpu.bus.store64(0x2004000, 3).unwrap();
+
+        // WFI waits for it even with interrupts disabled in mstatus, then goes on
+        cpu.bus.store(0x80000004, 4, 0x00000013).unwrap();
+        exec(&mut cpu, WFI);
+        cpu.run_instr();
+        cpu.run_instr();
+        assert!(cpu.waiting);
+        assert_eq!(cpu.pc, 0x80000004);
+        cpu.run_instr();
+        assert!(!cpu.waiting);
+        assert_eq!(cpu.pc, 0x80000008);
+        assert_eq!(cpu.csr.read(csr::MIP) & csr::MIP_MTIP, csr::MIP_MTIP);
+
+        // Taken once enabled
+        cpu.csr.write(csr::MSTATUS, csr::MSTATUS_MIE);
+        cpu.run_instr();
+        assert_eq!((cpu.pc, cpu.csr.read(csr::MCAUSE)), (0x80001000, (1 << 63) | 7));
+
+        // Moving mtimecmp ahead clears it
+        cpu.bus.store64(0x2004000, u64::MAX).unwrap();
+        cpu.run_instr();
+        assert_eq!(cpu.csr.read(csr::MIP) & csr::MIP_MTIP, 0);
+    }
+
+    // Data for the atomics is at 0x80001000, pointed to by a1, a2 is the value stored
+    fn atomics() -> CPU {
+        let mut cpu = cpu();
+        cpu.csr.write(csr::MTVEC, 0x80002000);
+        cpu.iregs.write_reg(11, 0x80001000);
+        cpu.iregs.write_reg(12, 0x1111);
+        cpu.bus.store64(0x80001000, 0x5555).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn lr_sc() {
+        let mut cpu = atomics();
+
+        // No reservation
+        assert_eq!(cause(&mut cpu, SC_D), None);
+        assert_eq!(cpu.iregs.read_reg(10), 1);
+        assert_eq!(cpu.bus.load64(0x80001000), Ok(0x5555));
+
+        assert_eq!(cause(&mut cpu, LR_D), None);
+        assert_eq!(cpu.iregs.read_reg(10), 0x5555);
+        assert_eq!(cause(&mut cpu, SC_D), None);
+        assert_eq!(cpu.iregs.read_reg(10), 0);
+        assert_eq!(cpu.bus.load64(0x80001000), Ok(0x1111));
+
+        // The reservation is gone after an SC
+        assert_eq!(cause(&mut cpu, SC_D), None);
+        assert_eq!(cpu.iregs.read_reg(10), 1);
+    }
+
+    #[test]
+    fn reservation_loss() {
+        let mut cpu = atomics();
+
+        // A store anywhere in the reserved doubleword
+        cause(&mut cpu, LR_D);
+        cpu.iregs.write_reg(13, 0x80001007);
+        cause(&mut cpu, SB);
+        cause(&mut cpu, SC_D);
+        assert_eq!(cpu.iregs.read_reg(10), 1);
+
+        // A store next to it doesn't count
+        cause(&mut cpu, LR_D);
+        cpu.iregs.write_reg(13, 0x80001008);
+        cause(&mut cpu, SD);
+        cause(&mut cpu, SC_D);
+        assert_eq!(cpu.iregs.read_reg(10), 0);
+
+        // An SC somewhere else fails, and loses the reservation
+        cause(&mut cpu, LR_D);
+        cpu.iregs.write_reg(11, 0x80001010);
+        cause(&mut cpu, SC_D);
+        assert_eq!(cpu.iregs.read_reg(10), 1);
+        cpu.iregs.write_reg(11, 0x80001000);
+        cause(&mut cpu, SC_D);
+        assert_eq!(cpu.iregs.read_reg(10), 1);
+    }
+
+    #[test]
+    fn words() {
+        let mut cpu = atomics();
+        cpu.bus.store64(0x80001000, 0xFFFF_FFFF_8000_0000).unwrap();
+
+        // Sign-extended
+        cause(&mut cpu, LR_W);
+        assert_eq!(cpu.iregs.read_reg(10), 0xFFFF_FFFF_8000_0000);
+        cause(&mut cpu, SC_W);
+        assert_eq!(cpu.bus.load64(0x80001000), Ok(0xFFFF_FFFF_0000_1111));
+
+        cause(&mut cpu, AMOADD_W);
+        assert_eq!(cpu.iregs.read_reg(10), 0x1111);
+        assert_eq!(cpu.bus.load64(0x80001000), Ok(0xFFFF_FFFF_0000_2222));
+
+        // Compared as unsigned 32-bit values
+        cpu.iregs.write_reg(12, 0xFFFF_FFFF);
+        cause(&mut cpu, AMOMAXU_W);
+        assert_eq!(cpu.bus.load64(0x80001000), Ok(0xFFFF_FFFF_FFFF_FFFF));
+
+        cpu.iregs.write_reg(12, 5);
+        cause(&mut cpu, AMOMIN_D);
+        assert_eq!(cpu.iregs.read_reg(10), u64::MAX);
+        assert_eq!(cpu.bus.load64(0x80001000), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn misaligned_atomics() {
+        let mut cpu = atomics();
+        cpu.iregs.write_reg(11, 0x80001004);
+
+        assert_eq!(cause(&mut cpu, LR_D), Some(4));
+        assert_eq!(cause(&mut cpu, SC_D), Some(6));
+        assert_eq!(cause(&mut cpu, AMOMIN_D), Some(6));
+        assert_eq!(cpu.csr.read(csr::MTVAL), 0x80001004);
+        assert_eq!(cause(&mut cpu, LR_W), None);
+    }
+
+    #[test]
+    fn compressed() {
+        let mut cpu = cpu();
+        cpu.csr.write(csr::MTVEC, 0x80002000);
+
+        // c.addi a0, 1
+        assert_eq!(cause(&mut cpu, 0x0505), None);
+        assert_eq!((cpu.pc, cpu.iregs.read_reg(10)), (0x80000002, 1));
+
+        // c.jr a1, to a 2-byte aligned address
+        cpu.iregs.write_reg(11, 0x80000102);
+        assert_eq!(cause(&mut cpu, 0x8582), None);
+        assert_eq!(cpu.pc, 0x80000102);
+
+        // Traps report the 16 bits of the instruction, and its address
+        assert_eq!(cause(&mut cpu, 0x0000), Some(2));
+        assert_eq!(cpu.csr.read(csr::MTVAL), 0);
+        assert_eq!(cause(&mut cpu, 0x9002), Some(3));
+        assert_eq!((cpu.csr.read(csr::MTVAL), cpu.csr.read(csr::MEPC)), (0x80000000, 0x80000000));
+        // c.lw a0, 0(a1)
+        cpu.iregs.write_reg(11, 0x8);
+        assert_eq!(cause(&mut cpu, 0x4188), Some(5));
+    }
+
+    #[test]
+    fn power_off_and_reset() {
+        let mut cpu = cpu();
+
+        // A NOP is only a NOP
+        assert_eq!(cause(&mut cpu, 0x00000013), None);
+        assert_eq!(cpu.power_state(), PowerState::Running);
+
+        cpu.bus.store(0x100000, 4, 0x7777).unwrap();
+        assert_eq!(cpu.power_state(), PowerState::Reset);
+
+        cpu.pc = 0x80001234;
+        cpu.iregs.write_reg(10, 1);
+        cpu.csr.write(csr::MSCRATCH, 1);
+        cpu.reset();
+        assert_eq!(cpu.power_state(), PowerState::Running);
+        assert_eq!((cpu.pc, cpu.iregs.read_reg(10), cpu.csr.read(csr::MSCRATCH)), (ROM_BASE, 0, 0));
+        // The program is still there
+        assert_eq!(cpu.bus.load(0x80000000, 4), Ok(0x00000013));
+
+        cpu.bus.store(0x100000, 4, (3 << 16) | 0x3333).unwrap();
+        assert_eq!(cpu.power_state(), PowerState::PowerOff(3));
+    }
+
+    #[test]
+    fn boot() {
+        let mut cpu = CPU::new();
+        cpu.firmware = Some(0x80000000);
+        cpu.kernel = Some(0x80200000);
+        let dtb = cpu.prepare_boot();
+
+        // The reset vector jumps to the firmware with the hart ID, the device tree and
+        // fw_dynamic's info, and after a reset too
+        for _ in 0..2 {
+            assert_eq!(cpu.pc, ROM_BASE);
+            for _ in 0..6 {
+                cpu.run_instr();
+            }
+
+            assert_eq!(cpu.pc, 0x80000000);
+            assert_eq!(cpu.iregs.read_reg(10), 0);
+            let addr = cpu.iregs.read_reg(11);
+            assert_eq!(addr & 0x1FFFFF, 0);
+            assert!(addr + dtb.len() as u64 <= DRAM_BASE + DRAM_SIZE as u64);
+            assert_eq!(cpu.bus.load(addr, 4).map(|value| u32::from_be(value as u32)), Ok(0xD00DFEED));
+
+            // magic, version, next address, next mode
+            let info = cpu.iregs.read_reg(12);
+            assert_eq!(cpu.bus.load64(info), Ok(0x4942534F));
+            assert_eq!(cpu.bus.load64(info + 16), Ok(0x80200000));
+            assert_eq!(cpu.bus.load64(info + 24), Ok(1));
+
+            cpu.reset();
+        }
+
+        // Without a firmware, straight to the kernel
+        let mut cpu = CPU::new();
+        cpu.kernel = Some(0x80200000);
+        cpu.prepare_boot();
+        for _ in 0..6 {
+            cpu.run_instr();
+        }
+        assert_eq!(cpu.pc, 0x80200000);
+
+        // And the ROM can't be written to
+        assert_eq!(cpu.bus.store(ROM_BASE, 4, 0), Err(Exception::StoreAccessFault(ROM_BASE)));
+    }
+}
@@ -0,0 +1,299 @@
+// Expansion of compressed instructions to their 32-bit equivalent, None if the encoding is
+// reserved or illegal.
+
+fn i_type(imm: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    ((imm & 0xFFF) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn s_type(imm: u32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    (((imm >> 5) & 0x7F) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | ((imm & 0x1F) << 7) | opcode
+}
+
+fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn b_type(imm: u32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    (((imm >> 12) & 0x1) << 31) | (((imm >> 5) & 0x3F) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) |
+        (((imm >> 1) & 0xF) << 8) | (((imm >> 11) & 0x1) << 7) | 0x63
+}
+
+fn j_type(imm: u32, rd: u32) -> u32 {
+    (((imm >> 20) & 0x1) << 31) | (((imm >> 1) & 0x3FF) << 21) | (((imm >> 11) & 0x1) << 20) |
+        (imm & 0xFF000) | (rd << 7) | 0x6F
+}
+
+// Sign-extends the low `bits` bits of value
+fn sext(value: u32, bits: u32) -> u32 {
+    (((value << (32 - bits)) as i32) >> (32 - bits)) as u32
+}
+
+pub fn expand(instr: u16) -> Option<u32> {
+    let i = instr as u32;
+    let funct3 = i >> 13;
+    // Full register fields
+    let rd = (i >> 7) & 0x1F;
+    let rs2 = (i >> 2) & 0x1F;
+    // The 3-bit fields only encode x8-x15
+    let rd_ = ((i >> 2) & 0x7) + 8;
+    let rs1_ = ((i >> 7) & 0x7) + 8;
+    // Offsets of the stack-less loads and stores, in bytes
+    let offset_w = ((i >> 7) & 0x38) | ((i >> 4) & 0x4) | ((i << 1) & 0x40);
+    let offset_d = ((i >> 7) & 0x38) | ((i << 1) & 0xC0);
+    // 6-bit signed immediate used by most of quadrant 1
+    let imm = sext(((i >> 7) & 0x20) | ((i >> 2) & 0x1F), 6);
+    let shamt = ((i >> 7) & 0x20) | ((i >> 2) & 0x1F);
+
+    let expanded = match (i & 0x3, funct3) {
+        // Quadrant 0
+        (0x0, 0x0) => {
+            // C.ADDI4SPN, a zero immediate is reserved (and covers the all-zero illegal instruction)
+            let imm = ((i >> 7) & 0x30) | ((i >> 1) & 0x3C0) | ((i >> 4) & 0x4) | ((i >> 2) & 0x8);
+            if imm == 0 { return None; }
+
+            i_type(imm, 2, 0x0, rd_, 0x13)
+        }
+        // C.FLD
+        (0x0, 0x1) => { i_type(offset_d, rs1_, 0x3, rd_, 0x07) }
+        // C.LW
+        (0x0, 0x2) => { i_type(offset_w, rs1_, 0x2, rd_, 0x03) }
+        // C.LD
+        (0x0, 0x3) => { i_type(offset_d, rs1_, 0x3, rd_, 0x03) }
+        // C.FSD
+        (0x0, 0x5) => { s_type(offset_d, rd_, rs1_, 0x3, 0x27) }
+        // C.SW
+        (0x0, 0x6) => { s_type(offset_w, rd_, rs1_, 0x2, 0x23) }
+        // C.SD
+        (0x0, 0x7) => { s_type(offset_d, rd_, rs1_, 0x3, 0x23) }
+
+        // Quadrant 1
+        // C.ADDI (C.NOP when rd is zero)
+        (0x1, 0x0) => { i_type(imm, rd, 0x0, rd, 0x13) }
+        // C.ADDIW
+        (0x1, 0x1) => {
+            if rd == 0 { return None; }
+
+            i_type(imm, rd, 0x0, rd, 0x1B)
+        }
+        // C.LI
+        (0x1, 0x2) => { i_type(imm, 0, 0x0, rd, 0x13) }
+        (0x1, 0x3) if rd == 2 => {
+            // C.ADDI16SP
+            let imm = ((i >> 3) & 0x200) | ((i >> 2) & 0x10) | ((i << 1) & 0x40) | ((i << 4) & 0x180) | ((i << 3) & 0x20);
+            if imm == 0 { return None; }
+
+            i_type(sext(imm, 10), 2, 0x0, 2, 0x13)
+        }
+        (0x1, 0x3) => {
+            // C.LUI
+            if imm == 0 { return None; }
+
+            (imm << 12) | (rd << 7) | 0x37
+        }
+        (0x1, 0x4) => {
+            let rs2_ = rd_;
+
+            match ((i >> 10) & 0x3, (i >> 12) & 0x1, (i >> 5) & 0x3) {
+                // C.SRLI
+                (0x0, _, _) => { i_type(shamt, rs1_, 0x5, rs1_, 0x13) }
+                // C.SRAI
+                (0x1, _, _) => { i_type(shamt | 0x400, rs1_, 0x5, rs1_, 0x13) }
+                // C.ANDI
+                (0x2, _, _) => { i_type(imm, rs1_, 0x7, rs1_, 0x13) }
+                // C.SUB
+                (0x3, 0, 0x0) => { r_type(0x20, rs2_, rs1_, 0x0, rs1_, 0x33) }
+                // C.XOR
+                (0x3, 0, 0x1) => { r_type(0x00, rs2_, rs1_, 0x4, rs1_, 0x33) }
+                // C.OR
+                (0x3, 0, 0x2) => { r_type(0x00, rs2_, rs1_, 0x6, rs1_, 0x33) }
+                // C.AND
+                (0x3, 0, 0x3) => { r_type(0x00, rs2_, rs1_, 0x7, rs1_, 0x33) }
+                // C.SUBW
+                (0x3, 1, 0x0) => { r_type(0x20, rs2_, rs1_, 0x0, rs1_, 0x3B) }
+                // C.ADDW
+                (0x3, 1, 0x1) => { r_type(0x00, rs2_, rs1_, 0x0, rs1_, 0x3B) }
+                _ => return None
+            }
+        }
+        (0x1, 0x5) => {
+            // C.J
+            let offset = ((i >> 1) & 0x800) | ((i >> 7) & 0x10) | ((i >> 1) & 0x300) | ((i << 2) & 0x400) |
+                ((i >> 1) & 0x40) | ((i << 1) & 0x80) | ((i >> 2) & 0xE) | ((i << 3) & 0x20);
+
+            j_type(sext(offset, 12), 0)
+        }
+        (0x1, 0x6) | (0x1, 0x7) => {
+            // C.BEQZ, C.BNEZ
+            let offset = ((i >> 4) & 0x100) | ((i >> 7) & 0x18) | ((i << 1) & 0xC0) | ((i >> 2) & 0x6) | ((i << 3) & 0x20);
+
+            b_type(sext(offset, 9), 0, rs1_, funct3 & 0x1)
+        }
+
+        // Quadrant 2
+        // C.SLLI
+        (0x2, 0x0) => { i_type(shamt, rd, 0x1, rd, 0x13) }
+        // C.FLDSP
+        (0x2, 0x1) => { i_type(((i >> 7) & 0x20) | ((i >> 2) & 0x18) | ((i << 4) & 0x1C0), 2, 0x3, rd, 0x07) }
+        // C.LWSP
+        (0x2, 0x2) => {
+            if rd == 0 { return None; }
+
+            i_type(((i >> 7) & 0x20) | ((i >> 2) & 0x1C) | ((i << 4) & 0xC0), 2, 0x2, rd, 0x03)
+        }
+        // C.LDSP
+        (0x2, 0x3) => {
+            if rd == 0 { return None; }
+
+            i_type(((i >> 7) & 0x20) | ((i >> 2) & 0x18) | ((i << 4) & 0x1C0), 2, 0x3, rd, 0x03)
+        }
+        (0x2, 0x4) => {
+            match ((i >> 12) & 0x1, rd, rs2) {
+                // C.JR
+                (0, 0, 0) => return None,
+                (0, _, 0) => { i_type(0, rd, 0x0, 0, 0x67) }
+                // C.MV
+                (0, _, _) => { r_type(0x00, rs2, 0, 0x0, rd, 0x33) }
+                // C.EBREAK
+                (1, 0, 0) => { 0x00100073 }
+                // C.JALR
+                (1, _, 0) => { i_type(0, rd, 0x0, 1, 0x67) }
+                // C.ADD
+                _ => { r_type(0x00, rs2, rd, 0x0, rd, 0x33) }
+            }
+        }
+        // C.FSDSP
+        (0x2, 0x5) => { s_type(((i >> 7) & 0x38) | ((i >> 1) & 0x1C0), rs2, 2, 0x3, 0x27) }
+        // C.SWSP
+        (0x2, 0x6) => { s_type(((i >> 7) & 0x3C) | ((i >> 1) & 0xC0), rs2, 2, 0x2, 0x23) }
+        // C.SDSP
+        (0x2, 0x7) => { s_type(((i >> 7) & 0x38) | ((i >> 1) & 0x1C0), rs2, 2, 0x3, 0x23) }
+        _ => return None
+    };
+
+    Some(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Compressed encodings and what the assembler makes of the same instruction without C
+    const EXPANSIONS: [(u16, u32); 40] = [
+        // Quadrant 0
+        // addi s0, sp, 16
+        (0x0800, 0x01010413),
+        // fld fa0, 8(a1)
+        (0x2588, 0x0085b507),
+        // lw a2, 4(a3)
+        (0x42d0, 0x0046a603),
+        // ld a4, 16(s1)
+        (0x6898, 0x0104b703),
+        // fsd fs1, 24(a5)
+        (0xaf84, 0x0097bc27),
+        // sw a0, 60(s1)
+        (0xdcc8, 0x02a4ae23),
+        // sd s0, 248(a5)
+        (0xffe0, 0x0e87bc23),
+        // Quadrant 1
+        // nop
+        (0x0001, 0x00000013),
+        // addi a0, a0, -1
+        (0x157d, 0xfff50513),
+        // addiw t1, t1, 5
+        (0x2315, 0x0053031b),
+        // li a5, -32
+        (0x5781, 0xfe000793),
+        // addi sp, sp, -64
+        (0x7139, 0xfc010113),
+        // addi sp, sp, 496
+        (0x617d, 0x1f010113),
+        // lui a0, 0x1f
+        (0x657d, 0x0001f537),
+        // srli s0, s0, 3
+        (0x800d, 0x00345413),
+        // srai a1, a1, 63
+        (0x95fd, 0x43f5d593),
+        // andi a2, a2, -2
+        (0x9a79, 0xffe67613),
+        // sub s0, s0, s1
+        (0x8c05, 0x40940433),
+        // xor a0, a0, a1
+        (0x8d2d, 0x00b54533),
+        // or a2, a2, a3
+        (0x8e55, 0x00d66633),
+        // and a4, a4, a5
+        (0x8f7d, 0x00f77733),
+        // subw s1, s1, a0
+        (0x9c89, 0x40a484bb),
+        // addw a3, a3, a4
+        (0x9eb9, 0x00e686bb),
+        // j 16
+        (0xa801, 0x0100006f),
+        // j -2048
+        (0xb001, 0x801ff06f),
+        // beqz s0, -256
+        (0xd001, 0xf00400e3),
+        // bnez a5, 254
+        (0xeffd, 0x0e079f63),
+        // Quadrant 2
+        // slli t0, t0, 12
+        (0x02b2, 0x00c29293),
+        // fld ft1, 8(sp)
+        (0x20a2, 0x00813087),
+        // lw ra, 12(sp)
+        (0x40b2, 0x00c12083),
+        // ld s2, 504(sp)
+        (0x797e, 0x1f813903),
+        // jr t2
+        (0x8382, 0x00038067),
+        // mv a0, t3, which is an add
+        (0x8572, 0x01c00533),
+        // ebreak
+        (0x9002, 0x00100073),
+        // jalr a1
+        (0x9582, 0x000580e7),
+        // add s3, s3, t4
+        (0x99f6, 0x01d989b3),
+        // fsd fa0, 16(sp)
+        (0xa82a, 0x00a13827),
+        // sw t0, 252(sp)
+        (0xdf96, 0x0e512e23),
+        // sd ra, 8(sp)
+        (0xe406, 0x00113423),
+        // lw ra, 0(sp)
+        (0x4082, 0x00012083)
+    ];
+
+    #[test]
+    fn expansions() {
+        for &(compressed, expanded) in &EXPANSIONS {
+            assert_eq!(expand(compressed), Some(expanded), "{:04x}", compressed);
+        }
+    }
+
+    #[test]
+    fn reserved_encodings() {
+        let reserved = [
+            // C.ADDI4SPN with a zero immediate, all zeros is the illegal instruction
+            0x0000, 0x0004,
+            // Reserved in quadrant 0
+            0x8000,
+            // C.ADDIW with rd = 0
+            0x2001, 0x2005,
+            // C.ADDI16SP and C.LUI with a zero immediate
+            0x6101, 0x6501,
+            // Reserved arithmetic in quadrant 1
+            0x9c41, 0x9c61,
+            // C.LWSP and C.LDSP with rd = 0
+            0x4002, 0x6002,
+            // C.JR with rs1 = 0
+            0x8002,
+            // Not compressed
+            0x0003
+        ];
+
+        for &instr in &reserved {
+            assert_eq!(expand(instr), None, "{:04x}", instr);
+        }
+    }
+}
@@ -0,0 +1,811 @@
+// Software IEEE 754 arithmetic for the F and D extensions.
+//
+// The host FPU can't be told which rounding mode to use and doesn't report the exception
+// flags, so every operation is computed exactly on integers and then rounded here.
+// Values are always passed around as raw bits, NaN results are always the canonical NaN.
+
+// Rounding modes, as encoded in the rm field and frm
+pub const RNE: u64 = 0;
+pub const RTZ: u64 = 1;
+pub const RDN: u64 = 2;
+pub const RUP: u64 = 3;
+pub const RMM: u64 = 4;
+
+// Exception flags, as encoded in fflags
+pub const NX: u64 = 1 << 0;
+pub const UF: u64 = 1 << 1;
+pub const OF: u64 = 1 << 2;
+pub const DZ: u64 = 1 << 3;
+pub const NV: u64 = 1 << 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    exp_bits: u32,
+    frac_bits: u32
+}
+
+pub const SINGLE: Format = Format { exp_bits: 8, frac_bits: 23 };
+pub const DOUBLE: Format = Format { exp_bits: 11, frac_bits: 52 };
+
+impl Format {
+    fn bias(self) -> i32 {
+        (1 << (self.exp_bits - 1)) - 1
+    }
+
+    // Biased exponent of infinities and NaNs
+    fn max_exp(self) -> u64 {
+        (1 << self.exp_bits) - 1
+    }
+
+    fn precision(self) -> i32 {
+        self.frac_bits as i32 + 1
+    }
+
+    pub fn sign_bit(self) -> u64 {
+        1 << (self.exp_bits + self.frac_bits)
+    }
+
+    fn frac_mask(self) -> u64 {
+        (1 << self.frac_bits) - 1
+    }
+
+    pub fn canonical_nan(self) -> u64 {
+        (self.max_exp() << self.frac_bits) | (1 << (self.frac_bits - 1))
+    }
+
+    fn infinity(self, sign: bool) -> u64 {
+        self.signed(sign, self.max_exp() << self.frac_bits)
+    }
+
+    fn max_finite(self, sign: bool) -> u64 {
+        self.signed(sign, ((self.max_exp() - 1) << self.frac_bits) | self.frac_mask())
+    }
+
+    fn zero(self, sign: bool) -> u64 {
+        self.signed(sign, 0)
+    }
+
+    fn signed(self, sign: bool, magnitude: u64) -> u64 {
+        if sign { magnitude | self.sign_bit() } else { magnitude }
+    }
+
+    fn unpack(self, bits: u64) -> (bool, Value) {
+        let sign = bits & self.sign_bit() != 0;
+        let exp = (bits >> self.frac_bits) & self.max_exp();
+        let frac = bits & self.frac_mask();
+
+        let value = if exp == self.max_exp() {
+            if frac == 0 {
+                Value::Infinity
+            } else {
+                Value::NaN { signaling: frac & (1 << (self.frac_bits - 1)) == 0 }
+            }
+        } else if exp == 0 {
+            if frac == 0 {
+                Value::Zero
+            } else {
+                Value::Finite { exp: 1 - self.bias() - self.frac_bits as i32, sig: frac as u128 }
+            }
+        } else {
+            Value::Finite {
+                exp: exp as i32 - self.bias() - self.frac_bits as i32,
+                sig: (frac | (1 << self.frac_bits)) as u128
+            }
+        };
+
+        (sign, value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    Zero,
+    // sig * 2^exp, sig is never 0
+    Finite { exp: i32, sig: u128 },
+    Infinity,
+    NaN { signaling: bool }
+}
+
+impl Value {
+    fn is_nan(self) -> bool {
+        matches!(self, Value::NaN { .. })
+    }
+
+    fn is_signaling(self) -> bool {
+        matches!(self, Value::NaN { signaling: true })
+    }
+}
+
+// How the bits shifted out compare to half an ulp
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Remainder {
+    Zero,
+    BelowHalf,
+    Half,
+    AboveHalf
+}
+
+// Position of the most significant bit of sig * 2^exp
+fn msb(exp: i32, sig: u128) -> i32 {
+    exp + 127 - sig.leading_zeros() as i32
+}
+
+fn isqrt(n: u128) -> u128 {
+    let mut n = n;
+    let mut root = 0;
+    let mut bit = 1 << 126;
+
+    while bit > n {
+        bit >>= 2;
+    }
+
+    while bit != 0 {
+        if n >= root + bit {
+            n -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+
+        bit >>= 2;
+    }
+
+    root
+}
+
+pub struct Softfloat {
+    rm: u64,
+    pub flags: u64
+}
+
+impl Softfloat {
+    pub fn new(rm: u64) -> Self {
+        Self { rm, flags: 0 }
+    }
+
+    fn round_up(&self, sign: bool, odd: bool, remainder: Remainder) -> bool {
+        match self.rm {
+            RNE => remainder == Remainder::AboveHalf || (remainder == Remainder::Half && odd),
+            RTZ => false,
+            RDN => remainder != Remainder::Zero && sign,
+            RUP => remainder != Remainder::Zero && !sign,
+            RMM => remainder == Remainder::Half || remainder == Remainder::AboveHalf,
+            _ => unreachable!()
+        }
+    }
+
+    // Rounds sig * 2^exp to a multiple of 2^lsb, returns that multiple and whether it was inexact.
+    // The caller guarantees that nothing overflows when lsb is below exp.
+    fn round_at(&self, sign: bool, exp: i32, sig: u128, lsb: i32) -> (u128, bool) {
+        let shift = lsb - exp;
+
+        if shift <= 0 {
+            return (sig << -shift, false);
+        }
+
+        let (kept, remainder) = if shift > 128 {
+            (0, Remainder::BelowHalf)
+        } else {
+            let kept = if shift == 128 { 0 } else { sig >> shift };
+            let rest = if shift == 128 { sig } else { sig & ((1 << shift) - 1) };
+            let half = 1 << (shift - 1);
+
+            let remainder = if rest == 0 {
+                Remainder::Zero
+            } else if rest < half {
+                Remainder::BelowHalf
+            } else if rest == half {
+                Remainder::Half
+            } else {
+                Remainder::AboveHalf
+            };
+
+            (kept, remainder)
+        };
+
+        let rounded = kept + self.round_up(sign, kept & 1 == 1, remainder) as u128;
+        (rounded, remainder != Remainder::Zero)
+    }
+
+    fn round_pack(&mut self, fmt: Format, sign: bool, exp: i32, sig: u128) -> u64 {
+        if sig == 0 {
+            return fmt.zero(sign);
+        }
+
+        let precision = fmt.precision();
+        let emin = 1 - fmt.bias();
+        let top = msb(exp, sig);
+        let unbounded_lsb = top - (precision - 1);
+        let mut lsb = unbounded_lsb.max(emin - fmt.frac_bits as i32);
+
+        // Tininess is detected after rounding, as if the exponent range was unbounded
+        let tiny = top < emin && {
+            let (rounded, _) = self.round_at(sign, exp, sig, unbounded_lsb);
+            !(rounded >> precision != 0 && top == emin - 1)
+        };
+
+        let (mut mantissa, inexact) = self.round_at(sign, exp, sig, lsb);
+        if mantissa >> precision != 0 {
+            mantissa >>= 1;
+            lsb += 1;
+        }
+
+        if inexact {
+            self.flags |= NX;
+            if tiny {
+                self.flags |= UF;
+            }
+        }
+
+        if mantissa >> (precision - 1) == 0 {
+            // Subnormal
+            return fmt.signed(sign, mantissa as u64);
+        }
+
+        let biased = (lsb + precision - 1 + fmt.bias()) as i64;
+        if biased >= fmt.max_exp() as i64 {
+            self.flags |= OF | NX;
+
+            let to_infinity = match self.rm {
+                RNE | RMM => true,
+                RUP => !sign,
+                RDN => sign,
+                _ => false
+            };
+
+            return if to_infinity { fmt.infinity(sign) } else { fmt.max_finite(sign) };
+        }
+
+        fmt.signed(sign, ((biased as u64) << fmt.frac_bits) | (mantissa as u64 & fmt.frac_mask()))
+    }
+
+    // Returns the canonical NaN if any operand is a NaN, raising NV for signaling ones
+    fn propagate_nan(&mut self, fmt: Format, values: &[Value]) -> Option<u64> {
+        if values.iter().any(|v| v.is_signaling()) {
+            self.flags |= NV;
+        }
+
+        if values.iter().any(|v| v.is_nan()) {
+            Some(fmt.canonical_nan())
+        } else {
+            None
+        }
+    }
+
+    fn invalid(&mut self, fmt: Format) -> u64 {
+        self.flags |= NV;
+        fmt.canonical_nan()
+    }
+
+    // Sum of two exact values, both sig must fit in 126 bits
+    fn add_exact(&mut self, fmt: Format, a: (bool, i32, u128), b: (bool, i32, u128)) -> u64 {
+        let (a, b) = if msb(b.1, b.2) > msb(a.1, a.2) { (b, a) } else { (a, b) };
+        let (sign_a, exp_a, sig_a) = a;
+        let (sign_b, exp_b, sig_b) = b;
+
+        // Put a's MSB at bit 125, which leaves room for the carry
+        let shift = sig_a.leading_zeros() as i32 - 2;
+        let sig_a = sig_a << shift;
+        let exp_a = exp_a - shift;
+
+        let distance = exp_a - exp_b;
+        let sig_b = if distance <= 0 {
+            sig_b << -distance
+        } else if distance >= 126 {
+            // Only matters for rounding, so keep it as a sticky bit
+            1
+        } else {
+            (sig_b >> distance) | (sig_b & ((1 << distance) - 1) != 0) as u128
+        };
+
+        let (sign, sig) = if sign_a == sign_b {
+            (sign_a, sig_a + sig_b)
+        } else if sig_a >= sig_b {
+            (sign_a, sig_a - sig_b)
+        } else {
+            (sign_b, sig_b - sig_a)
+        };
+
+        if sig == 0 {
+            // x - x is +0, except when rounding down
+            return fmt.zero(self.rm == RDN);
+        }
+
+        self.round_pack(fmt, sign, exp_a, sig)
+    }
+
+    pub fn add(&mut self, fmt: Format, a: u64, b: u64) -> u64 {
+        let (sign_a, va) = fmt.unpack(a);
+        let (sign_b, vb) = fmt.unpack(b);
+
+        if let Some(nan) = self.propagate_nan(fmt, &[va, vb]) {
+            return nan;
+        }
+
+        match (va, vb) {
+            (Value::Infinity, Value::Infinity) if sign_a != sign_b => self.invalid(fmt),
+            (Value::Infinity, _) => a,
+            (_, Value::Infinity) => b,
+            (Value::Zero, Value::Zero) => {
+                if sign_a == sign_b { a } else { fmt.zero(self.rm == RDN) }
+            }
+            (Value::Zero, _) => b,
+            (_, Value::Zero) => a,
+            (Value::Finite { exp: ea, sig: sa }, Value::Finite { exp: eb, sig: sb }) => {
+                self.add_exact(fmt, (sign_a, ea, sa), (sign_b, eb, sb))
+            }
+            _ => unreachable!()
+        }
+    }
+
+    pub fn sub(&mut self, fmt: Format, a: u64, b: u64) -> u64 {
+        self.add(fmt, a, b ^ fmt.sign_bit())
+    }
+
+    pub fn mul(&mut self, fmt: Format, a: u64, b: u64) -> u64 {
+        let (sign_a, va) = fmt.unpack(a);
+        let (sign_b, vb) = fmt.unpack(b);
+        let sign = sign_a != sign_b;
+
+        if let Some(nan) = self.propagate_nan(fmt, &[va, vb]) {
+            return nan;
+        }
+
+        match (va, vb) {
+            (Value::Infinity, Value::Zero) | (Value::Zero, Value::Infinity) => self.invalid(fmt),
+            (Value::Infinity, _) | (_, Value::Infinity) => fmt.infinity(sign),
+            (Value::Zero, _) | (_, Value::Zero) => fmt.zero(sign),
+            (Value::Finite { exp: ea, sig: sa }, Value::Finite { exp: eb, sig: sb }) => {
+                self.round_pack(fmt, sign, ea + eb, sa * sb)
+            }
+            _ => unreachable!()
+        }
+    }
+
+    // (a * b) + c with a single rounding, the negations are applied to the product and to c
+    pub fn fma(&mut self, fmt: Format, a: u64, b: u64, c: u64, negate_product: bool, negate_c: bool) -> u64 {
+        let (sign_a, va) = fmt.unpack(a);
+        let (sign_b, vb) = fmt.unpack(b);
+        let (sign_c, vc) = fmt.unpack(c);
+        let sign_product = (sign_a != sign_b) != negate_product;
+        let sign_c = sign_c != negate_c;
+
+        // inf * 0 is invalid even if c is a quiet NaN
+        let product_invalid = matches!((va, vb), (Value::Infinity, Value::Zero) | (Value::Zero, Value::Infinity));
+
+        if let Some(nan) = self.propagate_nan(fmt, &[va, vb, vc]) {
+            if product_invalid {
+                self.flags |= NV;
+            }
+
+            return nan;
+        }
+
+        if product_invalid {
+            return self.invalid(fmt);
+        }
+
+        let product = match (va, vb) {
+            (Value::Infinity, _) | (_, Value::Infinity) => Value::Infinity,
+            (Value::Zero, _) | (_, Value::Zero) => Value::Zero,
+            (Value::Finite { exp: ea, sig: sa }, Value::Finite { exp: eb, sig: sb }) => {
+                Value::Finite { exp: ea + eb, sig: sa * sb }
+            }
+            _ => unreachable!()
+        };
+
+        match (product, vc) {
+            (Value::Infinity, Value::Infinity) if sign_product != sign_c => self.invalid(fmt),
+            (Value::Infinity, _) => fmt.infinity(sign_product),
+            (_, Value::Infinity) => fmt.infinity(sign_c),
+            (Value::Zero, Value::Zero) => {
+                if sign_product == sign_c { fmt.zero(sign_c) } else { fmt.zero(self.rm == RDN) }
+            }
+            (Value::Zero, _) => fmt.signed(sign_c, c & !fmt.sign_bit()),
+            (Value::Finite { exp, sig }, Value::Zero) => self.round_pack(fmt, sign_product, exp, sig),
+            (Value::Finite { exp: ep, sig: sp }, Value::Finite { exp: ec, sig: sc }) => {
+                self.add_exact(fmt, (sign_product, ep, sp), (sign_c, ec, sc))
+            }
+            _ => unreachable!()
+        }
+    }
+
+    pub fn div(&mut self, fmt: Format, a: u64, b: u64) -> u64 {
+        let (sign_a, va) = fmt.unpack(a);
+        let (sign_b, vb) = fmt.unpack(b);
+        let sign = sign_a != sign_b;
+
+        if let Some(nan) = self.propagate_nan(fmt, &[va, vb]) {
+            return nan;
+        }
+
+        match (va, vb) {
+            (Value::Infinity, Value::Infinity) | (Value::Zero, Value::Zero) => self.invalid(fmt),
+            (Value::Infinity, _) => fmt.infinity(sign),
+            (_, Value::Infinity) => fmt.zero(sign),
+            (Value::Zero, _) => fmt.zero(sign),
+            (_, Value::Zero) => {
+                self.flags |= DZ;
+                fmt.infinity(sign)
+            }
+            (Value::Finite { exp: ea, sig: sa }, Value::Finite { exp: eb, sig: sb }) => {
+                // Normalize both to 64 bits so the quotient has at least 64 bits
+                let shift_a = sa.leading_zeros() as i32 - 64;
+                let shift_b = sb.leading_zeros() as i32 - 64;
+                let sa = sa << shift_a;
+                let sb = sb << shift_b;
+
+                let quotient = (sa << 64) / sb;
+                let sticky = !(sa << 64).is_multiple_of(sb);
+
+                self.round_pack(fmt, sign, (ea - shift_a) - (eb - shift_b) - 65, (quotient << 1) | sticky as u128)
+            }
+            _ => unreachable!()
+        }
+    }
+
+    pub fn sqrt(&mut self, fmt: Format, a: u64) -> u64 {
+        let (sign, va) = fmt.unpack(a);
+
+        if let Some(nan) = self.propagate_nan(fmt, &[va]) {
+            return nan;
+        }
+
+        match va {
+            // sqrt(-0) is -0
+            Value::Zero => a,
+            _ if sign => self.invalid(fmt),
+            Value::Infinity => a,
+            Value::Finite { exp, sig } => {
+                // Put the MSB around bit 125 with an even exponent
+                let shift = sig.leading_zeros() as i32 - 2;
+                let (mut exp, mut sig) = (exp - shift, sig << shift);
+
+                if exp & 1 != 0 {
+                    sig >>= 1;
+                    exp += 1;
+                }
+
+                let root = isqrt(sig);
+                let sticky = root * root != sig;
+
+                self.round_pack(fmt, false, exp / 2 - 1, (root << 1) | sticky as u128)
+            }
+            _ => unreachable!()
+        }
+    }
+
+    pub fn convert(&mut self, from: Format, to: Format, a: u64) -> u64 {
+        let (sign, va) = from.unpack(a);
+
+        if let Some(nan) = self.propagate_nan(to, &[va]) {
+            return nan;
+        }
+
+        match va {
+            Value::Zero => to.zero(sign),
+            Value::Infinity => to.infinity(sign),
+            Value::Finite { exp, sig } => self.round_pack(to, sign, exp, sig),
+            _ => unreachable!()
+        }
+    }
+
+    // Converts to a signed or unsigned integer of 32 or 64 bits, returned sign-extended to 64 bits
+    pub fn float_to_int(&mut self, fmt: Format, a: u64, signed: bool, bits: u32) -> u64 {
+        let (sign, va) = fmt.unpack(a);
+
+        let max: u128 = if signed { (1 << (bits - 1)) - 1 } else { (1 << bits) - 1 };
+        let min_magnitude: u128 = if signed { 1 << (bits - 1) } else { 0 };
+
+        let saturate = |negative: bool| -> u64 {
+            let value = if negative {
+                (min_magnitude as u64).wrapping_neg()
+            } else {
+                max as u64
+            };
+
+            if bits == 32 { value as i32 as i64 as u64 } else { value }
+        };
+
+        let (exp, sig) = match va {
+            Value::Zero => return 0,
+            Value::NaN { .. } => {
+                self.flags |= NV;
+                return saturate(false);
+            }
+            Value::Infinity => {
+                self.flags |= NV;
+                return saturate(sign);
+            }
+            Value::Finite { exp, sig } => (exp, sig)
+        };
+
+        // Anything with a bit above 2^64 is out of range, this also keeps round_at from overflowing
+        if msb(exp, sig) >= 64 {
+            self.flags |= NV;
+            return saturate(sign);
+        }
+
+        let (magnitude, inexact) = self.round_at(sign, exp, sig, 0);
+        let in_range = if sign { magnitude <= min_magnitude } else { magnitude <= max };
+
+        if !in_range {
+            self.flags |= NV;
+            return saturate(sign);
+        }
+
+        if inexact {
+            self.flags |= NX;
+        }
+
+        let value = if sign { (magnitude as u64).wrapping_neg() } else { magnitude as u64 };
+        if bits == 32 { value as i32 as i64 as u64 } else { value }
+    }
+
+    // The value must already be sign or zero-extended to 64 bits
+    pub fn int_to_float(&mut self, fmt: Format, value: u64, signed: bool) -> u64 {
+        let sign = signed && (value as i64) < 0;
+        let magnitude = if sign { (value as i64).unsigned_abs() } else { value };
+
+        self.round_pack(fmt, sign, 0, magnitude as u128)
+    }
+
+    // FEQ is a quiet comparison
+    pub fn eq(&mut self, fmt: Format, a: u64, b: u64) -> bool {
+        let (_, va) = fmt.unpack(a);
+        let (_, vb) = fmt.unpack(b);
+
+        if va.is_nan() || vb.is_nan() {
+            if va.is_signaling() || vb.is_signaling() {
+                self.flags |= NV;
+            }
+
+            return false;
+        }
+
+        (va == Value::Zero && vb == Value::Zero) || a == b
+    }
+
+    // FLT and FLE are signaling comparisons
+    pub fn lt(&mut self, fmt: Format, a: u64, b: u64, or_equal: bool) -> bool {
+        let (_, va) = fmt.unpack(a);
+        let (_, vb) = fmt.unpack(b);
+
+        if va.is_nan() || vb.is_nan() {
+            self.flags |= NV;
+            return false;
+        }
+
+        let (ka, kb) = (order_key(fmt, a), order_key(fmt, b));
+        if or_equal { ka <= kb } else { ka < kb }
+    }
+
+    pub fn min_max(&mut self, fmt: Format, a: u64, b: u64, max: bool) -> u64 {
+        let (_, va) = fmt.unpack(a);
+        let (_, vb) = fmt.unpack(b);
+
+        if va.is_signaling() || vb.is_signaling() {
+            self.flags |= NV;
+        }
+
+        match (va.is_nan(), vb.is_nan()) {
+            (true, true) => fmt.canonical_nan(),
+            (true, false) => b,
+            (false, true) => a,
+            (false, false) => {
+                let (ka, kb) = (order_key(fmt, a), order_key(fmt, b));
+
+                // -0 is smaller than +0
+                if ka == kb {
+                    if max { a & b } else { a | b }
+                } else if (ka < kb) != max {
+                    a
+                } else {
+                    b
+                }
+            }
+        }
+    }
+}
+
+// Maps the bits of a non-NaN value to an integer with the same order, +0 and -0 are equal
+fn order_key(fmt: Format, bits: u64) -> i64 {
+    let magnitude = (bits & !fmt.sign_bit()) as i64;
+
+    if bits & fmt.sign_bit() != 0 { -magnitude } else { magnitude }
+}
+
+pub fn classify(fmt: Format, bits: u64) -> u64 {
+    let (sign, value) = fmt.unpack(bits);
+    let subnormal = (bits >> fmt.frac_bits) & fmt.max_exp() == 0;
+
+    let bit = match value {
+        Value::Infinity => if sign { 0 } else { 7 },
+        Value::Finite { .. } if !subnormal => if sign { 1 } else { 6 },
+        Value::Finite { .. } => if sign { 2 } else { 5 },
+        Value::Zero => if sign { 3 } else { 4 },
+        Value::NaN { signaling: true } => 8,
+        Value::NaN { signaling: false } => 9
+    };
+
+    1 << bit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONE_S: u64 = 0x3F800000;
+    const ONE_D: u64 = 0x3FF0000000000000;
+    const QNAN_S: u64 = 0x7FC00000;
+    const SNAN_S: u64 = 0x7F800001;
+
+    // The result and the flags it raised
+    fn run(rm: u64, op: impl FnOnce(&mut Softfloat) -> u64) -> (u64, u64) {
+        let mut sf = Softfloat::new(rm);
+        let result = op(&mut sf);
+
+        (result, sf.flags)
+    }
+
+    #[test]
+    fn rounding_modes() {
+        // 1 + 2^-24 is halfway between 1 and the next single
+        let cases = [(RNE, 0x3F800000), (RTZ, 0x3F800000), (RDN, 0x3F800000), (RUP, 0x3F800001), (RMM, 0x3F800001)];
+        for &(rm, expected) in &cases {
+            assert_eq!(run(rm, |sf| sf.add(SINGLE, ONE_S, 0x33800000)), (expected, NX), "rm {}", rm);
+        }
+
+        let cases = [(RNE, 0xBF800000), (RTZ, 0xBF800000), (RDN, 0xBF800001), (RUP, 0xBF800000), (RMM, 0xBF800001)];
+        for &(rm, expected) in &cases {
+            assert_eq!(run(rm, |sf| sf.add(SINGLE, 0xBF800000, 0xB3800000)), (expected, NX), "rm {}", rm);
+        }
+
+        // Ties go to the even one
+        assert_eq!(run(RNE, |sf| sf.add(SINGLE, 0x3F800001, 0x33800000)), (0x3F800002, NX));
+
+        // 1/3 isn't a tie, only the directed modes can round up
+        let cases = [(RNE, 0x3FD5555555555555), (RTZ, 0x3FD5555555555555), (RDN, 0x3FD5555555555555),
+            (RUP, 0x3FD5555555555556), (RMM, 0x3FD5555555555555)];
+        for &(rm, expected) in &cases {
+            assert_eq!(run(rm, |sf| sf.div(DOUBLE, ONE_D, 0x4008000000000000)), (expected, NX), "rm {}", rm);
+        }
+
+        assert_eq!(run(RDN, |sf| sf.div(DOUBLE, 0xBFF0000000000000, 0x4008000000000000)), (0xBFD5555555555556, NX));
+        assert_eq!(run(RUP, |sf| sf.div(DOUBLE, 0xBFF0000000000000, 0x4008000000000000)), (0xBFD5555555555555, NX));
+    }
+
+    #[test]
+    fn exact_results_raise_nothing() {
+        assert_eq!(run(RNE, |sf| sf.add(SINGLE, ONE_S, ONE_S)), (0x40000000, 0));
+        assert_eq!(run(RNE, |sf| sf.sqrt(DOUBLE, 0x4010000000000000)), (0x4000000000000000, 0));
+        // An exact subnormal result isn't an underflow
+        assert_eq!(run(RNE, |sf| sf.mul(SINGLE, 0x00800000, 0x3F000000)), (0x00400000, 0));
+    }
+
+    #[test]
+    fn overflow() {
+        let max = 0x7FEFFFFFFFFFFFFF;
+        let two = 0x4000000000000000;
+
+        assert_eq!(run(RNE, |sf| sf.mul(DOUBLE, max, two)), (0x7FF0000000000000, OF | NX));
+        assert_eq!(run(RTZ, |sf| sf.mul(DOUBLE, max, two)), (max, OF | NX));
+        assert_eq!(run(RDN, |sf| sf.mul(DOUBLE, max, two)), (max, OF | NX));
+        assert_eq!(run(RUP, |sf| sf.mul(DOUBLE, max, two)), (0x7FF0000000000000, OF | NX));
+        assert_eq!(run(RUP, |sf| sf.mul(DOUBLE, max | DOUBLE.sign_bit(), two)), (0xFFEFFFFFFFFFFFFF, OF | NX));
+    }
+
+    #[test]
+    fn underflow() {
+        let half = 0x3F000000;
+
+        // Half of the smallest subnormal is a tie between 0 and it
+        assert_eq!(run(RNE, |sf| sf.mul(SINGLE, 0x00000001, half)), (0x00000000, UF | NX));
+        assert_eq!(run(RUP, |sf| sf.mul(SINGLE, 0x00000001, half)), (0x00000001, UF | NX));
+        assert_eq!(run(RNE, |sf| sf.mul(SINGLE, 0x00000003, half)), (0x00000002, UF | NX));
+    }
+
+    #[test]
+    fn divide_by_zero() {
+        assert_eq!(run(RNE, |sf| sf.div(SINGLE, ONE_S, 0x00000000)), (0x7F800000, DZ));
+        assert_eq!(run(RNE, |sf| sf.div(SINGLE, ONE_S, 0x80000000)), (0xFF800000, DZ));
+        // 0/0 has no meaningful result, it's invalid rather than a division by zero
+        assert_eq!(run(RNE, |sf| sf.div(SINGLE, 0x00000000, 0x00000000)), (QNAN_S, NV));
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(run(RNE, |sf| sf.sqrt(SINGLE, 0xBF800000)), (QNAN_S, NV));
+        // The square root of -0 is -0
+        assert_eq!(run(RNE, |sf| sf.sqrt(SINGLE, 0x80000000)), (0x80000000, 0));
+        assert_eq!(run(RNE, |sf| sf.add(SINGLE, 0x7F800000, 0xFF800000)), (QNAN_S, NV));
+        assert_eq!(run(RNE, |sf| sf.mul(SINGLE, 0x00000000, 0x7F800000)), (QNAN_S, NV));
+    }
+
+    #[test]
+    fn canonical_nan() {
+        assert_eq!(SINGLE.canonical_nan(), QNAN_S);
+        assert_eq!(DOUBLE.canonical_nan(), 0x7FF8000000000000);
+
+        // Payloads aren't propagated, and only signaling NaNs are invalid
+        assert_eq!(run(RNE, |sf| sf.add(SINGLE, 0x7FC12345, ONE_S)), (QNAN_S, 0));
+        assert_eq!(run(RNE, |sf| sf.add(SINGLE, SNAN_S, ONE_S)), (QNAN_S, NV));
+        assert_eq!(run(RNE, |sf| sf.mul(DOUBLE, 0xFFF8000000000001, ONE_D)), (0x7FF8000000000000, 0));
+        assert_eq!(run(RNE, |sf| sf.convert(DOUBLE, SINGLE, 0x7FF8000000000001)), (QNAN_S, 0));
+        assert_eq!(run(RNE, |sf| sf.convert(SINGLE, DOUBLE, SNAN_S)), (0x7FF8000000000000, NV));
+    }
+
+    #[test]
+    fn min_max() {
+        let (zero, minus_zero) = (0x00000000, 0x80000000);
+
+        // -0 is smaller than +0, whatever the order
+        assert_eq!(run(RNE, |sf| sf.min_max(SINGLE, zero, minus_zero, false)), (minus_zero, 0));
+        assert_eq!(run(RNE, |sf| sf.min_max(SINGLE, minus_zero, zero, false)), (minus_zero, 0));
+        assert_eq!(run(RNE, |sf| sf.min_max(SINGLE, zero, minus_zero, true)), (zero, 0));
+        assert_eq!(run(RNE, |sf| sf.min_max(SINGLE, minus_zero, zero, true)), (zero, 0));
+
+        // A single NaN is ignored, a signaling one is still invalid
+        assert_eq!(run(RNE, |sf| sf.min_max(SINGLE, QNAN_S, ONE_S, false)), (ONE_S, 0));
+        assert_eq!(run(RNE, |sf| sf.min_max(SINGLE, ONE_S, SNAN_S, true)), (ONE_S, NV));
+        assert_eq!(run(RNE, |sf| sf.min_max(SINGLE, 0x7FC12345, SNAN_S, true)), (QNAN_S, NV));
+    }
+
+    #[test]
+    fn float_to_int() {
+        let (two_and_half, minus_two_and_half) = (0x40200000, 0xC0200000);
+
+        let cases = [(RNE, 2), (RTZ, 2), (RDN, 2), (RUP, 3), (RMM, 3)];
+        for &(rm, expected) in &cases {
+            assert_eq!(run(rm, |sf| sf.float_to_int(SINGLE, two_and_half, true, 32)), (expected, NX), "rm {}", rm);
+        }
+
+        // 32-bit results are sign-extended
+        assert_eq!(run(RNE, |sf| sf.float_to_int(SINGLE, minus_two_and_half, true, 32)), (-2i64 as u64, NX));
+        assert_eq!(run(RDN, |sf| sf.float_to_int(SINGLE, minus_two_and_half, true, 32)), (-3i64 as u64, NX));
+
+        // Out of range values saturate, NaNs go to the largest value
+        assert_eq!(run(RNE, |sf| sf.float_to_int(SINGLE, 0x4F000000, true, 32)), (0x7FFFFFFF, NV));
+        assert_eq!(run(RNE, |sf| sf.float_to_int(SINGLE, QNAN_S, true, 32)), (0x7FFFFFFF, NV));
+        assert_eq!(run(RNE, |sf| sf.float_to_int(DOUBLE, 0xFFF0000000000000, true, 64)), (0x8000000000000000, NV));
+        assert_eq!(run(RNE, |sf| sf.float_to_int(SINGLE, 0xBF800000, false, 32)), (0, NV));
+
+        // Rounding to 0 first makes it in range for an unsigned result
+        assert_eq!(run(RTZ, |sf| sf.float_to_int(SINGLE, 0xBF000000, false, 32)), (0, NX));
+    }
+
+    #[test]
+    fn int_to_float() {
+        // 2^24 + 1 doesn't fit in a single
+        assert_eq!(run(RNE, |sf| sf.int_to_float(SINGLE, 0x1000001, true)), (0x4B800000, NX));
+        assert_eq!(run(RUP, |sf| sf.int_to_float(SINGLE, 0x1000001, true)), (0x4B800001, NX));
+        assert_eq!(run(RNE, |sf| sf.int_to_float(DOUBLE, -1i64 as u64, true)), (0xBFF0000000000000, 0));
+        assert_eq!(run(RNE, |sf| sf.int_to_float(DOUBLE, u64::MAX, false)), (0x43F0000000000000, NX));
+    }
+
+    #[test]
+    fn comparisons() {
+        // FEQ is quiet, FLT and FLE signal on any NaN
+        let mut sf = Softfloat::new(RNE);
+        assert!(!sf.eq(SINGLE, QNAN_S, QNAN_S));
+        assert_eq!(sf.flags, 0);
+        assert!(!sf.lt(SINGLE, QNAN_S, ONE_S, false));
+        assert_eq!(sf.flags, NV);
+
+        let mut sf = Softfloat::new(RNE);
+        assert!(sf.eq(SINGLE, 0x00000000, 0x80000000));
+        assert!(sf.lt(SINGLE, 0x80000000, 0x00000000, true));
+        assert!(!sf.lt(SINGLE, 0x80000000, 0x00000000, false));
+        assert_eq!(sf.flags, 0);
+    }
+
+    #[test]
+    fn classify_values() {
+        assert_eq!(classify(SINGLE, 0xFF800000), 1 << 0);
+        assert_eq!(classify(SINGLE, 0x80000001), 1 << 2);
+        assert_eq!(classify(SINGLE, 0x00000000), 1 << 4);
+        assert_eq!(classify(SINGLE, ONE_S), 1 << 6);
+        assert_eq!(classify(SINGLE, SNAN_S), 1 << 8);
+        assert_eq!(classify(SINGLE, QNAN_S), 1 << 9);
+    }
+}
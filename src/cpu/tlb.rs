@@ -0,0 +1,164 @@
+// Direct-mapped, indexed by the low bits of the 4 KiB virtual page number.
+// Superpages are cached one 4 KiB slice at a time.
+const TLB_ENTRIES: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Entry {
+    valid: bool,
+    vpn: u64,
+    asid: u16,
+    global: bool,
+    // Level of the leaf PTE, 0 for a 4 KiB page
+    pub level: u64,
+    pub pte: u64
+}
+
+pub struct Tlb {
+    entries: Vec<Entry>,
+    hits: u64,
+    misses: u64
+}
+
+impl Tlb {
+    pub fn new() -> Self {
+        Self {
+            entries: vec![Default::default(); TLB_ENTRIES],
+            hits: 0,
+            misses: 0
+        }
+    }
+
+    // Only counts as a hit if the cached entry is usable for this access
+    pub fn lookup<F: Fn(&Entry) -> bool>(&mut self, vaddr: u64, asid: u16, usable: F) -> Option<Entry> {
+        let vpn = vaddr >> 12;
+        let entry = self.entries[vpn as usize % TLB_ENTRIES];
+
+        if entry.valid && entry.vpn == vpn && (entry.global || entry.asid == asid) && usable(&entry) {
+            self.hits += 1;
+            Some(entry)
+        } else {
+            self.misses += 1;
+            None
+        }
+    }
+
+    // Hits and misses since the hart started
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    pub fn insert(&mut self, vaddr: u64, asid: u16, global: bool, level: u64, pte: u64) {
+        let vpn = vaddr >> 12;
+
+        self.entries[vpn as usize % TLB_ENTRIES] = Entry { valid: true, vpn, asid, global, level, pte };
+    }
+
+    // SFENCE.VMA semantics: a None address flushes every page, a None ASID flushes every
+    // address space including global mappings.
+    pub fn flush(&mut self, vaddr: Option<u64>, asid: Option<u16>) {
+        for entry in self.entries.iter_mut() {
+            let page_matches = match vaddr {
+                Some(vaddr) => {
+                    let shift = 9 * entry.level;
+                    (entry.vpn >> shift) == ((vaddr >> 12) >> shift)
+                },
+                None => true
+            };
+
+            let asid_matches = match asid {
+                Some(asid) => !entry.global && entry.asid == asid,
+                None => true
+            };
+
+            if page_matches && asid_matches {
+                entry.valid = false;
+            }
+        }
+    }
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for Tlb {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (hits, misses) = self.stats();
+        let rate = if hits + misses == 0 { 0.0 } else { hits as f64 * 100.0 / (hits + misses) as f64 };
+
+        write!(f, "hits={} misses={} ({:.2}% hit rate)", hits, misses, rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached(tlb: &mut Tlb, vaddr: u64, asid: u16) -> bool {
+        tlb.lookup(vaddr, asid, |_| true).is_some()
+    }
+
+    #[test]
+    fn lookup() {
+        let mut tlb = Tlb::new();
+        tlb.insert(0x40001000, 1, false, 0, 0x1234);
+
+        assert_eq!(tlb.lookup(0x40001ABC, 1, |_| true).map(|entry| entry.pte), Some(0x1234));
+        // Another address space, or an entry that isn't good for this access
+        assert!(!cached(&mut tlb, 0x40001000, 2));
+        assert!(tlb.lookup(0x40001000, 1, |_| false).is_none());
+        // Same slot, another page
+        assert!(!cached(&mut tlb, 0x40001000 + (TLB_ENTRIES as u64) * 4096, 1));
+
+        assert_eq!(tlb.stats(), (1, 3));
+    }
+
+    #[test]
+    fn global_entries() {
+        let mut tlb = Tlb::new();
+        tlb.insert(0x40001000, 1, true, 0, 0);
+        tlb.insert(0x40002000, 1, false, 0, 0);
+
+        // Seen from every address space, and not flushed with one
+        assert!(cached(&mut tlb, 0x40001000, 7));
+        tlb.flush(None, Some(1));
+        assert!(cached(&mut tlb, 0x40001000, 1));
+        assert!(!cached(&mut tlb, 0x40002000, 1));
+
+        tlb.flush(None, None);
+        assert!(!cached(&mut tlb, 0x40001000, 1));
+    }
+
+    #[test]
+    fn flush_by_address() {
+        let mut tlb = Tlb::new();
+        tlb.insert(0x40001000, 1, false, 0, 0);
+        tlb.insert(0x40002000, 1, false, 0, 0);
+        tlb.insert(0x40003000, 2, false, 0, 0);
+
+        tlb.flush(Some(0x40001000), None);
+        assert!(!cached(&mut tlb, 0x40001000, 1));
+        assert!(cached(&mut tlb, 0x40002000, 1));
+
+        // Only that page in that address space
+        tlb.flush(Some(0x40003000), Some(1));
+        assert!(cached(&mut tlb, 0x40003000, 2));
+        tlb.flush(Some(0x40003000), Some(2));
+        assert!(!cached(&mut tlb, 0x40003000, 2));
+    }
+
+    #[test]
+    fn flush_superpages() {
+        let mut tlb = Tlb::new();
+        // Two 4 KiB slices of the same 2 MiB page
+        tlb.insert(0x40200000, 1, false, 1, 0);
+        tlb.insert(0x40345000, 1, false, 1, 0);
+
+        // Any address in the superpage flushes all of it
+        tlb.flush(Some(0x403FF000), None);
+        assert!(!cached(&mut tlb, 0x40200000, 1));
+        assert!(!cached(&mut tlb, 0x40345000, 1));
+    }
+}
@@ -1,4 +1,7 @@
-fn get_reg_name(reg: u32) -> String {
+use crate::cpu::rvc;
+use super::symbols::Symbols;
+
+pub fn get_reg_name(reg: u32) -> String {
     let abi = [
             "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0",
             "a1", "a2", "a3", "a4", "a5", "a6", "a7", "s2", "s3", "s4", "s5",
@@ -8,7 +11,34 @@ fn get_reg_name(reg: u32) -> String {
     abi[reg as usize].to_string()
 }
 
-pub fn disasm_general(instr: u32) -> String {
+pub fn get_freg_name(reg: u32) -> String {
+    let abi = [
+            "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0",
+            "fa1", "fa2", "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5",
+            "fs6", "fs7", "fs8", "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+        ];
+
+    abi[reg as usize].to_string()
+}
+
+// Symbol of a branch or jump target, if there's one
+fn target(pc: u64, offset: i64, symbols: &Symbols) -> String {
+    match symbols.lookup(pc.wrapping_add(offset as u64)) {
+        Some(symbol) => format!(" <{}>", symbol),
+        None => String::new()
+    }
+}
+
+// pc is the address of the instruction, to find the targets of branches and jumps
+pub fn disasm_general(instr: u32, pc: u64, symbols: &Symbols) -> String {
+    // Compressed instructions are shown as the instruction they expand to
+    if instr & 0x3 != 0x3 {
+        return match rvc::expand(instr as u16) {
+            Some(expanded) => format!("c.{}", disasm_general(expanded, pc, symbols)),
+            None => format!("Can't disassemble instr {:04x}", instr)
+        };
+    }
+
     let opcode = instr & 0x7F;
     let funct3 = (instr >> 12) & 0x7;
     let rd = (instr >> 7) & 0x1F;
@@ -34,12 +64,24 @@ pub fn disasm_general(instr: u32) -> String {
                 0x5 => { format!("lhu {}, {}({})", get_reg_name(rs1), imm, get_reg_name(rs1)) }
                 // LWU
                 0x6 => { format!("lwu {}, {}({})", get_reg_name(rs1), imm, get_reg_name(rs1)) }
-                _ => panic!("Can't disassemble instr {:08x}", instr)
+                _ => format!("Can't disassemble instr {:08x}", instr)
             }
         },
+        0x07 => {
+            // RV32/64F and D load functions
+            let imm = (instr as i32 as i64) >> 20;
+
+            match funct3 {
+                // FLW
+                0x2 => { format!("flw {}, {}({})", get_freg_name(rd), imm, get_reg_name(rs1)) }
+                // FLD
+                0x3 => { format!("fld {}, {}({})", get_freg_name(rd), imm, get_reg_name(rs1)) }
+                _ => format!("Can't disassemble instr {:08x}", instr)
+            }
+        }
         0xF => {
             // FENCE instructions
-            format!("fence")
+            "fence".to_string()
         }
         0x13 => {
             // Immediate functions
@@ -66,7 +108,7 @@ pub fn disasm_general(instr: u32) -> String {
                 (0x6, _) => { format!("ori {}, {}, {:03x}", get_reg_name(rd), get_reg_name(rs1), imm) }
                 // ANDI
                 (0x7, _) => { format!("andi {}, {}, {:03x}", get_reg_name(rd), get_reg_name(rs1), imm) }
-                _ => panic!("Can't disassemble instr {:08x}", instr)
+                _ => format!("Can't disassemble instr {:08x}", instr)
             }
         },
         0x17 => {
@@ -90,12 +132,12 @@ pub fn disasm_general(instr: u32) -> String {
                 (0x5, 0x0) => { format!("srliw {}, {}, {}", get_reg_name(rd), get_reg_name(rs1), shamt) }
                 // SRAIW
                 (0x5, 0x1) => { format!("sraiw {}, {}, {}", get_reg_name(rd), get_reg_name(rs1), shamt) }
-                _ => panic!("Can't disassemble instr {:08x}", instr)
+                _ => format!("Can't disassemble instr {:08x}", instr)
             }
         },
         0x23 => {
             // RV32/64I store instructions
-            let imm = ((((instr & 0xfe000000) as i32 as i64) >> 20) ) | ((instr >> 7) & 0x1F) as i64;
+            let imm = (((instr & 0xfe000000) as i32 as i64) >> 20) | ((instr >> 7) & 0x1F) as i64;
             let rs2 = (instr >> 20) & 0x1F;
 
             match funct3 {
@@ -107,7 +149,51 @@ pub fn disasm_general(instr: u32) -> String {
                 0x2 => { format!("sw {}, {}({})", get_reg_name(rs2), imm, get_reg_name(rs1)) }
                 // SD
                 0x3 => { format!("sd {}, {}({})", get_reg_name(rs2), imm, get_reg_name(rs1)) }
-                _ => panic!("Can't disassemble instr {:08x}", instr)
+                _ => format!("Can't disassemble instr {:08x}", instr)
+            }
+        }
+        0x27 => {
+            // RV32/64F and D store instructions
+            let imm = (((instr & 0xfe000000) as i32 as i64) >> 20) | ((instr >> 7) & 0x1F) as i64;
+            let rs2 = (instr >> 20) & 0x1F;
+
+            match funct3 {
+                // FSW
+                0x2 => { format!("fsw {}, {}({})", get_freg_name(rs2), imm, get_reg_name(rs1)) }
+                // FSD
+                0x3 => { format!("fsd {}, {}({})", get_freg_name(rs2), imm, get_reg_name(rs1)) }
+                _ => format!("Can't disassemble instr {:08x}", instr)
+            }
+        }
+        0x2F => {
+            // RV32/64A atomic instructions
+            let rs2 = (instr >> 20) & 0x1F;
+            let funct5 = instr >> 27;
+            let suffix = format!("{}{}{}",
+                if funct3 == 0x2 { ".w" } else { ".d" },
+                if instr & (1 << 26) != 0 { ".aq" } else { "" },
+                if instr & (1 << 25) != 0 { ".rl" } else { "" }
+            );
+
+            let name = match funct5 {
+                0x00 => "amoadd",
+                0x01 => "amoswap",
+                0x02 => "lr",
+                0x03 => "sc",
+                0x04 => "amoxor",
+                0x08 => "amoor",
+                0x0C => "amoand",
+                0x10 => "amomin",
+                0x14 => "amomax",
+                0x18 => "amominu",
+                0x1C => "amomaxu",
+                _ => return format!("Can't disassemble instr {:08x}", instr)
+            };
+
+            if funct5 == 0x02 {
+                format!("{}{} {}, ({})", name, suffix, get_reg_name(rd), get_reg_name(rs1))
+            } else {
+                format!("{}{} {}, {}, ({})", name, suffix, get_reg_name(rd), get_reg_name(rs2), get_reg_name(rs1))
             }
         }
         0x37 => {
@@ -159,7 +245,7 @@ pub fn disasm_general(instr: u32) -> String {
                 (0x6, 1) => { format!("rem {}, {}, {}", get_reg_name(rd), get_reg_name(rs1), get_reg_name(rs2)) }
                 // REMU
                 (0x7, 1) => { format!("remu {}, {}, {}", get_reg_name(rd), get_reg_name(rs1), get_reg_name(rs2)) }
-                _ => panic!("Can't disassemble instr {:08x}", instr)
+                _ => format!("Can't disassemble instr {:08x}", instr)
             }
         },
         0x3B => {
@@ -189,9 +275,80 @@ pub fn disasm_general(instr: u32) -> String {
                 (0x6, 1) => { format!("remw {}, {}, {}", get_reg_name(rd), get_reg_name(rs1), get_reg_name(rs2)) }
                 // REMUW
                 (0x7, 1) => { format!("remuw {}, {}, {}", get_reg_name(rd), get_reg_name(rs1), get_reg_name(rs2)) }
-                _ => panic!("Can't disassemble instr {:08x}", instr)
+                _ => format!("Can't disassemble instr {:08x}", instr)
             }
         },
+        0x43 | 0x47 | 0x4B | 0x4F => {
+            // RV32/64F and D fused multiply-adds
+            let rs2 = (instr >> 20) & 0x1F;
+            let rs3 = instr >> 27;
+            let fmt = if (instr >> 25) & 0x3 == 0 { "s" } else { "d" };
+            let name = match opcode {
+                0x43 => "fmadd",
+                0x47 => "fmsub",
+                0x4B => "fnmsub",
+                _ => "fnmadd"
+            };
+
+            format!("{}.{} {}, {}, {}, {}", name, fmt,
+                get_freg_name(rd), get_freg_name(rs1), get_freg_name(rs2), get_freg_name(rs3))
+        }
+        0x53 => {
+            // RV32/64F and D computational instructions
+            let rs2 = (instr >> 20) & 0x1F;
+            let funct5 = instr >> 27;
+            let fmt = if (instr >> 25) & 0x3 == 0 { "s" } else { "d" };
+            let int_fmt = ["w", "wu", "l", "lu"];
+
+            match (funct5, funct3) {
+                // FADD, FSUB, FMUL, FDIV
+                (0x00..=0x03, _) => {
+                    let name = ["fadd", "fsub", "fmul", "fdiv"][funct5 as usize];
+                    format!("{}.{} {}, {}, {}", name, fmt, get_freg_name(rd), get_freg_name(rs1), get_freg_name(rs2))
+                }
+                // FSQRT
+                (0x0B, _) => { format!("fsqrt.{} {}, {}", fmt, get_freg_name(rd), get_freg_name(rs1)) }
+                // FSGNJ, FSGNJN, FSGNJX
+                (0x04, 0x0..=0x2) => {
+                    let name = ["fsgnj", "fsgnjn", "fsgnjx"][funct3 as usize];
+                    format!("{}.{} {}, {}, {}", name, fmt, get_freg_name(rd), get_freg_name(rs1), get_freg_name(rs2))
+                }
+                // FMIN, FMAX
+                (0x05, 0x0..=0x1) => {
+                    let name = ["fmin", "fmax"][funct3 as usize];
+                    format!("{}.{} {}, {}, {}", name, fmt, get_freg_name(rd), get_freg_name(rs1), get_freg_name(rs2))
+                }
+                // FCVT.S.D, FCVT.D.S
+                (0x08, _) => {
+                    let from = if rs2 == 0 { "s" } else { "d" };
+                    format!("fcvt.{}.{} {}, {}", fmt, from, get_freg_name(rd), get_freg_name(rs1))
+                }
+                // FLE, FLT, FEQ
+                (0x14, 0x0..=0x2) => {
+                    let name = ["fle", "flt", "feq"][funct3 as usize];
+                    format!("{}.{} {}, {}, {}", name, fmt, get_reg_name(rd), get_freg_name(rs1), get_freg_name(rs2))
+                }
+                // FCVT to an integer
+                (0x18, _) if rs2 < 4 => {
+                    format!("fcvt.{}.{} {}, {}", int_fmt[rs2 as usize], fmt, get_reg_name(rd), get_freg_name(rs1))
+                }
+                // FCVT from an integer
+                (0x1A, _) if rs2 < 4 => {
+                    format!("fcvt.{}.{} {}, {}", fmt, int_fmt[rs2 as usize], get_freg_name(rd), get_reg_name(rs1))
+                }
+                // FMV.X.W, FMV.X.D
+                (0x1C, 0x0) => {
+                    format!("fmv.x.{} {}, {}", if fmt == "s" { "w" } else { "d" }, get_reg_name(rd), get_freg_name(rs1))
+                }
+                // FCLASS
+                (0x1C, 0x1) => { format!("fclass.{} {}, {}", fmt, get_reg_name(rd), get_freg_name(rs1)) }
+                // FMV.W.X, FMV.D.X
+                (0x1E, 0x0) => {
+                    format!("fmv.{}.x {}, {}", if fmt == "s" { "w" } else { "d" }, get_freg_name(rd), get_reg_name(rs1))
+                }
+                _ => format!("Can't disassemble instr {:08x}", instr)
+            }
+        }
         0x63 => {
             // RV32/64I branch instructions
             let offset = (((instr & 0x80000000) as i32 as i64) >> 19) |
@@ -202,18 +359,18 @@ pub fn disasm_general(instr: u32) -> String {
 
             match funct3 {
                 // BEQ
-                0x0 => { format!("beq {}, {}, {}", get_reg_name(rs1), get_reg_name(rs2), offset) }
+                0x0 => { format!("beq {}, {}, {}{}", get_reg_name(rs1), get_reg_name(rs2), offset, target(pc, offset, symbols)) }
                 // BNE
-                0x1 => { format!("bne {}, {}, {}", get_reg_name(rs1), get_reg_name(rs2), offset) }
+                0x1 => { format!("bne {}, {}, {}{}", get_reg_name(rs1), get_reg_name(rs2), offset, target(pc, offset, symbols)) }
                 // BLT
-                0x4 => { format!("blt {}, {}, {}", get_reg_name(rs1), get_reg_name(rs2), offset) }
+                0x4 => { format!("blt {}, {}, {}{}", get_reg_name(rs1), get_reg_name(rs2), offset, target(pc, offset, symbols)) }
                 // BGE
-                0x5 => { format!("bge {}, {}, {}", get_reg_name(rs1), get_reg_name(rs2), offset) }
+                0x5 => { format!("bge {}, {}, {}{}", get_reg_name(rs1), get_reg_name(rs2), offset, target(pc, offset, symbols)) }
                 // BLTU 
-                0x6 => { format!("bltu {}, {}, {}", get_reg_name(rs1), get_reg_name(rs2), offset) }
+                0x6 => { format!("bltu {}, {}, {}{}", get_reg_name(rs1), get_reg_name(rs2), offset, target(pc, offset, symbols)) }
                 // BGEU
-                0x7 => { format!("bgeu {}, {}, {}", get_reg_name(rs1), get_reg_name(rs2), offset) }
-                _ => panic!("Can't disassemble instr {:08x}", instr)
+                0x7 => { format!("bgeu {}, {}, {}{}", get_reg_name(rs1), get_reg_name(rs2), offset, target(pc, offset, symbols)) }
+                _ => format!("Can't disassemble instr {:08x}", instr)
             }
         }
         0x67 => {
@@ -229,29 +386,34 @@ pub fn disasm_general(instr: u32) -> String {
                 ((instr >> 9) & 0x800) as i32 |
                 ((instr >> 20) & 0x7fe) as i32;
 
-                format!("jal {}, {}", get_reg_name(rd), offset)
+                format!("jal {}, {}{}", get_reg_name(rd), offset, target(pc, offset as i64, symbols))
         },
         0x73 => {
-            // Environment calls and breakpoints, and Zicsr
+            // Environment calls and breakpoints, privileged instructions, and Zicsr
             let csr = instr >> 20;
+            let rs2 = (instr >> 20) & 0x1f;
 
             match funct3 {
+                0x0 if instr >> 25 == 0x09 => format!("sfence.vma {}, {}", get_reg_name(rs1), get_reg_name(rs2)),
                 0x0 => {
-                    match csr & 1 {
-                        0 => format!("ecall"),
-                        1 => format!("ebreak"),
-                        _ => unreachable!()
+                    match csr {
+                        0x000 => "ecall".to_string(),
+                        0x001 => "ebreak".to_string(),
+                        0x102 => "sret".to_string(),
+                        0x302 => "mret".to_string(),
+                        0x105 => "wfi".to_string(),
+                        _ => format!("Can't disassemble instr {:08x}", instr)
                     }
                 },
                 0x1 => format!("csrrw {}, {:04x}, {}", get_reg_name(rd), csr, get_reg_name(rs1)),
                 0x2 => format!("csrrs {}, {:04x}, {}", get_reg_name(rd), csr, get_reg_name(rs1)),
                 0x3 => format!("csrrc {}, {:04x}, {}", get_reg_name(rd), csr, get_reg_name(rs1)),
-                0x5 => format!("csrrsi {}, {:04x}, {:02x}", get_reg_name(rd), csr, rs1),
+                0x5 => format!("csrrwi {}, {:04x}, {:02x}", get_reg_name(rd), csr, rs1),
                 0x6 => format!("csrrsi {}, {:04x}, {:02x}", get_reg_name(rd), csr, rs1),
                 0x7 => format!("csrrci {}, {:04x}, {:02x}", get_reg_name(rd), csr, rs1),
                 _ => format!("Can't disassemble instr {:08x}", instr)
             }
         }
-        _ => panic!("Can't disassemble instr {:08x}", instr)
+        _ => format!("Can't disassemble instr {:08x}", instr)
     }
 }
\ No newline at end of file
@@ -0,0 +1,663 @@
+// GDB remote serial protocol stub, so GDB can debug what runs on the emulator with
+// `target remote localhost:1234`. There's a single hart, which GDB sees as thread 1.
+
+use std::collections::BTreeSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use crate::bus::{Watchpoint, WatchKind};
+use crate::cpu::CPU;
+use crate::cpu::csr;
+
+// Register numbers as GDB knows them for RISC-V, CSRs are numbered after the FPU registers
+const PC_REGNUM: usize = 32;
+const FIRST_FPR_REGNUM: usize = 33;
+const LAST_FPR_REGNUM: usize = 64;
+const FIRST_CSR_REGNUM: usize = 65;
+const PRIV_REGNUM: usize = FIRST_CSR_REGNUM + 4096;
+
+const GPR_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6"
+];
+
+const FPR_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2", "fa3", "fa4", "fa5",
+    "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11"
+];
+
+// The CSRs GDB shows, the others can still be read with their number
+const CSR_NAMES: [(&str, u16); 25] = [
+    ("sstatus", csr::SSTATUS), ("sie", csr::SIE), ("stvec", csr::STVEC), ("scounteren", csr::SCOUNTEREN),
+    ("sscratch", csr::SSCRATCH), ("sepc", csr::SEPC), ("scause", csr::SCAUSE), ("stval", csr::STVAL), ("sip", csr::SIP), ("satp", csr::SATP),
+    ("mstatus", csr::MSTATUS), ("misa", csr::MISA), ("medeleg", csr::MEDELEG), ("mideleg", csr::MIDELEG), ("mie", csr::MIE),
+    ("mtvec", csr::MTVEC), ("mcounteren", csr::MCOUNTEREN), ("mscratch", csr::MSCRATCH), ("mepc", csr::MEPC), ("mcause", csr::MCAUSE),
+    ("mtval", csr::MTVAL), ("mip", csr::MIP), ("mcycle", csr::MCYCLE), ("minstret", csr::MINSTRET), ("mhartid", csr::MHARTID)
+];
+
+// Biggest packet GDB can send us, as told in qSupported. Replies to m are kept under it too.
+const PACKET_SIZE: usize = 0x4000;
+
+// How many instructions run between two checks for a Ctrl-C from GDB
+const INTERRUPT_CHECK_INTERVAL: u64 = 1024;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// TCP or Unix socket, what's needed to poll for Ctrl-C while the program runs
+trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+// Why the program stopped
+enum Stop {
+    Signal(u8),
+    // swbreak or hwbreak
+    Breakpoint(&'static str),
+    // watch, rwatch or awatch, with the address that was accessed
+    Watchpoint(&'static str, u64),
+    Exited(i32)
+}
+
+pub struct GdbStub {
+    connection: Box<dyn Connection>,
+    // Neither kind is written to memory, the addresses are checked before each instruction
+    breakpoints: BTreeSet<u64>,
+    hw_breakpoints: BTreeSet<u64>,
+    // With the virtual address GDB asked for, the bus only knows the physical one
+    watchpoints: Vec<(u64, Watchpoint)>
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn parse_number(number: &str) -> Option<u64> {
+    u64::from_str_radix(number, 16).ok()
+}
+
+// Registers are sent as little-endian bytes
+fn parse_register(value: &str) -> Option<u64> {
+    let bytes = parse_hex(value)?;
+    if bytes.len() != 8 {
+        return None;
+    }
+
+    Some(bytes.iter().rev().fold(0, |value, &byte| (value << 8) | byte as u64))
+}
+
+fn read_register(cpu: &CPU, reg: usize) -> Option<u64> {
+    match reg {
+        0..=31 => Some(cpu.xreg(reg as u32)),
+        PC_REGNUM => Some(cpu.pc()),
+        FIRST_FPR_REGNUM..=LAST_FPR_REGNUM => Some(cpu.freg((reg - FIRST_FPR_REGNUM) as u32)),
+        FIRST_CSR_REGNUM..PRIV_REGNUM => cpu.read_csr((reg - FIRST_CSR_REGNUM) as u16),
+        PRIV_REGNUM => Some(cpu.privilege()),
+        _ => None
+    }
+}
+
+fn write_register(cpu: &mut CPU, reg: usize, value: u64) -> bool {
+    match reg {
+        0..=31 => cpu.set_xreg(reg as u32, value),
+        PC_REGNUM => cpu.set_pc(value),
+        FIRST_FPR_REGNUM..=LAST_FPR_REGNUM => cpu.set_freg((reg - FIRST_FPR_REGNUM) as u32, value),
+        FIRST_CSR_REGNUM..PRIV_REGNUM => return cpu.write_csr((reg - FIRST_CSR_REGNUM) as u16, value),
+        PRIV_REGNUM => cpu.set_privilege(value),
+        _ => return false
+    }
+
+    true
+}
+
+// Describes the registers to GDB, with the numbers used in p and P packets
+fn target_xml() -> String {
+    let reg = |name: &str, regnum: usize, kind: &str| {
+        format!("<reg name=\"{}\" bitsize=\"64\" regnum=\"{}\" type=\"{}\"/>", name, regnum, kind)
+    };
+
+    let gprs: String = GPR_NAMES.iter().enumerate().map(|(i, name)| {
+        reg(name, i, match i { 1 => "code_ptr", 2..=4 => "data_ptr", _ => "int" })
+    }).collect();
+    let fprs: String = FPR_NAMES.iter().enumerate().map(|(i, name)| reg(name, FIRST_FPR_REGNUM + i, "ieee_double")).collect();
+    let fcsrs: String = ["fflags", "frm", "fcsr"].iter().enumerate()
+        .map(|(i, name)| reg(name, FIRST_CSR_REGNUM + csr::FFLAGS as usize + i, "int")).collect();
+    let csrs: String = CSR_NAMES.iter().map(|&(name, addr)| reg(name, FIRST_CSR_REGNUM + addr as usize, "int")).collect();
+
+    format!("<?xml version=\"1.0\"?>\
+        <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+        <target version=\"1.0\">\
+        <architecture>riscv:rv64</architecture>\
+        <feature name=\"org.gnu.gdb.riscv.cpu\">{}{}</feature>\
+        <feature name=\"org.gnu.gdb.riscv.fpu\">{}{}</feature>\
+        <feature name=\"org.gnu.gdb.riscv.csr\">{}</feature>\
+        <feature name=\"org.gnu.gdb.riscv.virtual\">{}</feature>\
+        </target>",
+        gprs, reg("pc", PC_REGNUM, "code_ptr"), fprs, fcsrs, csrs, reg("priv", PRIV_REGNUM, "int"))
+}
+
+impl GdbStub {
+    // Waits for GDB to connect. The address is a TCP port on localhost, or the path of a Unix socket.
+    pub fn listen(address: &str) -> io::Result<Self> {
+        let connection: Box<dyn Connection> = match address.parse::<u16>() {
+            Ok(port) => {
+                let listener = TcpListener::bind(("127.0.0.1", port))?;
+                eprintln!("Waiting for GDB on localhost:{}", port);
+
+                let (stream, _) = listener.accept()?;
+                // Packets are small and GDB waits for each answer
+                stream.set_nodelay(true)?;
+                Box::new(stream)
+            }
+            Err(_) => {
+                let listener = UnixListener::bind(address)?;
+                eprintln!("Waiting for GDB on {}", address);
+
+                let (stream, _) = listener.accept()?;
+                // Nobody else can connect, and the next run can bind it again
+                std::fs::remove_file(address)?;
+                Box::new(stream)
+            }
+        };
+
+        Ok(Self { connection, breakpoints: BTreeSet::new(), hw_breakpoints: BTreeSet::new(), watchpoints: vec![] })
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.connection.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    // Next packet from GDB, without the framing. Acks and Ctrl-C while stopped are skipped.
+    fn receive(&mut self) -> io::Result<String> {
+        loop {
+            while self.read_byte()? != b'$' {}
+
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => data.push(byte)
+                }
+            }
+
+            let mut checksum = [0; 2];
+            self.connection.read_exact(&mut checksum)?;
+
+            let expected = std::str::from_utf8(&checksum).ok().and_then(parse_number);
+            let actual = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+
+            if expected == Some(actual as u64) {
+                self.connection.write_all(b"+")?;
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            }
+
+            // GDB sends it again
+            self.connection.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+
+        loop {
+            write!(self.connection, "${}#{:02x}", data, checksum)?;
+            self.connection.flush()?;
+
+            // Sent again until GDB acks it
+            loop {
+                match self.read_byte()? {
+                    b'+' => return Ok(()),
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // Whether GDB sent a Ctrl-C (0x03) while the program runs
+    fn interrupted(&mut self) -> io::Result<bool> {
+        let mut byte = [0];
+
+        self.connection.set_nonblocking(true)?;
+        let result = self.connection.read(&mut byte);
+        self.connection.set_nonblocking(false)?;
+
+        match result {
+            Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+            Ok(_) => Ok(byte[0] == 0x03),
+            Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error)
+        }
+    }
+
+    // The instruction at the current pc runs even if there's a breakpoint on it, that's the
+    // one GDB resumes from
+    fn resume(&mut self, cpu: &mut CPU, step: bool) -> io::Result<Stop> {
+        let mut count: u64 = 0;
+
+        loop {
+            if let Some(code) = cpu.step() {
+                return Ok(Stop::Exited(code));
+            }
+
+            // The access is done by then, GDB sees the instruction that made it as already executed
+            if let Some((_, hit)) = cpu.take_watch_hit() {
+                if let Some(&(addr, watchpoint)) = self.watchpoints.iter().find(|(_, watchpoint)| *watchpoint == hit.watchpoint) {
+                    let kind = match watchpoint.kind {
+                        WatchKind::Write => "watch",
+                        WatchKind::Read => "rwatch",
+                        WatchKind::Access => "awatch"
+                    };
+
+                    return Ok(Stop::Watchpoint(kind, addr + hit.addr.saturating_sub(watchpoint.addr)));
+                }
+            }
+
+            if step {
+                return Ok(Stop::Signal(SIGTRAP));
+            }
+
+            if self.breakpoints.contains(&cpu.pc()) {
+                return Ok(Stop::Breakpoint("swbreak"));
+            }
+
+            if self.hw_breakpoints.contains(&cpu.pc()) {
+                return Ok(Stop::Breakpoint("hwbreak"));
+            }
+
+            count += 1;
+            if count.is_multiple_of(INTERRUPT_CHECK_INTERVAL) && self.interrupted()? {
+                return Ok(Stop::Signal(SIGINT));
+            }
+        }
+    }
+
+    // c and s, with an optional address to resume from
+    fn run_until_stop(&mut self, cpu: &mut CPU, args: &str, step: bool) -> io::Result<Option<i32>> {
+        if let Some(addr) = parse_number(args) {
+            cpu.set_pc(addr);
+        }
+
+        match self.resume(cpu, step)? {
+            Stop::Signal(signal) => self.send(&format!("S{:02x}", signal))?,
+            Stop::Breakpoint(kind) => self.send(&format!("T{:02x}{}:;", SIGTRAP, kind))?,
+            Stop::Watchpoint(kind, addr) => self.send(&format!("T{:02x}{}:{:x};", SIGTRAP, kind, addr))?,
+            Stop::Exited(code) => {
+                self.send(&format!("W{:02x}", code as u8))?;
+                return Ok(Some(code));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Z and z packets: type,addr,kind. For watchpoints, kind is the length.
+    fn breakpoint(&mut self, cpu: &mut CPU, args: &str, insert: bool) -> String {
+        let fields: Vec<&str> = args.split(',').collect();
+        let (kind, addr, len) = match fields[..] {
+            [kind, addr, len] => match (parse_number(addr), parse_number(len)) {
+                (Some(addr), Some(len)) => (kind, addr, len),
+                _ => return "E01".to_string()
+            },
+            _ => return "E01".to_string()
+        };
+
+        let set = match kind {
+            "0" => &mut self.breakpoints,
+            "1" => &mut self.hw_breakpoints,
+            "2" | "3" | "4" => return self.watchpoint(cpu, kind, addr, len, insert),
+            _ => return String::new()
+        };
+
+        if insert { set.insert(addr); } else { set.remove(&addr); }
+        "OK".to_string()
+    }
+
+    fn watchpoint(&mut self, cpu: &mut CPU, kind: &str, addr: u64, len: u64, insert: bool) -> String {
+        let kind = match kind {
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            _ => WatchKind::Access
+        };
+
+        if insert {
+            let paddr = match cpu.physical_addr(addr) {
+                Some(paddr) => paddr,
+                None => return "E14".to_string()
+            };
+            let watchpoint = Watchpoint { addr: paddr, size: len, kind };
+
+            cpu.add_watchpoint(watchpoint);
+            self.watchpoints.push((addr, watchpoint));
+        } else if let Some(index) = self.watchpoints.iter()
+            .position(|&(other, watchpoint)| other == addr && watchpoint.size == len && watchpoint.kind == kind) {
+            let (_, watchpoint) = self.watchpoints.remove(index);
+            cpu.remove_watchpoint(watchpoint);
+        }
+
+        "OK".to_string()
+    }
+
+    fn read_memory(cpu: &mut CPU, args: &str) -> Option<String> {
+        let (addr, len) = args.split_once(',')?;
+        // Each byte is two hex digits. GDB reads the rest with another packet.
+        let len = parse_number(len)?.min(PACKET_SIZE as u64 / 2);
+        let mut data = vec![0; len as usize];
+
+        if cpu.read_memory(parse_number(addr)?, &mut data) { Some(hex(&data)) } else { None }
+    }
+
+    fn write_memory(cpu: &mut CPU, args: &str) -> Option<()> {
+        let (addr, rest) = args.split_once(',')?;
+        let (len, data) = rest.split_once(':')?;
+        let data = parse_hex(data)?;
+
+        if data.len() as u64 != parse_number(len)? || !cpu.write_memory(parse_number(addr)?, &data) {
+            return None;
+        }
+
+        Some(())
+    }
+
+    fn query(&mut self, query: &str) -> String {
+        if let Some(annex) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let xml = target_xml();
+            let (offset, length) = match annex.split_once(',') {
+                Some((offset, length)) => (parse_number(offset).unwrap_or(0) as usize, parse_number(length).unwrap_or(0) as usize),
+                None => return "E01".to_string()
+            };
+
+            let start = offset.min(xml.len());
+            let end = offset.saturating_add(length).min(xml.len());
+            // l for the last chunk
+            let more = if end < xml.len() { "m" } else { "l" };
+
+            return format!("{}{}", more, &xml[start..end]);
+        }
+
+        match query.split(':').next().unwrap_or("") {
+            "Supported" => format!("PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+", PACKET_SIZE),
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new()
+        }
+    }
+
+    // Answers GDB until it detaches, then the emulator runs on its own. Returns the exit code
+    // if the machine powered off (or GDB killed it) in the meantime.
+    pub fn run(&mut self, cpu: &mut CPU) -> io::Result<Option<i32>> {
+        let result = self.serve(cpu);
+
+        // However GDB left, nothing is there anymore to report its watchpoints
+        for (_, watchpoint) in self.watchpoints.drain(..) {
+            cpu.remove_watchpoint(watchpoint);
+        }
+
+        result
+    }
+
+    fn serve(&mut self, cpu: &mut CPU) -> io::Result<Option<i32>> {
+        loop {
+            let packet = self.receive()?;
+            let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+            let reply = match command {
+                "?" => format!("S{:02x}", SIGTRAP),
+                "g" => (0..=PC_REGNUM).map(|reg| hex(&read_register(cpu, reg).unwrap_or(0).to_le_bytes())).collect(),
+                "G" => {
+                    let values: Option<Vec<u64>> = (0..=PC_REGNUM).map(|reg| parse_register(args.get(16 * reg..16 * (reg + 1))?)).collect();
+
+                    match values {
+                        Some(values) => {
+                            for (reg, value) in values.into_iter().enumerate() {
+                                write_register(cpu, reg, value);
+                            }
+                            "OK".to_string()
+                        }
+                        None => "E01".to_string()
+                    }
+                }
+                "p" => match parse_number(args).and_then(|reg| read_register(cpu, reg as usize)) {
+                    Some(value) => hex(&value.to_le_bytes()),
+                    None => "E01".to_string()
+                },
+                "P" => {
+                    let written = args.split_once('=').and_then(|(reg, value)| {
+                        Some(write_register(cpu, parse_number(reg)? as usize, parse_register(value)?))
+                    });
+
+                    if written == Some(true) { "OK".to_string() } else { "E01".to_string() }
+                }
+                // Error 14 is EFAULT
+                "m" => Self::read_memory(cpu, args).unwrap_or_else(|| "E14".to_string()),
+                "M" => Self::write_memory(cpu, args).map_or_else(|| "E14".to_string(), |()| "OK".to_string()),
+                "c" | "s" => {
+                    if let Some(code) = self.run_until_stop(cpu, args, command == "s")? {
+                        return Ok(Some(code));
+                    }
+                    continue;
+                }
+                "Z" => self.breakpoint(cpu, args, true),
+                "z" => self.breakpoint(cpu, args, false),
+                // There's only one thread
+                "H" | "T" => "OK".to_string(),
+                "q" => self.query(args),
+                "D" => {
+                    self.send("OK")?;
+                    return Ok(None);
+                }
+                // No answer expected
+                "k" => return Ok(Some(0)),
+                _ => String::new()
+            };
+
+            self.send(&reply)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    // What GDB sends, and what the stub answers
+    struct Script {
+        input: Cursor<Vec<u8>>,
+        output: Rc<RefCell<Vec<u8>>>
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buffer)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.output.borrow_mut().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Script {
+        fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Runs the stub on those packets, each one acked, and returns the replies
+    fn session(cpu: &mut CPU, packets: &[&str]) -> (io::Result<Option<i32>>, Vec<String>) {
+        let input: String = packets.iter().map(|packet| {
+            let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+            format!("${}#{:02x}+", packet, checksum)
+        }).collect();
+
+        let output = Rc::new(RefCell::new(vec![]));
+        let script = Script { input: Cursor::new(input.into_bytes()), output: output.clone() };
+        let mut stub = GdbStub { connection: Box::new(script), breakpoints: BTreeSet::new(), hw_breakpoints: BTreeSet::new(), watchpoints: vec![] };
+
+        let result = stub.run(cpu);
+        let output = String::from_utf8(output.borrow().clone()).unwrap();
+        let replies = output.split('$').skip(1).map(|reply| reply.split('#').next().unwrap().to_string()).collect();
+
+        (result, replies)
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(parse_hex("00ff7A"), Some(vec![0x00, 0xFF, 0x7A]));
+        assert_eq!(parse_hex("abc"), None);
+        assert_eq!(parse_hex("zz"), None);
+        // Not on a character boundary
+        assert_eq!(parse_hex("aé"), None);
+
+        assert_eq!(parse_number("8000001c"), Some(0x8000001C));
+        assert_eq!(parse_number(""), None);
+        assert_eq!(parse_register("efcdab8967452301"), Some(0x0123456789ABCDEF));
+        assert_eq!(parse_register("efcdab89"), None);
+        assert_eq!(hex(&[0x12, 0xAB]), "12ab");
+    }
+
+    #[test]
+    fn registers() {
+        let mut cpu = CPU::new();
+        cpu.set_xreg(10, 0x1234);
+
+        let (result, replies) = session(&mut cpu, &[
+            "pa", "P1=0800000000000000", "p20", "p21", &format!("p{:x}", PRIV_REGNUM),
+            // Not a register, or a read-only CSR
+            "p2000", &format!("P{:x}=0100000000000000", FIRST_CSR_REGNUM + csr::MHARTID as usize),
+            "D"
+        ]);
+
+        assert!(matches!(result, Ok(None)));
+        assert_eq!(replies, vec![
+            "3412000000000000", "OK", "0010000000000000", "0000000000000000", "0300000000000000",
+            "E01", "E01", "OK"
+        ]);
+        assert_eq!(cpu.xreg(1), 8);
+    }
+
+    #[test]
+    fn memory() {
+        let mut cpu = CPU::new();
+
+        let (_, replies) = session(&mut cpu, &[
+            "M80000000,4:13000000", "m80000000,6",
+            // Wrong length, nothing there, and a device
+            "M80000000,2:00", "m0,4", "m10000000,1", "M10000000,1:41",
+            // The ROM can be read but not written
+            "m1000,4", "M1000,1:00",
+            "D"
+        ]);
+
+        assert_eq!(replies, vec!["OK", "130000000000", "E14", "E14", "E14", "E14", "00000000", "E14", "OK"]);
+    }
+
+    #[test]
+    fn big_reads() {
+        let mut cpu = CPU::new();
+
+        // Cut to what fits in a packet
+        let (_, replies) = session(&mut cpu, &["m80000000,100000", "D"]);
+        assert_eq!(replies[0].len(), PACKET_SIZE);
+    }
+
+    #[test]
+    fn running() {
+        let mut cpu = CPU::new();
+        // Two NOPs, then a jump back to the first one
+        cpu.write_memory(0x80000000, &[0x13, 0, 0, 0, 0x13, 0, 0, 0, 0x6F, 0xF0, 0x9F, 0xFF]);
+        cpu.set_pc(0x80000000);
+
+        let (_, replies) = session(&mut cpu, &["s", "Z0,80000008,4", "c", "z0,80000008,4", "Z1,80000004,4", "c", "D"]);
+        assert_eq!(replies, vec!["S05", "OK", "T05swbreak:;", "OK", "OK", "T05hwbreak:;", "OK"]);
+        assert_eq!(cpu.pc(), 0x80000004);
+
+        // The program powers the machine off
+        let mut cpu = CPU::new();
+        cpu.write_memory(0x80000000, &[0xB7, 0x02, 0x10, 0x00, 0x37, 0x53, 0x00, 0x00, 0x1B, 0x03, 0x53, 0x55, 0x23, 0xA0, 0x62, 0x00]);
+        cpu.set_pc(0x80000000);
+
+        let (result, replies) = session(&mut cpu, &["c"]);
+        assert!(matches!(result, Ok(Some(0))));
+        assert_eq!(replies, vec!["W00"]);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut cpu = CPU::new();
+        // sw zero, 0x100(t0) in a loop
+        cpu.write_memory(0x80000000, &[0x23, 0xA0, 0x02, 0x10, 0xE3, 0x0E, 0x00, 0xFE]);
+        cpu.set_xreg(5, 0x80000000);
+        cpu.set_pc(0x80000000);
+
+        let (_, replies) = session(&mut cpu, &["Z2,80000102,2", "Z3,80000100,4", "c", "z2,80000102,2", "s", "s", "D"]);
+        assert_eq!(replies, vec!["OK", "OK", "T05watch:80000102;", "OK", "S05", "S05", "OK"]);
+        assert_eq!(cpu.pc(), 0x80000004);
+
+        // Whichever way GDB leaves, its watchpoints go away with it
+        assert!(cpu.watchpoints().is_empty());
+        let (result, _) = session(&mut cpu, &["Z4,80000100,4"]);
+        assert!(result.is_err());
+        assert!(cpu.watchpoints().is_empty());
+    }
+
+    #[test]
+    fn queries() {
+        let mut cpu = CPU::new();
+
+        let (_, replies) = session(&mut cpu, &["qSupported:swbreak+", "qXfer:features:read:target.xml:0,10", "qAttached", "qXfer:features:read:target.xml:100000,10", "vMustReplyEmpty", "D"]);
+        assert_eq!(replies[0], format!("PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+", PACKET_SIZE));
+        assert_eq!(replies[1], "m<?xml version=\"1");
+        assert_eq!(replies[2], "1");
+        assert_eq!(replies[3], "l");
+        assert_eq!(replies[4], "");
+
+        // Every CSR GDB is told about can be read
+        let xml = target_xml();
+        for &(name, addr) in CSR_NAMES.iter() {
+            assert!(xml.contains(name));
+            assert!(read_register(&cpu, FIRST_CSR_REGNUM + addr as usize).is_some());
+        }
+    }
+
+    #[test]
+    fn connection_lost() {
+        let mut cpu = CPU::new();
+
+        let (result, _) = session(&mut cpu, &["g"]);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -1 +1,5 @@
 pub mod disasm;
+pub mod gdb;
+pub mod monitor;
+pub mod symbols;
+pub mod trace;
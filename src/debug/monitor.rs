@@ -0,0 +1,508 @@
+// Built-in monitor, to poke at the machine without GDB. It takes over the terminal when the
+// emulator starts with --monitor, on Ctrl-C, and at breakpoints and watchpoints.
+
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::bus::{Watchpoint, WatchKind};
+use crate::cpu::{csr, CPU};
+use crate::devices::terminal::{self, Terminal};
+use super::disasm;
+
+const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6"
+];
+
+// Most bytes x shows at once
+const MAX_DUMP: u64 = 0x10000;
+
+const HELP: &str = "\
+step [n]                 run n instructions (1 by default)
+continue [addr]          run until a breakpoint, or until addr
+break [addr]             add a breakpoint, or list them
+delete [addr]            remove a breakpoint, or all of them
+watch addr [len]         stop after a write to len bytes (8 by default) at addr, list without addr
+rwatch addr [len]        same for reads
+awatch addr [len]        same for reads and writes
+unwatch [addr]           remove the watchpoints at addr, or all of them
+regs                     integer registers
+fregs                    floating-point registers
+info                     instructions run, and how the TLBs do
+set reg value            change a register (pc, a0, x10...)
+x addr [len]             show len bytes (0x40 by default, 0x10000 at most) of memory
+write addr value [size]  write a value of size bytes (8 by default) to memory
+disasm [addr] [n]        disassemble n instructions (0xa by default) from addr (pc by default)
+quit [code]              exit the emulator with code (0 by default)
+Addresses, values, counts and sizes are hexadecimal, with or without 0x. Addresses can also be symbols like main+0x10,
+or registers like $sp. Memory addresses are virtual, watchpoints are on the physical memory they map to when they're set.";
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+enum Command {
+    // Back to the program
+    Resume,
+    Quit(i32),
+    Prompt
+}
+
+pub struct Monitor {
+    // Whether the next instruction waits for the prompt
+    stopped: bool,
+    breakpoints: BTreeSet<u64>,
+    // Instructions left before stopping, for step
+    steps: Option<u64>,
+    // Where continue stops, even without a breakpoint
+    until: Option<u64>
+}
+
+fn parse_number(number: &str) -> Option<u64> {
+    u64::from_str_radix(number.trim_start_matches("0x"), 16).ok()
+}
+
+fn register_number(name: &str) -> Option<u32> {
+    if let Some(number) = name.strip_prefix('x').and_then(|number| number.parse::<u32>().ok()) {
+        return if number < 32 { Some(number) } else { None };
+    }
+
+    match name {
+        "fp" => Some(8),
+        _ => REGISTER_NAMES.iter().position(|&reg| reg == name).map(|reg| reg as u32)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ")
+}
+
+impl Monitor {
+    pub fn new(stopped: bool) -> Self {
+        Self { stopped, breakpoints: BTreeSet::new(), steps: None, until: None }
+    }
+
+    // $reg, symbol, symbol+offset, or a number
+    fn parse_addr(cpu: &CPU, text: &str) -> Option<u64> {
+        if let Some(reg) = text.strip_prefix('$') {
+            return if reg == "pc" { Some(cpu.pc()) } else { register_number(reg).map(|reg| cpu.xreg(reg)) };
+        }
+
+        let (name, offset) = match text.split_once('+') {
+            Some((name, offset)) => (name, parse_number(offset)?),
+            None => (text, 0)
+        };
+
+        match cpu.symbols().find(name) {
+            Some(addr) => Some(addr.wrapping_add(offset)),
+            None if offset == 0 => parse_number(text),
+            None => None
+        }
+    }
+
+    // Next instruction, reads 2 bytes first in case it's a compressed one
+    fn instruction(cpu: &mut CPU, addr: u64) -> Option<u32> {
+        let mut low = [0; 2];
+        if !cpu.read_memory(addr, &mut low) {
+            return None;
+        }
+
+        let low = u16::from_le_bytes(low) as u32;
+        if low & 0x3 != 0x3 {
+            return Some(low);
+        }
+
+        let mut high = [0; 2];
+        if !cpu.read_memory(addr.wrapping_add(2), &mut high) {
+            return None;
+        }
+
+        Some((u16::from_le_bytes(high) as u32) << 16 | low)
+    }
+
+    // One line per instruction, returns the size of the instruction
+    fn show_instruction(cpu: &mut CPU, addr: u64) -> Option<u64> {
+        let instr = Self::instruction(cpu, addr);
+        let location = cpu.symbols().lookup(addr).map_or_else(String::new, |symbol| format!(" <{}>", symbol));
+
+        match instr {
+            Some(instr) if instr & 0x3 != 0x3 => {
+                println!("{:016x}{}:     {:04x} {}", addr, location, instr, disasm::disasm_general(instr, addr, cpu.symbols()));
+                Some(2)
+            }
+            Some(instr) => {
+                println!("{:016x}{}: {:08x} {}", addr, location, instr, disasm::disasm_general(instr, addr, cpu.symbols()));
+                Some(4)
+            }
+            None => {
+                println!("{:016x}{}: can't read memory", addr, location);
+                None
+            }
+        }
+    }
+
+    // Runs the machine until it powers off, and returns the exit code
+    pub fn run(&mut self, cpu: &mut CPU) -> i32 {
+        unsafe {
+            libc::signal(libc::SIGINT, on_sigint as *const () as libc::sighandler_t);
+        }
+
+        let mut terminal = None;
+
+        loop {
+            if self.stopped {
+                // Back to a normal terminal for the prompt
+                terminal = None;
+                Self::show_instruction(cpu, cpu.pc());
+
+                match self.prompt(cpu) {
+                    Some(code) => return code,
+                    None => self.stopped = false
+                }
+
+                INTERRUPTED.store(false, Ordering::Relaxed);
+            }
+
+            if terminal.is_none() {
+                terminal = Some(Terminal::raw());
+            }
+
+            if let Some(code) = cpu.step() {
+                return code;
+            }
+
+            self.stopped = self.check(cpu);
+        }
+    }
+
+    // Whether to stop before the next instruction
+    fn check(&mut self, cpu: &mut CPU) -> bool {
+        let mut stop = false;
+
+        if INTERRUPTED.swap(false, Ordering::Relaxed) {
+            println!("Interrupted");
+            stop = true;
+        }
+
+        if let Some(steps) = self.steps {
+            self.steps = if steps > 1 { Some(steps - 1) } else { None };
+            stop |= steps <= 1;
+        }
+
+        let pc = cpu.pc();
+        if self.until == Some(pc) {
+            self.until = None;
+            stop = true;
+        }
+
+        if self.breakpoints.contains(&pc) {
+            println!("Breakpoint at {:016x}", pc);
+            stop = true;
+        }
+
+        if let Some((pc, hit)) = cpu.take_watch_hit() {
+            let value = |value: u64| format!("{:0width$x}", value, width = 2 * hit.size as usize);
+
+            if hit.write {
+                let old = hit.old.map_or_else(|| "?".to_string(), value);
+                println!("Watchpoint: write of {} bytes at {:016x}, {} -> {}, by", hit.size, hit.addr, old, value(hit.new));
+            } else {
+                println!("Watchpoint: read of {} bytes at {:016x}, {}, by", hit.size, hit.addr, value(hit.new));
+            }
+
+            Self::show_instruction(cpu, pc);
+            stop = true;
+        }
+
+        // Whatever was in progress is over
+        if stop {
+            self.steps = None;
+            self.until = None;
+        }
+
+        stop
+    }
+
+    // Reads commands until one resumes the program. Returns the exit code if the emulator has to quit.
+    fn prompt(&mut self, cpu: &mut CPU) -> Option<i32> {
+        loop {
+            print!("(monitor) ");
+            std::io::stdout().flush().ok();
+
+            // Nothing more to read, like Ctrl-D
+            let line = match terminal::read_line() {
+                Some(line) => line,
+                None => return Some(0)
+            };
+
+            let args: Vec<&str> = line.split_whitespace().collect();
+            match self.command(cpu, &args) {
+                Ok(Command::Resume) => return None,
+                Ok(Command::Quit(code)) => return Some(code),
+                Ok(Command::Prompt) => {}
+                Err(error) => println!("{}", error)
+            }
+        }
+    }
+
+    fn command(&mut self, cpu: &mut CPU, args: &[&str]) -> Result<Command, String> {
+        let address = |cpu: &CPU, arg: Option<&&str>| -> Result<Option<u64>, String> {
+            match arg {
+                Some(arg) => Self::parse_addr(cpu, arg).map(Some).ok_or_else(|| format!("Unknown address {}", arg)),
+                None => Ok(None)
+            }
+        };
+        let number = |arg: Option<&&str>, default: u64| -> Result<u64, String> {
+            match arg {
+                Some(arg) => parse_number(arg).ok_or_else(|| format!("{} isn't a number", arg)),
+                None => Ok(default)
+            }
+        };
+
+        match args.first().copied().unwrap_or("") {
+            "" => {}
+            "s" | "step" => {
+                self.steps = Some(number(args.get(1), 1)?);
+                return Ok(Command::Resume);
+            }
+            "c" | "continue" => {
+                self.until = address(cpu, args.get(1))?;
+                return Ok(Command::Resume);
+            }
+            "b" | "break" => match address(cpu, args.get(1))? {
+                Some(addr) => { self.breakpoints.insert(addr); }
+                None => for &breakpoint in &self.breakpoints {
+                    Self::show_instruction(cpu, breakpoint);
+                }
+            },
+            "d" | "delete" => match address(cpu, args.get(1))? {
+                Some(addr) if !self.breakpoints.remove(&addr) => return Err(format!("No breakpoint at {:016x}", addr)),
+                Some(_) => {}
+                None => self.breakpoints.clear()
+            },
+            "w" | "watch" | "rwatch" | "awatch" => match address(cpu, args.get(1))? {
+                Some(addr) => {
+                    let kind = match args[0] {
+                        "rwatch" => WatchKind::Read,
+                        "awatch" => WatchKind::Access,
+                        _ => WatchKind::Write
+                    };
+                    let paddr = cpu.physical_addr(addr).ok_or_else(|| format!("{:016x} isn't mapped", addr))?;
+                    let size = number(args.get(2), 8)?;
+                    if size == 0 {
+                        return Err("A watchpoint is at least 1 byte".to_string());
+                    }
+
+                    cpu.add_watchpoint(Watchpoint { addr: paddr, size, kind });
+                }
+                None => for watchpoint in cpu.watchpoints() {
+                    println!("{:016x}..{:016x} {:?}", watchpoint.addr, watchpoint.addr.saturating_add(watchpoint.size), watchpoint.kind);
+                }
+            },
+            "unwatch" => {
+                let paddr = match address(cpu, args.get(1))? {
+                    Some(addr) => Some(cpu.physical_addr(addr).ok_or_else(|| format!("{:016x} isn't mapped", addr))?),
+                    None => None
+                };
+                let watchpoints: Vec<Watchpoint> = cpu.watchpoints().iter().copied()
+                    .filter(|watchpoint| paddr.is_none_or(|paddr| watchpoint.addr == paddr))
+                    .collect();
+
+                for watchpoint in watchpoints {
+                    cpu.remove_watchpoint(watchpoint);
+                }
+            }
+            "r" | "regs" => println!("pc={:016x} privilege={}\n{}", cpu.pc(), cpu.privilege(), cpu.format_xregs()),
+            "f" | "fregs" => println!("{}", cpu.format_fregs()),
+            "i" | "info" => {
+                println!("pc={:016x} privilege={} cycles={} instructions={}", cpu.pc(), cpu.privilege(),
+                    cpu.read_csr(csr::MCYCLE).unwrap_or(0), cpu.read_csr(csr::MINSTRET).unwrap_or(0));
+
+                for (name, (hits, misses)) in ["iTLB", "dTLB"].iter().zip(cpu.tlb_stats().iter()) {
+                    let total = hits + misses;
+                    let rate = if total == 0 { 0.0 } else { *hits as f64 * 100.0 / total as f64 };
+                    println!("{}: hits={} misses={} ({:.2}% hit rate)", name, hits, misses, rate);
+                }
+            }
+            "set" => {
+                let (reg, value) = match args {
+                    [_, reg, value] => (reg.trim_start_matches('$'), address(cpu, Some(value))?.unwrap_or(0)),
+                    _ => return Err("set reg value".to_string())
+                };
+
+                match reg {
+                    "pc" => cpu.set_pc(value),
+                    _ => cpu.set_xreg(register_number(reg).ok_or_else(|| format!("Unknown register {}", reg))?, value)
+                }
+            }
+            "x" => {
+                let addr = address(cpu, args.get(1))?.ok_or("x addr [len]")?;
+                let len = number(args.get(2), 64)?;
+                if len > MAX_DUMP {
+                    return Err(format!("x shows at most {:#x} bytes", MAX_DUMP));
+                }
+
+                let mut data = vec![0; len as usize];
+                if !cpu.read_memory(addr, &mut data) {
+                    return Err(format!("Can't read memory at {:016x}", addr));
+                }
+
+                for (i, line) in data.chunks(16).enumerate() {
+                    println!("{:016x}: {}", addr.wrapping_add(16 * i as u64), hex(line));
+                }
+            }
+            "write" => {
+                let addr = address(cpu, args.get(1))?.ok_or("write addr value [size]")?;
+                let value = address(cpu, args.get(2))?.ok_or("write addr value [size]")?;
+                let size = number(args.get(3), 8)? as usize;
+                if !(1..=8).contains(&size) {
+                    return Err("The size is at most 8 bytes".to_string());
+                }
+
+                if !cpu.write_memory(addr, &value.to_le_bytes()[..size]) {
+                    return Err(format!("Can't write memory at {:016x}", addr));
+                }
+            }
+            "disasm" => {
+                let mut addr = address(cpu, args.get(1))?.unwrap_or(cpu.pc());
+
+                for _ in 0..number(args.get(2), 10)? {
+                    match Self::show_instruction(cpu, addr) {
+                        Some(size) => addr = addr.wrapping_add(size),
+                        None => break
+                    }
+                }
+            }
+            "h" | "help" => println!("{}", HELP),
+            "q" | "quit" => return Ok(Command::Quit(number(args.get(1), 0)? as i32)),
+            command => return Err(format!("Unknown command {}, try help", command))
+        }
+
+        Ok(Command::Prompt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::dram::DRAM_BASE;
+
+    fn cpu() -> CPU {
+        let mut cpu = CPU::new();
+        cpu.set_pc(DRAM_BASE);
+        cpu
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(parse_number("0x10"), Some(0x10));
+        assert_eq!(parse_number("ff"), Some(0xFF));
+        assert_eq!(parse_number("10k"), None);
+
+        assert_eq!(register_number("x31"), Some(31));
+        assert_eq!(register_number("x32"), None);
+        assert_eq!(register_number("fp"), Some(8));
+        assert_eq!(register_number("a0"), Some(10));
+        assert_eq!(register_number("pc"), None);
+
+        let mut cpu = cpu();
+        cpu.set_xreg(2, 0x1234);
+        assert_eq!(Monitor::parse_addr(&cpu, "$sp"), Some(0x1234));
+        assert_eq!(Monitor::parse_addr(&cpu, "$pc"), Some(DRAM_BASE));
+        assert_eq!(Monitor::parse_addr(&cpu, "0x80000010"), Some(0x80000010));
+        assert_eq!(Monitor::parse_addr(&cpu, "main+0x10"), None);
+    }
+
+    #[test]
+    fn commands() {
+        let mut cpu = cpu();
+        let mut monitor = Monitor::new(true);
+
+        // Counts are hexadecimal too
+        assert!(matches!(monitor.command(&mut cpu, &["step", "10"]), Ok(Command::Resume)));
+        assert_eq!(monitor.steps, Some(0x10));
+        assert!(matches!(monitor.command(&mut cpu, &["quit", "0x2a"]), Ok(Command::Quit(0x2A))));
+        assert!(monitor.command(&mut cpu, &["step", "ten"]).is_err());
+
+        assert!(matches!(monitor.command(&mut cpu, &["set", "a0", "0x42"]), Ok(Command::Prompt)));
+        assert_eq!(cpu.xreg(10), 0x42);
+        assert!(monitor.command(&mut cpu, &["set", "y0", "1"]).is_err());
+
+        assert!(monitor.command(&mut cpu, &["write", "80000100", "abcd", "2"]).is_ok());
+        let mut data = [0; 4];
+        assert!(cpu.read_memory(0x80000100, &mut data));
+        assert_eq!(data, [0xCD, 0xAB, 0, 0]);
+        assert!(monitor.command(&mut cpu, &["write", "80000100", "0", "9"]).is_err());
+
+        // Too much, and not memory
+        assert!(monitor.command(&mut cpu, &["x", "80000000", "10000"]).is_ok());
+        assert!(monitor.command(&mut cpu, &["x", "80000000", "10001"]).is_err());
+        assert!(monitor.command(&mut cpu, &["watch", "80000000", "0"]).is_err());
+        assert!(monitor.command(&mut cpu, &["x", "10000000"]).is_err());
+
+        assert!(matches!(monitor.command(&mut cpu, &["info"]), Ok(Command::Prompt)));
+        assert!(monitor.command(&mut cpu, &["frobnicate"]).is_err());
+    }
+
+    #[test]
+    fn stopping() {
+        let mut cpu = cpu();
+        let mut monitor = Monitor::new(false);
+
+        monitor.command(&mut cpu, &["break", "80000008"]).unwrap();
+        monitor.command(&mut cpu, &["continue", "80000004"]).unwrap();
+
+        assert!(!monitor.check(&mut cpu));
+        cpu.set_pc(0x80000004);
+        assert!(monitor.check(&mut cpu));
+        // continue only stops there once
+        assert!(!monitor.check(&mut cpu));
+
+        cpu.set_pc(0x80000008);
+        assert!(monitor.check(&mut cpu));
+        monitor.command(&mut cpu, &["delete", "80000008"]).unwrap();
+        assert!(!monitor.check(&mut cpu));
+        assert!(monitor.command(&mut cpu, &["delete", "80000008"]).is_err());
+
+        // Stepping is over after n instructions
+        monitor.command(&mut cpu, &["step", "2"]).unwrap();
+        assert!(!monitor.check(&mut cpu));
+        assert!(monitor.check(&mut cpu));
+        assert_eq!(monitor.steps, None);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut cpu = cpu();
+        let mut monitor = Monitor::new(false);
+
+        // sb zero, 0x102(t0) then lb t1, 0x100(t0), twice
+        let program = [0x10028123u32, 0x10028303, 0x10028123, 0x10028303];
+        let bytes: Vec<u8> = program.iter().flat_map(|instr| instr.to_le_bytes()).collect();
+        assert!(cpu.write_memory(DRAM_BASE, &bytes));
+        cpu.set_xreg(5, DRAM_BASE);
+
+        monitor.command(&mut cpu, &["watch", "80000100", "4"]).unwrap();
+        monitor.command(&mut cpu, &["rwatch", "80000100", "1"]).unwrap();
+        assert_eq!(cpu.watchpoints().len(), 2);
+
+        // Stops after the access
+        assert_eq!(cpu.step(), None);
+        assert!(monitor.check(&mut cpu));
+        assert_eq!(cpu.step(), None);
+        assert!(monitor.check(&mut cpu));
+
+        monitor.command(&mut cpu, &["unwatch", "80000100"]).unwrap();
+        assert!(cpu.watchpoints().is_empty());
+        assert_eq!(cpu.step(), None);
+        assert!(!monitor.check(&mut cpu));
+
+        // Listing one that goes past the end of the address space
+        monitor.command(&mut cpu, &["awatch", "ffffffffffffff00", "1000"]).unwrap();
+        assert!(matches!(monitor.command(&mut cpu, &["watch"]), Ok(Command::Prompt)));
+        monitor.command(&mut cpu, &["unwatch"]).unwrap();
+        assert!(cpu.watchpoints().is_empty());
+    }
+}
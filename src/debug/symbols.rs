@@ -0,0 +1,115 @@
+// Address to symbol map, so traces can say main+0x1c instead of 800001a4
+
+use std::collections::BTreeMap;
+use crate::elf::Elf;
+
+struct Symbol {
+    name: String,
+    // 0 for labels in assembly code, those cover everything up to the next symbol
+    size: u64
+}
+
+#[derive(Default)]
+pub struct Symbols {
+    symbols: BTreeMap<u64, Symbol>
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Several files can be loaded, like a firmware and a kernel
+    pub fn add_elf(&mut self, elf: &Elf) {
+        let symbols = &mut self.symbols;
+
+        for symbol in &elf.symbols {
+            // Several symbols at the same address: keep the one with a size, it's a function
+            // or a variable rather than a label
+            let replace = symbols.get(&symbol.value).is_none_or(|known: &Symbol| known.size == 0 && symbol.size != 0);
+
+            if replace {
+                symbols.insert(symbol.value, Symbol { name: symbol.name.clone(), size: symbol.size });
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    // Address of a symbol, for the monitor's commands
+    pub fn find(&self, name: &str) -> Option<u64> {
+        self.symbols.iter().find(|(_, symbol)| symbol.name == name).map(|(&addr, _)| addr)
+    }
+
+    // Closest symbol at or below the address, as name+0xoffset
+    pub fn lookup(&self, addr: u64) -> Option<String> {
+        let (start, symbol) = self.symbols.range(..=addr).next_back()?;
+        let offset = addr - start;
+
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+
+        if offset == 0 {
+            Some(symbol.name.clone())
+        } else {
+            Some(format!("{}+0x{:x}", symbol.name, offset))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf;
+
+    fn table(list: &[(&str, u64, u64)]) -> Symbols {
+        let symbols = list.iter()
+            .map(|&(name, value, size)| elf::Symbol { name: name.to_string(), value, size })
+            .collect();
+
+        let mut table = Symbols::new();
+        table.add_elf(&Elf { entry: 0, segments: vec![], symbols });
+        table
+    }
+
+    #[test]
+    fn lookup() {
+        let symbols = table(&[("main", 0x1000, 0x20), ("start", 0x2000, 0), ("table", 0x3000, 8)]);
+
+        assert_eq!(symbols.lookup(0x1000).as_deref(), Some("main"));
+        assert_eq!(symbols.lookup(0x101c).as_deref(), Some("main+0x1c"));
+        // Past the end of a sized symbol, or before the first one
+        assert_eq!(symbols.lookup(0x1020), None);
+        assert_eq!(symbols.lookup(0xFFF), None);
+        // Labels go up to the next symbol
+        assert_eq!(symbols.lookup(0x2FFF).as_deref(), Some("start+0xfff"));
+        assert_eq!(symbols.lookup(0x3004).as_deref(), Some("table+0x4"));
+        assert!(Symbols::new().is_empty());
+    }
+
+    #[test]
+    fn same_address() {
+        // The label doesn't replace the function, in whatever order they come
+        let symbols = table(&[("entry", 0x1000, 0), ("main", 0x1000, 0x10), ("other", 0x1000, 0)]);
+        assert_eq!(symbols.lookup(0x1004).as_deref(), Some("main+0x4"));
+
+        let symbols = table(&[("first", 0x1000, 0), ("second", 0x1000, 0)]);
+        assert_eq!(symbols.lookup(0x1000).as_deref(), Some("first"));
+    }
+
+    #[test]
+    fn several_files() {
+        let mut symbols = table(&[("fw_start", 0x80000000, 0), ("kernel", 0x80200000, 0)]);
+        symbols.add_elf(&Elf {
+            entry: 0,
+            segments: vec![],
+            symbols: vec![elf::Symbol { name: "start_kernel".to_string(), value: 0x80200000, size: 0x100 }]
+        });
+
+        assert_eq!(symbols.lookup(0x80000010).as_deref(), Some("fw_start+0x10"));
+        assert_eq!(symbols.lookup(0x80200010).as_deref(), Some("start_kernel+0x10"));
+    }
+}
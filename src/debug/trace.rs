@@ -0,0 +1,354 @@
+// Execution trace. Off by default, it's a lot of output: each level shows what the one
+// before does, plus some more.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use super::disasm::{self, get_freg_name, get_reg_name};
+use super::symbols::Symbols;
+use crate::trap::Exception;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TraceLevel {
+    Off,
+    // Every instruction run, and the exceptions they raise
+    Instructions,
+    // The registers they write
+    Registers,
+    // The loads and stores they do
+    Memory
+}
+
+impl TraceLevel {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(TraceLevel::Off),
+            "instructions" => Some(TraceLevel::Instructions),
+            "registers" => Some(TraceLevel::Registers),
+            "memory" => Some(TraceLevel::Memory),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    // For people
+    Text,
+    // Same as spike --log-commits, to compare runs with it. Only the instructions that
+    // complete are there, with the registers they write and the memory they access.
+    Spike
+}
+
+impl TraceFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(TraceFormat::Text),
+            "spike" => Some(TraceFormat::Spike),
+            _ => None
+        }
+    }
+}
+
+// What an instruction did, shown after it
+enum Record {
+    XReg(u32, u64),
+    FReg(u32, u64),
+    Load { addr: u64, size: u64, value: u64 },
+    Store { addr: u64, size: u64, value: u64 }
+}
+
+pub struct Tracer {
+    level: TraceLevel,
+    format: TraceFormat,
+    // Only the instructions in [start, end) are traced
+    range: Option<(u64, u64)>,
+    output: Box<dyn Write>,
+    // Whether the instruction being run is traced, and the privilege mode it runs in
+    active: bool,
+    privilege: u64,
+    records: Vec<Record>
+}
+
+impl Tracer {
+    // The Spike format always has everything in it, the level only turns it on or off
+    pub fn new(level: TraceLevel, format: TraceFormat, range: Option<(u64, u64)>, file: Option<&str>) -> io::Result<Self> {
+        let output: Box<dyn Write> = match file {
+            Some(file) => Box::new(BufWriter::new(File::create(file)?)),
+            // Not buffered more than by lines, it goes along with what the UART prints
+            None => Box::new(io::stdout())
+        };
+
+        let level = if format == TraceFormat::Spike && level > TraceLevel::Off { TraceLevel::Memory } else { level };
+
+        Ok(Self { level, format, range, output, active: false, privilege: 0, records: vec![] })
+    }
+
+    pub fn off() -> Self {
+        Self {
+            level: TraceLevel::Off,
+            format: TraceFormat::Text,
+            range: None,
+            output: Box::new(io::sink()),
+            active: false,
+            privilege: 0,
+            records: vec![]
+        }
+    }
+
+    // Called before each instruction, with its address
+    pub fn begin(&mut self, pc: u64, privilege: u64) {
+        self.active = self.level > TraceLevel::Off &&
+            self.range.is_none_or(|(start, end)| pc >= start && pc < end);
+        self.privilege = privilege;
+        self.records.clear();
+    }
+
+    fn wants_registers(&self) -> bool {
+        self.active && self.level >= TraceLevel::Registers
+    }
+
+    pub fn xreg(&mut self, reg: u32, value: u64) {
+        if self.wants_registers() {
+            self.records.push(Record::XReg(reg, value));
+        }
+    }
+
+    pub fn freg(&mut self, reg: u32, value: u64) {
+        if self.wants_registers() {
+            self.records.push(Record::FReg(reg, value));
+        }
+    }
+
+    // Loads and stores are on the virtual addresses the program uses
+    pub fn access(&mut self, addr: u64, size: u64, value: u64, store: bool) {
+        if self.active && self.level >= TraceLevel::Memory {
+            self.records.push(if store {
+                Record::Store { addr, size, value }
+            } else {
+                Record::Load { addr, size, value }
+            });
+        }
+    }
+
+    // Called once the instruction is done. There's no instruction if fetching it failed.
+    pub fn end(&mut self, pc: u64, instr: Option<u32>, exception: Option<Exception>, symbols: &Symbols) {
+        if !self.active {
+            return;
+        }
+
+        let result = match self.format {
+            TraceFormat::Text => self.write_text(pc, instr, exception, symbols),
+            TraceFormat::Spike => self.write_spike(pc, instr, exception)
+        };
+
+        if let Err(error) = result {
+            eprintln!("Can't write the trace, it's turned off: {}", error);
+            self.level = TraceLevel::Off;
+        }
+
+        self.active = false;
+    }
+
+    // Taking an interrupt isn't an instruction, but it changes where the program goes
+    pub fn interrupt(&mut self, pc: u64, privilege: u64, cause: u64) {
+        self.begin(pc, privilege);
+
+        // Spike doesn't log them
+        if self.active && self.format == TraceFormat::Text && writeln!(self.output, "{:016x}: interrupt {}", pc, cause).is_err() {
+            self.level = TraceLevel::Off;
+        }
+
+        self.active = false;
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    fn write_text(&mut self, pc: u64, instr: Option<u32>, exception: Option<Exception>, symbols: &Symbols) -> io::Result<()> {
+        let location = symbols.lookup(pc).map_or_else(String::new, |symbol| format!(" <{}>", symbol));
+
+        // Same as in the monitor
+        match instr {
+            Some(instr) if instr & 0x3 != 0x3 =>
+                writeln!(self.output, "{:016x}{}:     {:04x} {}", pc, location, instr, disasm::disasm_general(instr, pc, symbols))?,
+            Some(instr) =>
+                writeln!(self.output, "{:016x}{}: {:08x} {}", pc, location, instr, disasm::disasm_general(instr, pc, symbols))?,
+            None => writeln!(self.output, "{:016x}{}: can't fetch", pc, location)?
+        }
+
+        for record in &self.records {
+            match *record {
+                Record::XReg(reg, value) => writeln!(self.output, "    {} <- {:016x}", get_reg_name(reg), value)?,
+                Record::FReg(reg, value) => writeln!(self.output, "    {} <- {:016x}", get_freg_name(reg), value)?,
+                Record::Load { addr, size, value } =>
+                    writeln!(self.output, "    load {} bytes at {:016x}: {:0width$x}", size, addr, value, width = 2 * size as usize)?,
+                Record::Store { addr, size, value } =>
+                    writeln!(self.output, "    store {} bytes at {:016x}: {:0width$x}", size, addr, value, width = 2 * size as usize)?
+            }
+        }
+
+        if let Some(exception) = exception {
+            writeln!(self.output, "    exception {:?}", exception)?;
+        }
+
+        Ok(())
+    }
+
+    // core   0: 3 0x0000000080000004 (0x0062a023) x6  0x0000000000000005 mem 0x0000000080001000 0x00000005
+    // Registers come first, then the addresses loaded from, then the stores.
+    fn write_spike(&mut self, pc: u64, instr: Option<u32>, exception: Option<Exception>) -> io::Result<()> {
+        let instr = match (instr, exception) {
+            (Some(instr), None) => instr,
+            _ => return Ok(())
+        };
+
+        write!(self.output, "core   0: {} 0x{:016x} ", self.privilege, pc)?;
+        if instr & 0x3 != 0x3 {
+            write!(self.output, "(0x{:04x})", instr)?;
+        } else {
+            write!(self.output, "(0x{:08x})", instr)?;
+        }
+
+        for record in &self.records {
+            match *record {
+                Record::XReg(reg, value) => write!(self.output, " x{:<2} 0x{:016x}", reg, value)?,
+                Record::FReg(reg, value) => write!(self.output, " f{:<2} 0x{:016x}", reg, value)?,
+                _ => ()
+            }
+        }
+
+        for record in &self.records {
+            if let Record::Load { addr, .. } = *record {
+                write!(self.output, " mem 0x{:016x}", addr)?;
+            }
+        }
+
+        for record in &self.records {
+            if let Record::Store { addr, size, value } = *record {
+                write!(self.output, " mem 0x{:016x} 0x{:0width$x}", addr, value, width = 2 * size as usize)?;
+            }
+        }
+
+        writeln!(self.output)
+    }
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        Self::off()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Keeps what's traced for the test to look at
+    #[derive(Clone)]
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn capturing(level: TraceLevel, format: TraceFormat, range: Option<(u64, u64)>) -> (Tracer, Capture) {
+        let capture = Capture(Rc::new(RefCell::new(vec![])));
+        let tracer = Tracer { level, format, range, output: Box::new(capture.clone()), active: false, privilege: 0, records: vec![] };
+
+        (tracer, capture)
+    }
+
+    // One instruction that writes a0 and stores it
+    fn trace(tracer: &mut Tracer, capture: &Capture, pc: u64) -> String {
+        capture.0.borrow_mut().clear();
+
+        tracer.begin(pc, 3);
+        tracer.xreg(10, 0x2A);
+        tracer.access(0x80001000, 4, 0x2A, true);
+        tracer.end(pc, Some(0x00A52023), None, &Symbols::new());
+
+        String::from_utf8(capture.0.borrow().clone()).unwrap()
+    }
+
+    #[test]
+    fn levels() {
+        assert_eq!(TraceLevel::parse("registers"), Some(TraceLevel::Registers));
+        assert_eq!(TraceLevel::parse("all"), None);
+
+        let (mut tracer, capture) = capturing(TraceLevel::Off, TraceFormat::Text, None);
+        assert_eq!(trace(&mut tracer, &capture, 0x80000000), "");
+
+        let (mut tracer, capture) = capturing(TraceLevel::Instructions, TraceFormat::Text, None);
+        assert_eq!(trace(&mut tracer, &capture, 0x80000000), "0000000080000000: 00a52023 sw a0, 0(a0)\n");
+
+        let (mut tracer, capture) = capturing(TraceLevel::Registers, TraceFormat::Text, None);
+        assert_eq!(trace(&mut tracer, &capture, 0x80000000).lines().nth(1), Some("    a0 <- 000000000000002a"));
+
+        let (mut tracer, capture) = capturing(TraceLevel::Memory, TraceFormat::Text, None);
+        assert_eq!(trace(&mut tracer, &capture, 0x80000000).lines().nth(2), Some("    store 4 bytes at 0000000080001000: 0000002a"));
+    }
+
+    #[test]
+    fn range() {
+        let (mut tracer, capture) = capturing(TraceLevel::Memory, TraceFormat::Text, Some((0x80000004, 0x80000008)));
+
+        assert_eq!(trace(&mut tracer, &capture, 0x80000000), "");
+        assert_eq!(trace(&mut tracer, &capture, 0x80000004).lines().count(), 3);
+        assert_eq!(trace(&mut tracer, &capture, 0x80000008), "");
+
+        // Interrupts are traced where they're taken
+        tracer.interrupt(0x80000004, 3, 7);
+        tracer.interrupt(0x80000010, 3, 7);
+        assert_eq!(String::from_utf8(capture.0.borrow().clone()).unwrap(), "0000000080000004: interrupt 7\n");
+    }
+
+    #[test]
+    fn exceptions() {
+        let (mut tracer, capture) = capturing(TraceLevel::Instructions, TraceFormat::Text, None);
+
+        tracer.begin(0x80000000, 3);
+        tracer.end(0x80000000, None, Some(Exception::InstructionAccessFault(0x80000000)), &Symbols::new());
+        tracer.begin(0x80000004, 3);
+        tracer.end(0x80000004, Some(0x0001), None, &Symbols::new());
+
+        let output = String::from_utf8(capture.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "0000000080000000: can't fetch");
+        assert_eq!(lines[1], "    exception InstructionAccessFault(2147483648)");
+        assert!(lines[2].starts_with("0000000080000004:     0001 "));
+    }
+
+    #[test]
+    fn spike_format() {
+        let (mut tracer, capture) = capturing(TraceLevel::Memory, TraceFormat::Spike, None);
+
+        // Stores come after loads whatever the order they were done in
+        tracer.begin(0x80000004, 1);
+        tracer.access(0x80001000, 4, 5, true);
+        tracer.access(0x80002000, 8, 6, false);
+        tracer.xreg(6, 5);
+        tracer.end(0x80000004, Some(0x0062A023), None, &Symbols::new());
+
+        // Neither what raised an exception, nor interrupts
+        tracer.begin(0x80000008, 1);
+        tracer.end(0x80000008, Some(0x00000073), Some(Exception::EnvironmentCallFromSMode), &Symbols::new());
+        tracer.interrupt(0x8000000C, 1, 5);
+        tracer.begin(0x8000000C, 0);
+        tracer.end(0x8000000C, Some(0x4501), None, &Symbols::new());
+
+        assert_eq!(String::from_utf8(capture.0.borrow().clone()).unwrap(), "\
+core   0: 1 0x0000000080000004 (0x0062a023) x6  0x0000000000000005 mem 0x0000000080002000 mem 0x0000000080001000 0x00000005
+core   0: 0 0x000000008000000c (0x4501)
+");
+    }
+}
@@ -0,0 +1,163 @@
+// Core-local interruptor, with the SiFive layout QEMU's virt machine uses. There's a single
+// hart, so a single msip and mtimecmp.
+
+use super::Device;
+use crate::cpu::csr::{MIP_MSIP, MIP_MTIP};
+use crate::fdt::{self, Fdt};
+
+pub const CLINT_BASE: u64 = 0x2000000;
+pub const CLINT_SIZE: u64 = 0x10000;
+
+const MSIP: u64 = 0x0;
+const MTIMECMP: u64 = 0x4000;
+const MTIME: u64 = 0xBFF8;
+
+pub struct Clint {
+    msip: u32,
+    mtimecmp: u64,
+    // Ticks once per instruction
+    mtime: u64
+}
+
+impl Clint {
+    pub fn new() -> Self {
+        Self {
+            msip: 0,
+            mtimecmp: u64::MAX,
+            mtime: 0
+        }
+    }
+}
+
+// Replaces the bytes [shift / 8, shift / 8 + size) of a register, for 32-bit accesses to 64-bit ones
+fn insert(reg: u64, shift: u64, size: u64, value: u64) -> u64 {
+    let mask = if size == 8 { u64::MAX } else { ((1 << (8 * size)) - 1) << shift };
+
+    (reg & !mask) | ((value << shift) & mask)
+}
+
+impl Device for Clint {
+    fn name(&self) -> &'static str {
+        "clint"
+    }
+
+    fn read(&mut self, offset: u64, size: u64) -> Option<u64> {
+        match (offset, size) {
+            (MSIP, 4) => Some(self.msip as u64),
+            (MTIMECMP, 8) => Some(self.mtimecmp),
+            (MTIMECMP, 4) | (0x4004, 4) => Some((self.mtimecmp >> (8 * (offset - MTIMECMP))) & 0xFFFFFFFF),
+            (MTIME, 8) => Some(self.mtime),
+            (MTIME, 4) | (0xBFFC, 4) => Some((self.mtime >> (8 * (offset - MTIME))) & 0xFFFFFFFF),
+            _ => None
+        }
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> bool {
+        match (offset, size) {
+            // Only the low bit of msip is writable
+            (MSIP, 4) => self.msip = (value & 1) as u32,
+            (MTIMECMP, 8) | (MTIMECMP, 4) | (0x4004, 4) => {
+                self.mtimecmp = insert(self.mtimecmp, 8 * (offset - MTIMECMP), size, value)
+            }
+            (MTIME, 8) | (MTIME, 4) | (0xBFFC, 4) => {
+                self.mtime = insert(self.mtime, 8 * (offset - MTIME), size, value)
+            }
+            _ => return false
+        }
+
+        true
+    }
+
+    fn tick(&mut self) {
+        self.mtime = self.mtime.wrapping_add(1);
+    }
+
+    fn local_interrupts(&self) -> u64 {
+        let msip = if self.msip & 1 != 0 { MIP_MSIP } else { 0 };
+        let mtip = if self.mtime >= self.mtimecmp { MIP_MTIP } else { 0 };
+
+        msip | mtip
+    }
+
+    fn mtime(&self) -> Option<u64> {
+        Some(self.mtime)
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn device_tree(&self, fdt: &mut Fdt, base: u64, size: u64, _irq: Option<u32>) {
+        fdt.begin_node(&format!("clint@{:x}", base));
+        fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+        fdt.property_reg(base, size);
+        // Interrupt numbers are the bits in mip
+        fdt.property_cells("interrupts-extended", &[
+            fdt::CPU_INTC_PHANDLE, MIP_MSIP.trailing_zeros(),
+            fdt::CPU_INTC_PHANDLE, MIP_MTIP.trailing_zeros()
+        ]);
+        fdt.end_node();
+    }
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timer() {
+        let mut clint = Clint::new();
+        assert_eq!(clint.local_interrupts(), 0);
+
+        assert!(clint.write(MTIMECMP, 8, 2));
+        clint.tick();
+        assert_eq!(clint.local_interrupts(), 0);
+        clint.tick();
+        assert_eq!(clint.local_interrupts(), MIP_MTIP);
+        assert_eq!(clint.mtime(), Some(2));
+
+        // Stays pending until mtimecmp moves
+        clint.tick();
+        assert_eq!(clint.local_interrupts(), MIP_MTIP);
+        assert!(clint.write(MTIMECMP + 4, 4, 1));
+        assert_eq!(clint.read(MTIMECMP, 8), Some((1 << 32) | 2));
+        assert_eq!(clint.local_interrupts(), 0);
+    }
+
+    #[test]
+    fn halves() {
+        let mut clint = Clint::new();
+
+        // 32-bit accesses only change their half
+        assert!(clint.write(MTIME, 8, 0x1111_2222_3333_4444));
+        assert!(clint.write(MTIME + 4, 4, 0xAAAA_BBBB));
+        assert_eq!(clint.read(MTIME, 4), Some(0x3333_4444));
+        assert_eq!(clint.read(MTIME + 4, 4), Some(0xAAAA_BBBB));
+        assert!(clint.write(MTIMECMP, 4, 0x5));
+        assert_eq!(clint.read(MTIMECMP, 8), Some(0xFFFF_FFFF_0000_0005));
+
+        // Anything else is an access fault
+        assert_eq!(clint.read(MTIME, 2), None);
+        assert_eq!(clint.read(MTIME + 2, 4), None);
+        assert!(!clint.write(0x8, 4, 0));
+    }
+
+    #[test]
+    fn software_interrupts() {
+        let mut clint = Clint::new();
+
+        assert!(clint.write(MSIP, 4, 0xFFFF_FFFF));
+        assert_eq!(clint.read(MSIP, 4), Some(1));
+        assert_eq!(clint.local_interrupts(), MIP_MSIP);
+
+        clint.reset();
+        assert_eq!(clint.local_interrupts(), 0);
+        assert_eq!(clint.mtime(), Some(0));
+    }
+}
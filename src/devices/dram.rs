@@ -0,0 +1,146 @@
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::rc::Rc;
+use super::Device;
+
+pub const DRAM_BASE: u64 = 0x80000000;
+
+// Guest RAM, shared between the DRAM device on the bus and the devices doing DMA.
+// Addresses are physical addresses.
+#[derive(Clone)]
+pub struct Memory {
+    data: Rc<RefCell<Vec<u8>>>
+}
+
+impl Memory {
+    pub fn new(size: usize) -> Self {
+        Self { data: Rc::new(RefCell::new(vec![0; size])) }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.data.borrow().len() as u64
+    }
+
+    // Offset in the buffer of [addr, addr + len), None if it isn't all in DRAM
+    fn range(&self, addr: u64, len: usize) -> Option<std::ops::Range<usize>> {
+        let start = addr.checked_sub(DRAM_BASE)? as usize;
+        let end = start.checked_add(len)?;
+
+        if end <= self.data.borrow().len() { Some(start..end) } else { None }
+    }
+
+    pub fn read(&self, addr: u64, buffer: &mut [u8]) -> bool {
+        match self.range(addr, buffer.len()) {
+            Some(range) => { buffer.copy_from_slice(&self.data.borrow()[range]); true }
+            None => false
+        }
+    }
+
+    pub fn write(&self, addr: u64, buffer: &[u8]) -> bool {
+        match self.range(addr, buffer.len()) {
+            Some(range) => { self.data.borrow_mut()[range].copy_from_slice(buffer); true }
+            None => false
+        }
+    }
+}
+
+pub struct Dram {
+    memory: Memory
+}
+
+impl Dram {
+    pub fn new(memory: Memory) -> Self {
+        Self { memory }
+    }
+}
+
+impl Device for Dram {
+    fn name(&self) -> &'static str {
+        "dram"
+    }
+
+    // The bus already checked that the access is in bounds
+    fn read(&mut self, offset: u64, size: u64) -> Option<u64> {
+        let data = self.memory.data.borrow();
+        let bytes = &data[offset as usize..(offset + size) as usize];
+
+        match size {
+            1 => Some(bytes[0] as u64),
+            2 => Some(u16::from_le_bytes(bytes.try_into().ok()?) as u64),
+            4 => Some(u32::from_le_bytes(bytes.try_into().ok()?) as u64),
+            8 => Some(u64::from_le_bytes(bytes.try_into().ok()?)),
+            _ => None
+        }
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> bool {
+        let mut data = self.memory.data.borrow_mut();
+        let bytes = &mut data[offset as usize..(offset + size) as usize];
+
+        match size {
+            1 => bytes[0] = value as u8,
+            2 => bytes.copy_from_slice(&(value as u16).to_le_bytes()),
+            4 => bytes.copy_from_slice(&(value as u32).to_le_bytes()),
+            8 => bytes.copy_from_slice(&value.to_le_bytes()),
+            _ => return false
+        }
+
+        true
+    }
+
+    fn write_bytes(&mut self, offset: u64, data: &[u8]) -> bool {
+        self.memory.write(DRAM_BASE + offset, data)
+    }
+
+    fn is_memory(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn little_endian() {
+        let mut dram = Dram::new(Memory::new(16));
+        assert!(dram.write(0, 8, 0x0123_4567_89AB_CDEF));
+
+        assert_eq!(dram.read(0, 1), Some(0xEF));
+        assert_eq!(dram.read(1, 2), Some(0xABCD));
+        assert_eq!(dram.read(4, 4), Some(0x0123_4567));
+        assert_eq!(dram.read(3, 8), Some(0x01_2345_6789));
+
+        assert!(dram.write(6, 2, 0xFFFF_BEEF));
+        assert_eq!(dram.read(0, 8), Some(0xBEEF_4567_89AB_CDEF));
+        assert_eq!(dram.read(8, 1), Some(0));
+    }
+
+    #[test]
+    fn odd_sizes() {
+        let mut dram = Dram::new(Memory::new(16));
+
+        assert_eq!(dram.read(0, 3), None);
+        assert!(!dram.write(0, 16, 0));
+
+        assert!(dram.write_bytes(13, b"abc"));
+        assert_eq!(dram.read(12, 4), Some(0x6362_6100));
+    }
+
+    #[test]
+    fn shared_memory() {
+        let memory = Memory::new(16);
+        let mut dram = Dram::new(memory.clone());
+
+        // What devices write with DMA is what the CPU sees
+        assert!(memory.write(DRAM_BASE + 4, &[1, 2]));
+        assert_eq!(dram.read(4, 2), Some(0x0201));
+
+        // Buffers have to be all in DRAM
+        let mut buffer = [0; 4];
+        assert!(!memory.read(DRAM_BASE + 14, &mut buffer));
+        assert!(!memory.read(DRAM_BASE - 2, &mut buffer));
+        assert!(!memory.write(u64::MAX - 1, &buffer));
+        assert!(memory.read(DRAM_BASE + 12, &mut buffer));
+    }
+}
@@ -0,0 +1,60 @@
+pub mod clint;
+pub mod dram;
+pub mod plic;
+pub mod rom;
+pub mod syscon;
+pub mod terminal;
+pub mod uart;
+pub mod virtio;
+pub mod virtio_blk;
+
+use crate::fdt::Fdt;
+
+// Anything that can be mapped on the bus. Offsets are relative to the base of the device's
+// region, and accesses are 1, 2, 4 or 8 bytes wide.
+pub trait Device {
+    fn name(&self) -> &'static str;
+
+    // None if the device doesn't support this access, the CPU gets an access fault
+    fn read(&mut self, offset: u64, size: u64) -> Option<u64>;
+
+    // Same, returns false if the access isn't supported
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> bool;
+
+    // Bulk copy, used to load images in memory. Devices can do better than byte accesses.
+    fn write_bytes(&mut self, offset: u64, data: &[u8]) -> bool {
+        data.iter().enumerate().all(|(i, &byte)| self.write(offset + i as u64, 1, byte as u64))
+    }
+
+    // RAM or ROM, reading it has no side effects. The debuggers only look at those.
+    fn is_memory(&self) -> bool {
+        false
+    }
+
+    // Called once per instruction
+    fn tick(&mut self) {}
+
+    // Level of the device's interrupt line
+    fn interrupt(&self) -> bool {
+        false
+    }
+
+    // Levels of the interrupt lines of every device, bit n being source n. Only the PLIC cares.
+    fn set_interrupt_lines(&mut self, _lines: u64) {}
+
+    // Bits of mip the device drives directly, like the CLINT's timer and software interrupts
+    fn local_interrupts(&self) -> u64 {
+        0
+    }
+
+    // Value of mtime, for the time CSR. Only the CLINT has one.
+    fn mtime(&self) -> Option<u64> {
+        None
+    }
+
+    // Back to the power-on state, when the machine is reset
+    fn reset(&mut self) {}
+
+    // Adds the device's node to the device tree, given where it's mapped and its PLIC source
+    fn device_tree(&self, _fdt: &mut Fdt, _base: u64, _size: u64, _irq: Option<u32>) {}
+}
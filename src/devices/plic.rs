@@ -0,0 +1,284 @@
+// Platform-level interrupt controller, with the SiFive layout QEMU's virt machine uses.
+// Context 0 is M-mode on hart 0, context 1 is S-mode on hart 0.
+
+use super::Device;
+use crate::cpu::csr::{MIP_MEIP, MIP_SEIP};
+use crate::fdt::{self, Fdt};
+
+pub const PLIC_BASE: u64 = 0x0C000000;
+pub const PLIC_SIZE: u64 = 0x600000;
+
+// Source 0 doesn't exist, it means "no interrupt" when claiming
+const SOURCES: usize = 64;
+const CONTEXTS: usize = 2;
+
+const PRIORITY: u64 = 0x0;
+const PENDING: u64 = 0x1000;
+const ENABLE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT: u64 = 0x200000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+
+pub struct Plic {
+    priority: [u32; SOURCES],
+    pending: u64,
+    // Claimed and not completed yet, the gateway doesn't forward new requests for those
+    claimed: u64,
+    enable: [u64; CONTEXTS],
+    threshold: [u32; CONTEXTS]
+}
+
+impl Plic {
+    pub fn new() -> Self {
+        Self {
+            priority: [0; SOURCES],
+            pending: 0,
+            claimed: 0,
+            enable: [0; CONTEXTS],
+            threshold: [0; CONTEXTS]
+        }
+    }
+
+    // Highest priority pending source above the context's threshold, ties go to the lowest ID
+    fn best(&self, context: usize) -> Option<usize> {
+        let candidates = self.pending & self.enable[context];
+
+        (1..SOURCES)
+            .filter(|&source| candidates & (1 << source) != 0 && self.priority[source] > self.threshold[context])
+            .fold(None, |best: Option<usize>, source| match best {
+                Some(best) if self.priority[best] >= self.priority[source] => Some(best),
+                _ => Some(source)
+            })
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best(context) {
+            Some(source) => {
+                self.pending &= !(1 << source);
+                self.claimed |= 1 << source;
+                source as u32
+            }
+            None => 0
+        }
+    }
+
+    fn complete(&mut self, context: usize, source: u64) {
+        // Completions for sources the context can't see are ignored
+        if (source as usize) < SOURCES && self.enable[context] & (1 << source) != 0 {
+            self.claimed &= !(1 << source);
+        }
+    }
+}
+
+impl Device for Plic {
+    fn name(&self) -> &'static str {
+        "plic"
+    }
+
+    // Every register is 32 bits wide
+    fn read(&mut self, offset: u64, size: u64) -> Option<u64> {
+        if size != 4 {
+            return None;
+        }
+
+        let value = match offset {
+            PRIORITY..=0xFFF => self.priority.get((offset / 4) as usize).copied().unwrap_or(0),
+            PENDING..=0x107F => {
+                let word = (offset - PENDING) / 4;
+                if word < 2 { (self.pending >> (32 * word)) as u32 } else { 0 }
+            }
+            ENABLE..=0x1F1FFF => {
+                let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+                let word = (offset - ENABLE) % ENABLE_STRIDE / 4;
+
+                match self.enable.get(context) {
+                    Some(&enable) if word < 2 => (enable >> (32 * word)) as u32,
+                    _ => 0
+                }
+            }
+            CONTEXT.. => {
+                let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+
+                match ((offset - CONTEXT) % CONTEXT_STRIDE, context < CONTEXTS) {
+                    (0, true) => self.threshold[context],
+                    (4, true) => self.claim(context),
+                    _ => 0
+                }
+            }
+            _ => 0
+        };
+
+        Some(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> bool {
+        if size != 4 {
+            return false;
+        }
+
+        let value = value as u32;
+
+        match offset {
+            PRIORITY..=0xFFF => {
+                let source = (offset / 4) as usize;
+                if source != 0 && source < SOURCES {
+                    self.priority[source] = value & 0x7;
+                }
+            }
+            ENABLE..=0x1F1FFF => {
+                let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+                let word = (offset - ENABLE) % ENABLE_STRIDE / 4;
+
+                if context < CONTEXTS && word < 2 {
+                    let shift = 32 * word;
+                    // Source 0 can't be enabled
+                    let enable = (self.enable[context] & !(0xFFFFFFFF << shift)) | ((value as u64) << shift);
+                    self.enable[context] = enable & !1;
+                }
+            }
+            CONTEXT.. => {
+                let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+
+                match ((offset - CONTEXT) % CONTEXT_STRIDE, context < CONTEXTS) {
+                    (0, true) => self.threshold[context] = value & 0x7,
+                    (4, true) => self.complete(context, value as u64),
+                    _ => {}
+                }
+            }
+            // The pending bits are read-only
+            _ => {}
+        }
+
+        true
+    }
+
+    // Level-triggered: a source that is high becomes pending, unless it's being serviced
+    fn set_interrupt_lines(&mut self, lines: u64) {
+        self.pending |= lines & !self.claimed & !1;
+    }
+
+    fn local_interrupts(&self) -> u64 {
+        let meip = if self.best(0).is_some() { MIP_MEIP } else { 0 };
+        let seip = if self.best(1).is_some() { MIP_SEIP } else { 0 };
+
+        meip | seip
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    // The contexts are listed in order, M-mode then S-mode
+    fn device_tree(&self, fdt: &mut Fdt, base: u64, size: u64, _irq: Option<u32>) {
+        fdt.begin_node(&format!("plic@{:x}", base));
+        fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_reg(base, size);
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_u32("riscv,ndev", SOURCES as u32 - 1);
+        fdt.property_cells("interrupts-extended", &[
+            fdt::CPU_INTC_PHANDLE, MIP_MEIP.trailing_zeros(),
+            fdt::CPU_INTC_PHANDLE, MIP_SEIP.trailing_zeros()
+        ]);
+        fdt.property_u32("phandle", fdt::PLIC_PHANDLE);
+        fdt.end_node();
+    }
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLAIM: u64 = CONTEXT + 4;
+    const S_CLAIM: u64 = CONTEXT + CONTEXT_STRIDE + 4;
+
+    fn store(plic: &mut Plic, offset: u64, value: u64) {
+        assert!(plic.write(offset, 4, value));
+    }
+
+    fn load(plic: &mut Plic, offset: u64) -> u64 {
+        plic.read(offset, 4).unwrap()
+    }
+
+    // Sources 3 and 10 enabled for M-mode with priority 1
+    fn plic() -> Plic {
+        let mut plic = Plic::new();
+        store(&mut plic, 3 * 4, 1);
+        store(&mut plic, 10 * 4, 1);
+        store(&mut plic, ENABLE, (1 << 3) | (1 << 10));
+        plic
+    }
+
+    #[test]
+    fn claim_and_complete() {
+        let mut plic = plic();
+        plic.set_interrupt_lines(1 << 10);
+        assert_eq!(load(&mut plic, PENDING), 1 << 10);
+        assert_eq!(plic.local_interrupts(), MIP_MEIP);
+
+        // Claiming clears the pending bit, and the line being high doesn't raise it again
+        assert_eq!(load(&mut plic, CLAIM), 10);
+        assert_eq!(plic.local_interrupts(), 0);
+        plic.set_interrupt_lines(1 << 10);
+        assert_eq!(load(&mut plic, PENDING), 0);
+        assert_eq!(load(&mut plic, CLAIM), 0);
+
+        // Until it's completed
+        store(&mut plic, CLAIM, 10);
+        plic.set_interrupt_lines(1 << 10);
+        assert_eq!(load(&mut plic, CLAIM), 10);
+
+        // A context can't complete a source it doesn't have enabled
+        store(&mut plic, S_CLAIM, 10);
+        plic.set_interrupt_lines(1 << 10);
+        assert_eq!(load(&mut plic, PENDING), 0);
+    }
+
+    #[test]
+    fn priorities() {
+        let mut plic = plic();
+        plic.set_interrupt_lines((1 << 3) | (1 << 10));
+
+        // Ties go to the lowest ID
+        assert_eq!(plic.best(0), Some(3));
+        store(&mut plic, 10 * 4, 2);
+        assert_eq!(plic.best(0), Some(10));
+
+        // Only sources above the threshold, and with a priority, are delivered
+        store(&mut plic, CONTEXT, 2);
+        assert_eq!(plic.local_interrupts(), 0);
+        store(&mut plic, CONTEXT, 0);
+        store(&mut plic, 10 * 4, 0);
+        store(&mut plic, 3 * 4, 0);
+        assert_eq!(plic.best(0), None);
+
+        // Priorities are 3 bits, and source 0 doesn't exist
+        store(&mut plic, 3 * 4, 0xFF);
+        store(&mut plic, 0, 5);
+        assert_eq!((load(&mut plic, 3 * 4), load(&mut plic, 0)), (7, 0));
+    }
+
+    #[test]
+    fn contexts() {
+        let mut plic = plic();
+        store(&mut plic, ENABLE + ENABLE_STRIDE, 1 | (1 << 3));
+        assert_eq!(load(&mut plic, ENABLE + ENABLE_STRIDE), 1 << 3);
+
+        // Both contexts see it, the first one to claim it gets it
+        plic.set_interrupt_lines(1 << 3);
+        assert_eq!(plic.local_interrupts(), MIP_MEIP | MIP_SEIP);
+        assert_eq!(load(&mut plic, S_CLAIM), 3);
+        assert_eq!(load(&mut plic, CLAIM), 0);
+
+        // Only 32-bit accesses
+        assert_eq!(plic.read(CLAIM, 8), None);
+        assert!(!plic.write(0, 1, 0));
+    }
+}
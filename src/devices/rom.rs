@@ -0,0 +1,99 @@
+// Boot ROM with the reset vector, at the same place as the one in QEMU's virt machine. The
+// hart starts there, and it jumps to the firmware with a0 = hart ID and a1 = device tree.
+
+use super::Device;
+
+pub const ROM_BASE: u64 = 0x1000;
+pub const ROM_SIZE: u64 = 0x1000;
+
+// Tells OpenSBI's fw_dynamic what to boot next, ignored by the other firmwares
+const FW_DYNAMIC_INFO_MAGIC: u64 = 0x4942534F; // "OSBI"
+const FW_DYNAMIC_INFO_VERSION: u64 = 2;
+const FW_DYNAMIC_INFO_NEXT_MODE_S: u64 = 1;
+
+pub struct Rom {
+    data: Vec<u8>
+}
+
+impl Rom {
+    pub fn new() -> Self {
+        Self { data: vec![0; ROM_SIZE as usize] }
+    }
+
+    // The same code as QEMU's, followed by the addresses it loads and fw_dynamic's info
+    pub fn reset_vector(entry: u64, dtb: u64, next: u64) -> Vec<u8> {
+        let code: [u32; 6] = [
+            0x00000297, // auipc t0, 0
+            0x02828613, // addi a2, t0, 40 (fw_dynamic_info)
+            0xF1402573, // csrr a0, mhartid
+            0x0202B583, // ld a1, 32(t0) (device tree)
+            0x0182B283, // ld t0, 24(t0) (entry)
+            0x00028067  // jr t0
+        ];
+        let data = [
+            entry,
+            dtb,
+            FW_DYNAMIC_INFO_MAGIC,
+            FW_DYNAMIC_INFO_VERSION,
+            next,
+            FW_DYNAMIC_INFO_NEXT_MODE_S,
+            0, // Options
+            0  // Boot hart
+        ];
+
+        code.iter().flat_map(|word| word.to_le_bytes())
+            .chain(data.iter().flat_map(|dword| dword.to_le_bytes()))
+            .collect()
+    }
+}
+
+impl Device for Rom {
+    fn name(&self) -> &'static str {
+        "rom"
+    }
+
+    fn read(&mut self, offset: u64, size: u64) -> Option<u64> {
+        let bytes = &self.data[offset as usize..(offset + size) as usize];
+        Some(bytes.iter().rev().fold(0, |value, &byte| (value << 8) | byte as u64))
+    }
+
+    // Read-only for the CPU
+    fn write(&mut self, _offset: u64, _size: u64, _value: u64) -> bool {
+        false
+    }
+
+    // That's how the contents get there
+    fn write_bytes(&mut self, offset: u64, data: &[u8]) -> bool {
+        self.data[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+        true
+    }
+
+    fn is_memory(&self) -> bool {
+        true
+    }
+}
+
+impl Default for Rom {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_only() {
+        let mut rom = Rom::new();
+        assert!(rom.write_bytes(0, &Rom::reset_vector(0x80000000, 0x87E00000, 0)));
+
+        assert_eq!(rom.read(0, 4), Some(0x00000297));
+        assert_eq!(rom.read(24, 8), Some(0x80000000));
+        assert_eq!(rom.read(40, 8), Some(FW_DYNAMIC_INFO_MAGIC));
+        assert_eq!(rom.read(26, 2), Some(0x8000));
+
+        assert!(!rom.write(0, 4, 0));
+        assert_eq!(rom.read(0, 1), Some(0x97));
+    }
+}
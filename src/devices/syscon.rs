@@ -0,0 +1,109 @@
+// SiFive "test finisher" style poweroff/reboot device, like the one in QEMU's virt machine.
+// Writing to its single 32-bit register ends the simulation.
+
+use std::cell::Cell;
+use std::rc::Rc;
+use super::Device;
+use crate::fdt::{self, Fdt};
+
+pub const SYSCON_BASE: u64 = 0x100000;
+pub const SYSCON_SIZE: u64 = 0x1000;
+
+const FINISHER_FAIL: u64 = 0x3333;
+const FINISHER_PASS: u64 = 0x5555;
+const FINISHER_RESET: u64 = 0x7777;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    Running,
+    // With the exit code of the process
+    PowerOff(i32),
+    Reset
+}
+
+pub struct Syscon {
+    // Shared with the bus, which is what the main loop looks at
+    state: Rc<Cell<PowerState>>
+}
+
+impl Syscon {
+    pub fn new(state: Rc<Cell<PowerState>>) -> Self {
+        Self { state }
+    }
+}
+
+impl Device for Syscon {
+    fn name(&self) -> &'static str {
+        "syscon"
+    }
+
+    fn read(&mut self, _offset: u64, size: u64) -> Option<u64> {
+        if size != 4 { None } else { Some(0) }
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> bool {
+        if size != 4 {
+            return false;
+        }
+
+        if offset != 0 {
+            return true;
+        }
+
+        // The upper half holds the exit code when failing
+        match value & 0xFFFF {
+            FINISHER_PASS => self.state.set(PowerState::PowerOff(0)),
+            FINISHER_FAIL => self.state.set(PowerState::PowerOff(((value >> 16) & 0xFFFF) as i32)),
+            FINISHER_RESET => self.state.set(PowerState::Reset),
+            _ => {}
+        }
+
+        true
+    }
+
+    // Linux powers off and reboots through the syscon-poweroff and syscon-reboot drivers
+    fn device_tree(&self, fdt: &mut Fdt, base: u64, size: u64, _irq: Option<u32>) {
+        fdt.begin_node(&format!("test@{:x}", base));
+        fdt.property_strings("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+        fdt.property_reg(base, size);
+        fdt.property_u32("phandle", fdt::SYSCON_PHANDLE);
+        fdt.end_node();
+
+        for (name, value) in [("poweroff", FINISHER_PASS), ("reboot", FINISHER_RESET)] {
+            fdt.begin_node(name);
+            fdt.property_string("compatible", &format!("syscon-{}", name));
+            fdt.property_u32("regmap", fdt::SYSCON_PHANDLE);
+            fdt.property_u32("offset", 0);
+            fdt.property_u32("value", value as u32);
+            fdt.end_node();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finish(offset: u64, value: u64) -> PowerState {
+        let state = Rc::new(Cell::new(PowerState::Running));
+        let mut syscon = Syscon::new(state.clone());
+        assert!(syscon.write(offset, 4, value));
+        state.get()
+    }
+
+    #[test]
+    fn finisher() {
+        assert_eq!(finish(0, FINISHER_PASS), PowerState::PowerOff(0));
+        assert_eq!(finish(0, (42 << 16) | FINISHER_FAIL), PowerState::PowerOff(42));
+        assert_eq!(finish(0, FINISHER_RESET), PowerState::Reset);
+
+        // Anything else does nothing
+        assert_eq!(finish(0, 0x1234), PowerState::Running);
+        assert_eq!(finish(4, FINISHER_PASS), PowerState::Running);
+
+        // Only 32-bit accesses
+        let mut syscon = Syscon::new(Rc::new(Cell::new(PowerState::Running)));
+        assert!(!syscon.write(0, 2, FINISHER_PASS));
+        assert_eq!(syscon.read(0, 8), None);
+    }
+}
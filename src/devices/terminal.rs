@@ -0,0 +1,117 @@
+// Puts the host terminal in raw mode while the emulator runs, so the guest gets every key
+// as it's typed. ISIG is kept, so Ctrl-C still reaches the emulator.
+//
+// What's typed is shared by the UART and the monitor. They never read at the same time,
+// since the guest doesn't run while the monitor waits for a command.
+
+use std::io::Read;
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+static INPUT: OnceLock<Mutex<Receiver<u8>>> = OnceLock::new();
+// Bytes in the channel. The UART polls for input at every instruction, this is cheaper than
+// taking the lock just to find there's nothing.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+// stdin blocks, so it gets its own thread, started the first time there's something to read.
+// It stops at EOF.
+fn input() -> &'static Mutex<Receiver<u8>> {
+    INPUT.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let mut stdin = std::io::stdin();
+            let mut buffer = [0; 64];
+
+            while let Ok(n @ 1..) = stdin.read(&mut buffer) {
+                // Counted before it's sent, so that the count never goes below 0
+                PENDING.fetch_add(n, Ordering::Release);
+
+                if buffer[..n].iter().any(|&byte| sender.send(byte).is_err()) {
+                    break;
+                }
+            }
+        });
+
+        Mutex::new(receiver)
+    })
+}
+
+// A byte typed on the host, if there's one waiting
+pub fn try_read_byte() -> Option<u8> {
+    let input = input();
+    if PENDING.load(Ordering::Acquire) == 0 {
+        return None;
+    }
+
+    let byte = input.lock().unwrap().try_recv().ok()?;
+    PENDING.fetch_sub(1, Ordering::Release);
+
+    Some(byte)
+}
+
+// Waits for a whole line, None at EOF
+pub fn read_line() -> Option<String> {
+    let input = input().lock().unwrap();
+    let mut line = vec![];
+
+    loop {
+        let byte = input.recv();
+        if byte.is_ok() {
+            PENDING.fetch_sub(1, Ordering::Release);
+        }
+
+        match byte {
+            Ok(b'\n') => break,
+            Ok(byte) => line.push(byte),
+            Err(_) if line.is_empty() => return None,
+            Err(_) => break
+        }
+    }
+
+    Some(String::from_utf8_lossy(&line).into_owned())
+}
+
+pub struct Terminal {
+    // None if stdin isn't a terminal, in which case there's nothing to restore
+    original: Option<libc::termios>
+}
+
+impl Terminal {
+    pub fn raw() -> Self {
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) == 0 {
+                return Self { original: None };
+            }
+
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return Self { original: None };
+            }
+
+            let original = termios;
+
+            // No echo, no line buffering, and Enter sends CR like a real serial terminal
+            termios.c_lflag &= !(libc::ICANON | libc::ECHO | libc::IEXTEN);
+            termios.c_iflag &= !(libc::ICRNL | libc::IXON);
+            termios.c_cc[libc::VMIN] = 1;
+            termios.c_cc[libc::VTIME] = 0;
+
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+
+            Self { original: Some(original) }
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if let Some(original) = self.original {
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &original);
+            }
+        }
+    }
+}
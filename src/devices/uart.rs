@@ -0,0 +1,326 @@
+// NS16550A UART, like the one in QEMU's virt machine. It's wired to the host terminal:
+// transmitted bytes go straight to stdout, and received ones come from stdin.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use super::Device;
+use super::terminal;
+use crate::fdt::{self, Fdt};
+
+pub const UART_BASE: u64 = 0x10000000;
+pub const UART_SIZE: u64 = 0x100;
+pub const UART_IRQ: u32 = 10;
+
+const FIFO_SIZE: usize = 16;
+// Only used to compute baud rates from the divisor, same as QEMU
+const CLOCK_FREQUENCY: u32 = 3686400;
+
+// Register offsets, some of them are different registers when read and written, or when DLAB is set
+const RBR: u64 = 0; // Receiver buffer (read), THR when written, DLL with DLAB
+const IER: u64 = 1; // Interrupt enable, DLM with DLAB
+const IIR: u64 = 2; // Interrupt identification (read), FCR when written
+const LCR: u64 = 3; // Line control
+const MCR: u64 = 4; // Modem control
+const LSR: u64 = 5; // Line status
+const MSR: u64 = 6; // Modem status
+const SCR: u64 = 7; // Scratch
+
+const IER_RDA: u8 = 1 << 0; // Received data available
+const IER_THRE: u8 = 1 << 1; // Transmitter holding register empty
+
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RX_DATA: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xC0;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const LSR_TX_EMPTY: u8 = 1 << 6;
+
+pub struct Uart {
+    rx_fifo: VecDeque<u8>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    // The THR empty interrupt is cleared by reading IIR, and raised again by the next transmission
+    thre_pending: bool,
+    // Where received bytes come from, the host terminal
+    input: fn() -> Option<u8>
+}
+
+impl Uart {
+    pub fn new() -> Self {
+        Self {
+            rx_fifo: VecDeque::with_capacity(FIFO_SIZE),
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 0,
+            thre_pending: false,
+            input: terminal::try_read_byte
+        }
+    }
+
+    // Moves whatever the host typed into the receive FIFO, as long as there's room
+    fn receive(&mut self) {
+        let capacity = if self.fcr & FCR_ENABLE != 0 { FIFO_SIZE } else { 1 };
+
+        while self.rx_fifo.len() < capacity {
+            match (self.input)() {
+                Some(byte) => self.rx_fifo.push_back(byte),
+                None => break
+            }
+        }
+    }
+
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_RDA != 0 && !self.rx_fifo.is_empty() {
+            IIR_RX_DATA
+        } else if self.ier & IER_THRE != 0 && self.thre_pending {
+            IIR_THR_EMPTY
+        } else {
+            IIR_NO_INTERRUPT
+        }
+    }
+}
+
+impl Device for Uart {
+    fn name(&self) -> &'static str {
+        "uart"
+    }
+
+    // The registers are all 8 bits wide
+    fn read(&mut self, offset: u64, size: u64) -> Option<u64> {
+        if size != 1 {
+            return None;
+        }
+
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        let value = match offset {
+            RBR if dlab => self.divisor as u8,
+            RBR => self.rx_fifo.pop_front().unwrap_or(0),
+            IER if dlab => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR => {
+                let id = self.interrupt_id();
+
+                if id == IIR_THR_EMPTY {
+                    self.thre_pending = false;
+                }
+
+                let fifo = if self.fcr & FCR_ENABLE != 0 { IIR_FIFO_ENABLED } else { 0 };
+                id | fifo
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            // Transmission is instantaneous, so the transmitter is always empty
+            LSR => LSR_THR_EMPTY | LSR_TX_EMPTY | if self.rx_fifo.is_empty() { 0 } else { LSR_DATA_READY },
+            // Report CTS, DSR and DCD as always asserted
+            MSR => 0xB0,
+            SCR => self.scr,
+            _ => 0
+        };
+
+        Some(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> bool {
+        if size != 1 {
+            return false;
+        }
+
+        let value = value as u8;
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        match offset {
+            RBR if dlab => self.divisor = (self.divisor & 0xFF00) | value as u16,
+            RBR => {
+                let mut stdout = std::io::stdout();
+                // There isn't much we can do if the host terminal is gone
+                let _ = stdout.write_all(&[value]).and_then(|_| stdout.flush());
+                self.thre_pending = true;
+            }
+            IER if dlab => self.divisor = (self.divisor & 0x00FF) | (value as u16) << 8,
+            IER => {
+                // Enabling the THR empty interrupt raises it right away, since the THR is always empty
+                if value & IER_THRE != 0 && self.ier & IER_THRE == 0 {
+                    self.thre_pending = true;
+                }
+
+                self.ier = value & 0x0F;
+            }
+            IIR => {
+                if value & FCR_CLEAR_RX != 0 {
+                    self.rx_fifo.clear();
+                }
+
+                self.fcr = value & !FCR_CLEAR_RX;
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & 0x1F,
+            SCR => self.scr = value,
+            _ => {}
+        }
+
+        true
+    }
+
+    // Moves whatever the host typed into the receive FIFO
+    fn tick(&mut self) {
+        self.receive();
+    }
+
+    fn interrupt(&self) -> bool {
+        self.interrupt_id() != IIR_NO_INTERRUPT
+    }
+
+    // Registers go back to their reset values, pending input is kept
+    fn reset(&mut self) {
+        self.ier = 0;
+        self.fcr = 0;
+        self.lcr = 0;
+        self.mcr = 0;
+        self.scr = 0;
+        self.divisor = 0;
+        self.thre_pending = false;
+    }
+
+    fn device_tree(&self, fdt: &mut Fdt, base: u64, size: u64, irq: Option<u32>) {
+        fdt.begin_node(&format!("serial@{:x}", base));
+        fdt.property_string("compatible", "ns16550a");
+        fdt.property_reg(base, size);
+        fdt.property_u32("clock-frequency", CLOCK_FREQUENCY);
+        if let Some(irq) = irq {
+            fdt.property_u32("interrupts", irq);
+            fdt.property_u32("interrupt-parent", fdt::PLIC_PHANDLE);
+        }
+        fdt.end_node();
+    }
+}
+
+impl Default for Uart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static TYPED: RefCell<VecDeque<u8>> = const { RefCell::new(VecDeque::new()) };
+    }
+
+    fn typed() -> Option<u8> {
+        TYPED.with(|typed| typed.borrow_mut().pop_front())
+    }
+
+    fn type_bytes(bytes: &[u8]) {
+        TYPED.with(|typed| typed.borrow_mut().extend(bytes));
+    }
+
+    // A UART that reads what the test types instead of stdin
+    fn uart() -> Uart {
+        let mut uart = Uart::new();
+        uart.input = typed;
+
+        uart
+    }
+
+    fn load8(uart: &mut Uart, offset: u64) -> u8 {
+        uart.read(offset, 1).unwrap() as u8
+    }
+
+    fn store8(uart: &mut Uart, offset: u64, value: u8) {
+        assert!(uart.write(offset, 1, value as u64));
+    }
+
+    #[test]
+    fn receive() {
+        let mut uart = uart();
+        assert_eq!(load8(&mut uart, LSR) & LSR_DATA_READY, 0);
+
+        // Without the FIFO, only one byte is held at a time
+        type_bytes(b"ab");
+        uart.tick();
+        assert_eq!(load8(&mut uart, LSR) & LSR_DATA_READY, LSR_DATA_READY);
+        assert_eq!(uart.rx_fifo.len(), 1);
+        assert_eq!(load8(&mut uart, RBR), b'a');
+        uart.tick();
+        assert_eq!(load8(&mut uart, RBR), b'b');
+        assert_eq!(load8(&mut uart, LSR) & LSR_DATA_READY, 0);
+
+        store8(&mut uart, IIR, FCR_ENABLE);
+        type_bytes(b"hello");
+        uart.tick();
+        assert_eq!(load8(&mut uart, IIR), IIR_NO_INTERRUPT | IIR_FIFO_ENABLED);
+        assert_eq!(uart.rx_fifo.len(), 5);
+
+        store8(&mut uart, IIR, FCR_ENABLE | FCR_CLEAR_RX);
+        assert!(uart.rx_fifo.is_empty());
+    }
+
+    #[test]
+    fn interrupts() {
+        let mut uart = uart();
+
+        // Enabling it raises the THR empty interrupt, reading IIR clears it
+        store8(&mut uart, IER, IER_THRE);
+        assert_eq!(load8(&mut uart, IIR), IIR_THR_EMPTY);
+        assert_eq!(load8(&mut uart, IIR), IIR_NO_INTERRUPT);
+
+        // Until the next transmission
+        store8(&mut uart, RBR, 0);
+        assert_eq!(uart.interrupt_id(), IIR_THR_EMPTY);
+
+        // Received data comes first
+        store8(&mut uart, IER, IER_THRE | IER_RDA);
+        type_bytes(b"x");
+        uart.tick();
+        assert_eq!(load8(&mut uart, IIR), IIR_RX_DATA);
+        assert_eq!(load8(&mut uart, RBR), b'x');
+        assert_eq!(load8(&mut uart, IIR), IIR_THR_EMPTY);
+    }
+
+    #[test]
+    fn divisor_latch() {
+        let mut uart = uart();
+        store8(&mut uart, IER, IER_RDA);
+
+        store8(&mut uart, LCR, LCR_DLAB | 0x03);
+        store8(&mut uart, RBR, 0x0C);
+        store8(&mut uart, IER, 0x01);
+        assert_eq!((load8(&mut uart, RBR), load8(&mut uart, IER)), (0x0C, 0x01));
+        assert_eq!(uart.divisor, 0x010C);
+
+        // The registers behind it are untouched
+        store8(&mut uart, LCR, 0x03);
+        assert_eq!(load8(&mut uart, IER), IER_RDA);
+    }
+
+    #[test]
+    fn reset_keeps_input() {
+        let mut uart = uart();
+        store8(&mut uart, SCR, 0x42);
+        store8(&mut uart, IER, IER_THRE);
+        type_bytes(b"q");
+        uart.tick();
+
+        uart.reset();
+        assert_eq!((load8(&mut uart, SCR), load8(&mut uart, IER)), (0, 0));
+        assert_eq!(load8(&mut uart, RBR), b'q');
+    }
+}
@@ -0,0 +1,547 @@
+// virtio-mmio transport, both the legacy (version 1) and the modern (version 2) register
+// layouts. The device behind it only sees descriptor chains, the rings are handled here.
+
+use std::convert::TryInto;
+use super::Device;
+use super::dram::Memory;
+use crate::fdt::{self, Fdt};
+
+pub const VIRTIO_BASE: u64 = 0x10001000;
+pub const VIRTIO_SIZE: u64 = 0x1000;
+pub const VIRTIO_IRQ: u32 = 1;
+
+const MAGIC: u64 = 0x000;
+const VERSION: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID: u64 = 0x00C;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const GUEST_PAGE_SIZE: u64 = 0x028; // Legacy only
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_ALIGN: u64 = 0x03C; // Legacy only
+const QUEUE_PFN: u64 = 0x040; // Legacy only
+const QUEUE_READY: u64 = 0x044; // Modern only
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0A0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0A4;
+const CONFIG_GENERATION: u64 = 0x0FC;
+const CONFIG: u64 = 0x100;
+
+const MAGIC_VALUE: u32 = 0x74726976; // "virt"
+const VENDOR_QEMU: u32 = 0x554D4551;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const QUEUE_SIZE: u16 = 128;
+const LEGACY_QUEUE_ALIGN: u32 = 4096;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+const INTERRUPT_USED_BUFFER: u32 = 1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    // Whether the device writes to the buffer, otherwise it reads from it
+    pub write: bool
+}
+
+// What sits behind the transport, like a block device
+pub trait VirtioDevice {
+    fn name(&self) -> &'static str;
+    fn device_id(&self) -> u32;
+    fn features(&self) -> u64;
+    // Device-specific configuration space
+    fn config(&self) -> Vec<u8>;
+    // Handles a request, returns the number of bytes written to the guest's buffers
+    fn process(&mut self, memory: &Memory, chain: &[Descriptor]) -> u32;
+}
+
+#[derive(Clone, Copy)]
+struct Queue {
+    num: u16,
+    ready: bool,
+    desc: u64,
+    driver: u64,
+    device: u64,
+    // Legacy queues are described by a page number
+    pfn: u32,
+    align: u32,
+    // Next entry of the available ring we haven't handled yet
+    last_avail: u16
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Self {
+            num: 0,
+            ready: false,
+            desc: 0,
+            driver: 0,
+            device: 0,
+            pfn: 0,
+            // What the legacy spec says QueueAlign is until the driver writes it, old xv6 never does
+            align: LEGACY_QUEUE_ALIGN,
+            last_avail: 0
+        }
+    }
+}
+
+pub struct VirtioMmio<D: VirtioDevice> {
+    device: D,
+    memory: Memory,
+    legacy: bool,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    guest_page_size: u32,
+    queue_sel: u32,
+    // A single queue is enough for block devices
+    queue: Queue,
+    interrupt_status: u32,
+    status: u32
+}
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    pub fn new(device: D, memory: Memory, legacy: bool) -> Self {
+        Self {
+            device,
+            memory,
+            legacy,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            guest_page_size: 0,
+            queue_sel: 0,
+            queue: Default::default(),
+            interrupt_status: 0,
+            status: 0
+        }
+    }
+
+    fn features(&self) -> u64 {
+        if self.legacy { self.device.features() } else { self.device.features() | VIRTIO_F_VERSION_1 }
+    }
+
+    // Writing 0 to the status register resets the device
+    fn reset_transport(&mut self) {
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.queue = Default::default();
+        self.interrupt_status = 0;
+        self.status = 0;
+    }
+
+    // Legacy queues are contiguous: descriptors, available ring, then the used ring aligned on QueueAlign
+    fn legacy_layout(&mut self) {
+        let queue = &mut self.queue;
+        let num = queue.num as u64;
+        let align = queue.align as u64;
+
+        queue.desc = queue.pfn as u64 * self.guest_page_size as u64;
+        queue.driver = queue.desc + 16 * num;
+        queue.device = (queue.driver + 6 + 2 * num).div_ceil(align) * align;
+        queue.ready = queue.pfn != 0;
+    }
+
+    fn read_u16(&self, addr: u64) -> Option<u16> {
+        let mut bytes = [0; 2];
+        if self.memory.read(addr, &mut bytes) { Some(u16::from_le_bytes(bytes)) } else { None }
+    }
+
+    fn read_descriptor(&self, index: u16) -> Option<(Descriptor, u16, u16)> {
+        let mut bytes = [0; 16];
+        if !self.memory.read(self.queue.desc + 16 * index as u64, &mut bytes) {
+            return None;
+        }
+
+        let flags = u16::from_le_bytes(bytes[12..14].try_into().ok()?);
+        let descriptor = Descriptor {
+            addr: u64::from_le_bytes(bytes[0..8].try_into().ok()?),
+            len: u32::from_le_bytes(bytes[8..12].try_into().ok()?),
+            write: flags & DESC_F_WRITE != 0
+        };
+
+        Some((descriptor, flags, u16::from_le_bytes(bytes[14..16].try_into().ok()?)))
+    }
+
+    // Handles every request the driver made available since the last notification
+    fn process_queue(&mut self) {
+        let queue = self.queue;
+        if !queue.ready || queue.num == 0 {
+            return;
+        }
+
+        let avail_idx = match self.read_u16(queue.driver + 2) {
+            Some(idx) => idx,
+            None => return
+        };
+
+        while self.queue.last_avail != avail_idx {
+            let slot = self.queue.last_avail % queue.num;
+            let head = match self.read_u16(queue.driver + 4 + 2 * slot as u64) {
+                Some(head) => head,
+                None => return
+            };
+
+            // Collect the chain, the length check breaks loops in broken chains
+            let mut chain = vec![];
+            let mut index = head;
+            while let Some((descriptor, flags, next)) = self.read_descriptor(index % queue.num) {
+                chain.push(descriptor);
+
+                if flags & DESC_F_NEXT == 0 || chain.len() >= queue.num as usize {
+                    break;
+                }
+                index = next;
+            }
+
+            let written = self.device.process(&self.memory, &chain);
+
+            // Put the chain in the used ring and bump its index
+            let used_idx = self.read_u16(queue.device + 2).unwrap_or(0);
+            let element = queue.device + 4 + 8 * (used_idx % queue.num) as u64;
+            let mut entry = [0; 8];
+            entry[0..4].copy_from_slice(&(head as u32).to_le_bytes());
+            entry[4..8].copy_from_slice(&written.to_le_bytes());
+
+            self.memory.write(element, &entry);
+            self.memory.write(queue.device + 2, &used_idx.wrapping_add(1).to_le_bytes());
+
+            self.queue.last_avail = self.queue.last_avail.wrapping_add(1);
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+        }
+    }
+}
+
+impl<D: VirtioDevice> Device for VirtioMmio<D> {
+    fn name(&self) -> &'static str {
+        self.device.name()
+    }
+
+    fn read(&mut self, offset: u64, size: u64) -> Option<u64> {
+        // The configuration space can be accessed with any width
+        if offset >= CONFIG {
+            let config = self.device.config();
+            let start = (offset - CONFIG) as usize;
+
+            let mut value = 0;
+            for i in 0..size as usize {
+                value |= (*config.get(start + i).unwrap_or(&0) as u64) << (8 * i);
+            }
+
+            return Some(value);
+        }
+
+        if size != 4 {
+            return None;
+        }
+
+        let features = self.features();
+        let value = match offset {
+            MAGIC => MAGIC_VALUE,
+            VERSION if self.legacy => 1,
+            VERSION => 2,
+            DEVICE_ID => self.device.device_id(),
+            VENDOR_ID => VENDOR_QEMU,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => features as u32,
+                1 => (features >> 32) as u32,
+                _ => 0
+            },
+            QUEUE_NUM_MAX if self.queue_sel == 0 => QUEUE_SIZE as u32,
+            QUEUE_PFN if self.legacy => self.queue.pfn,
+            QUEUE_READY if !self.legacy => self.queue.ready as u32,
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            // The configuration never changes
+            CONFIG_GENERATION => 0,
+            _ => 0
+        };
+
+        Some(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> bool {
+        if size != 4 {
+            return false;
+        }
+
+        let value = value as u32;
+        // Only queue 0 exists, writes to the others are ignored
+        let queue_ok = self.queue_sel == 0;
+
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            DRIVER_FEATURES => match self.driver_features_sel {
+                0 => self.driver_features = (self.driver_features & !0xFFFFFFFF) | value as u64,
+                1 => self.driver_features = (self.driver_features & 0xFFFFFFFF) | (value as u64) << 32,
+                _ => {}
+            },
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            GUEST_PAGE_SIZE if self.legacy => self.guest_page_size = value,
+            QUEUE_SEL => self.queue_sel = value,
+            QUEUE_NUM if queue_ok => self.queue.num = (value as u16).min(QUEUE_SIZE),
+            // 0 isn't an alignment, the one the queue has is kept
+            QUEUE_ALIGN if self.legacy && queue_ok && value != 0 => self.queue.align = value,
+            QUEUE_PFN if self.legacy && queue_ok => {
+                self.queue.pfn = value;
+                self.legacy_layout();
+            }
+            QUEUE_READY if !self.legacy && queue_ok => self.queue.ready = value & 1 != 0,
+            QUEUE_DESC_LOW if queue_ok => self.queue.desc = (self.queue.desc & !0xFFFFFFFF) | value as u64,
+            QUEUE_DESC_HIGH if queue_ok => self.queue.desc = (self.queue.desc & 0xFFFFFFFF) | (value as u64) << 32,
+            QUEUE_DRIVER_LOW if queue_ok => self.queue.driver = (self.queue.driver & !0xFFFFFFFF) | value as u64,
+            QUEUE_DRIVER_HIGH if queue_ok => self.queue.driver = (self.queue.driver & 0xFFFFFFFF) | (value as u64) << 32,
+            QUEUE_DEVICE_LOW if queue_ok => self.queue.device = (self.queue.device & !0xFFFFFFFF) | value as u64,
+            QUEUE_DEVICE_HIGH if queue_ok => self.queue.device = (self.queue.device & 0xFFFFFFFF) | (value as u64) << 32,
+            // Queue 0 is the only one
+            QUEUE_NOTIFY if value == 0 => self.process_queue(),
+            INTERRUPT_ACK => self.interrupt_status &= !value,
+            STATUS if value == 0 => self.reset_transport(),
+            STATUS => self.status = value,
+            _ => {}
+        }
+
+        true
+    }
+
+    fn interrupt(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn reset(&mut self) {
+        self.reset_transport();
+    }
+
+    fn device_tree(&self, fdt: &mut Fdt, base: u64, size: u64, irq: Option<u32>) {
+        fdt.begin_node(&format!("virtio_mmio@{:x}", base));
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_reg(base, size);
+        if let Some(irq) = irq {
+            fdt.property_u32("interrupts", irq);
+            fdt.property_u32("interrupt-parent", fdt::PLIC_PHANDLE);
+        }
+        fdt.end_node();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::dram::DRAM_BASE;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const DESC: u64 = DRAM_BASE + 0x1000;
+    const DRIVER: u64 = DRAM_BASE + 0x2000;
+    const DEVICE: u64 = DRAM_BASE + 0x3000;
+
+    type Chains = Rc<RefCell<Vec<Vec<(u64, u32, bool)>>>>;
+
+    // Keeps the chains it gets, and says it wrote 3 bytes
+    struct Recorder {
+        chains: Chains
+    }
+
+    impl VirtioDevice for Recorder {
+        fn name(&self) -> &'static str {
+            "recorder"
+        }
+
+        fn device_id(&self) -> u32 {
+            42
+        }
+
+        fn features(&self) -> u64 {
+            1 << 5
+        }
+
+        fn config(&self) -> Vec<u8> {
+            vec![0x11, 0x22, 0x33]
+        }
+
+        fn process(&mut self, _memory: &Memory, chain: &[Descriptor]) -> u32 {
+            self.chains.borrow_mut().push(chain.iter().map(|d| (d.addr, d.len, d.write)).collect());
+            3
+        }
+    }
+
+    fn transport(legacy: bool) -> (VirtioMmio<Recorder>, Memory, Chains) {
+        let memory = Memory::new(0x10000);
+        let chains = Chains::default();
+        let recorder = Recorder { chains: chains.clone() };
+
+        (VirtioMmio::new(recorder, memory.clone(), legacy), memory, chains)
+    }
+
+    fn store(virtio: &mut VirtioMmio<Recorder>, offset: u64, value: u64) {
+        assert!(virtio.write(offset, 4, value));
+    }
+
+    fn load(virtio: &mut VirtioMmio<Recorder>, offset: u64) -> u64 {
+        virtio.read(offset, 4).unwrap()
+    }
+
+    // An 8-entry queue at DESC, DRIVER and DEVICE
+    fn modern() -> (VirtioMmio<Recorder>, Memory, Chains) {
+        let (mut virtio, memory, chains) = transport(false);
+
+        store(&mut virtio, QUEUE_SEL, 0);
+        store(&mut virtio, QUEUE_NUM, 8);
+        store(&mut virtio, QUEUE_DESC_LOW, DESC);
+        store(&mut virtio, QUEUE_DRIVER_LOW, DRIVER);
+        store(&mut virtio, QUEUE_DEVICE_LOW, DEVICE);
+        store(&mut virtio, QUEUE_READY, 1);
+
+        (virtio, memory, chains)
+    }
+
+    fn descriptor(memory: &Memory, desc: u64, index: u16, addr: u64, len: u32, flags: u16, next: u16) {
+        let mut bytes = [0; 16];
+        bytes[0..8].copy_from_slice(&addr.to_le_bytes());
+        bytes[8..12].copy_from_slice(&len.to_le_bytes());
+        bytes[12..14].copy_from_slice(&flags.to_le_bytes());
+        bytes[14..16].copy_from_slice(&next.to_le_bytes());
+        assert!(memory.write(desc + 16 * index as u64, &bytes));
+    }
+
+    // Puts the chains starting at `heads` in the available ring
+    fn make_available(memory: &Memory, driver: u64, heads: &[u16]) {
+        for (slot, head) in heads.iter().enumerate() {
+            memory.write(driver + 4 + 2 * slot as u64, &head.to_le_bytes());
+        }
+        memory.write(driver + 2, &(heads.len() as u16).to_le_bytes());
+    }
+
+    fn read_u16(memory: &Memory, addr: u64) -> u16 {
+        let mut bytes = [0; 2];
+        assert!(memory.read(addr, &mut bytes));
+        u16::from_le_bytes(bytes)
+    }
+
+    fn read_u32(memory: &Memory, addr: u64) -> u32 {
+        let mut bytes = [0; 4];
+        assert!(memory.read(addr, &mut bytes));
+        u32::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn registers() {
+        let (mut virtio, _, _) = transport(false);
+
+        assert_eq!(load(&mut virtio, MAGIC), MAGIC_VALUE as u64);
+        assert_eq!(load(&mut virtio, VERSION), 2);
+        assert_eq!(load(&mut virtio, DEVICE_ID), 42);
+        assert_eq!(load(&mut virtio, DEVICE_FEATURES), 1 << 5);
+        store(&mut virtio, DEVICE_FEATURES_SEL, 1);
+        assert_eq!(load(&mut virtio, DEVICE_FEATURES), 1);
+        assert_eq!(load(&mut virtio, QUEUE_NUM_MAX), QUEUE_SIZE as u64);
+
+        // Other queues don't exist
+        store(&mut virtio, QUEUE_SEL, 1);
+        assert_eq!(load(&mut virtio, QUEUE_NUM_MAX), 0);
+
+        // The configuration space takes any width, the registers only 32 bits
+        assert_eq!(virtio.read(CONFIG + 1, 2), Some(0x3322));
+        assert_eq!(virtio.read(CONFIG + 2, 4), Some(0x33));
+        assert_eq!(virtio.read(STATUS, 2), None);
+
+        // Legacy devices don't have VERSION_1
+        let (mut virtio, _, _) = transport(true);
+        assert_eq!(load(&mut virtio, VERSION), 1);
+        store(&mut virtio, DEVICE_FEATURES_SEL, 1);
+        assert_eq!(load(&mut virtio, DEVICE_FEATURES), 0);
+    }
+
+    #[test]
+    fn descriptor_chains() {
+        let (mut virtio, memory, chains) = modern();
+
+        // Header, data and status, then a request that's a single descriptor
+        descriptor(&memory, DESC, 0, DRAM_BASE + 0x4000, 16, DESC_F_NEXT, 5);
+        descriptor(&memory, DESC, 5, DRAM_BASE + 0x5000, 512, DESC_F_NEXT | DESC_F_WRITE, 2);
+        descriptor(&memory, DESC, 2, DRAM_BASE + 0x6000, 1, DESC_F_WRITE, 0);
+        descriptor(&memory, DESC, 3, DRAM_BASE + 0x7000, 8, 0, 0);
+        make_available(&memory, DRIVER, &[0, 3]);
+
+        store(&mut virtio, QUEUE_NOTIFY, 0);
+        assert_eq!(*chains.borrow(), vec![
+            vec![(DRAM_BASE + 0x4000, 16, false), (DRAM_BASE + 0x5000, 512, true), (DRAM_BASE + 0x6000, 1, true)],
+            vec![(DRAM_BASE + 0x7000, 8, false)]
+        ]);
+
+        // Both are in the used ring, with what the device wrote
+        assert_eq!(read_u16(&memory, DEVICE + 2), 2);
+        assert_eq!((read_u32(&memory, DEVICE + 4), read_u32(&memory, DEVICE + 8)), (0, 3));
+        assert_eq!((read_u32(&memory, DEVICE + 12), read_u32(&memory, DEVICE + 16)), (3, 3));
+
+        assert!(virtio.interrupt());
+        assert_eq!(load(&mut virtio, INTERRUPT_STATUS), INTERRUPT_USED_BUFFER as u64);
+        store(&mut virtio, INTERRUPT_ACK, INTERRUPT_USED_BUFFER as u64);
+        assert!(!virtio.interrupt());
+
+        // Nothing new, nothing to do
+        store(&mut virtio, QUEUE_NOTIFY, 0);
+        assert_eq!(chains.borrow().len(), 2);
+        assert!(!virtio.interrupt());
+    }
+
+    #[test]
+    fn broken_chains() {
+        let (mut virtio, memory, chains) = modern();
+
+        // A loop ends after as many descriptors as the queue has
+        descriptor(&memory, DESC, 0, DRAM_BASE, 1, DESC_F_NEXT, 1);
+        descriptor(&memory, DESC, 1, DRAM_BASE, 1, DESC_F_NEXT, 0);
+        // Indexes past the end of the table wrap around
+        descriptor(&memory, DESC, 4, DRAM_BASE + 4, 4, 0, 0);
+        make_available(&memory, DRIVER, &[0, 12]);
+
+        store(&mut virtio, QUEUE_NOTIFY, 0);
+        assert_eq!(chains.borrow()[0].len(), 8);
+        assert_eq!(chains.borrow()[1], vec![(DRAM_BASE + 4, 4, false)]);
+
+        // Queues outside of memory are ignored
+        store(&mut virtio, QUEUE_DRIVER_LOW, 0x1000);
+        store(&mut virtio, QUEUE_NOTIFY, 0);
+        assert_eq!(chains.borrow().len(), 2);
+    }
+
+    #[test]
+    fn legacy_queues() {
+        let (mut virtio, memory, chains) = transport(true);
+
+        // With the default alignment, the used ring is on the next page
+        store(&mut virtio, GUEST_PAGE_SIZE, 4096);
+        store(&mut virtio, QUEUE_NUM, 8);
+        store(&mut virtio, QUEUE_ALIGN, 0);
+        store(&mut virtio, QUEUE_PFN, DESC / 4096);
+        assert_eq!(load(&mut virtio, QUEUE_PFN), DESC / 4096);
+
+        descriptor(&memory, DESC, 0, DRAM_BASE, 1, 0, 0);
+        make_available(&memory, DESC + 16 * 8, &[0]);
+        store(&mut virtio, QUEUE_NOTIFY, 0);
+
+        assert_eq!(chains.borrow().len(), 1);
+        assert_eq!(read_u16(&memory, DESC + 0x1000 + 2), 1);
+
+        // Writing 0 to the status resets the queue
+        store(&mut virtio, STATUS, 0);
+        assert_eq!(load(&mut virtio, QUEUE_PFN), 0);
+    }
+}
@@ -0,0 +1,325 @@
+// virtio-blk device backed by a disk image on the host. Requests are a chain of descriptors:
+// a header, the data buffers, and a status byte the device writes.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use super::dram::Memory;
+use super::virtio::{Descriptor, VirtioDevice};
+
+pub const SECTOR_SIZE: u64 = 512;
+
+const DEVICE_ID_BLOCK: u32 = 2;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+// The serial number the guest gets with GET_ID, up to 20 bytes
+const DEVICE_SERIAL: &[u8] = b"riscvellina";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageMode {
+    ReadWrite,
+    // The guest sees a read-only disk
+    ReadOnly,
+    // The guest can write, but changes are kept in memory and the image is left untouched
+    CopyOnWrite
+}
+
+pub struct VirtioBlk {
+    file: File,
+    mode: ImageMode,
+    sectors: u64,
+    // Sectors written in copy-on-write mode
+    overlay: HashMap<u64, Vec<u8>>
+}
+
+impl VirtioBlk {
+    pub fn open(path: &str, mode: ImageMode) -> std::io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(mode == ImageMode::ReadWrite).open(path)?;
+        // A partial sector at the end of the image can't be accessed
+        let sectors = file.metadata()?.len() / SECTOR_SIZE;
+
+        Ok(Self { file, mode, sectors, overlay: HashMap::new() })
+    }
+
+    fn read_sector(&mut self, sector: u64, buffer: &mut [u8]) -> std::io::Result<()> {
+        if let Some(data) = self.overlay.get(&sector) {
+            buffer.copy_from_slice(data);
+            return Ok(());
+        }
+
+        self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        self.file.read_exact(buffer)
+    }
+
+    fn write_sector(&mut self, sector: u64, buffer: &[u8]) -> std::io::Result<()> {
+        if self.mode == ImageMode::CopyOnWrite {
+            self.overlay.insert(sector, buffer.to_vec());
+            return Ok(());
+        }
+
+        self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        self.file.write_all(buffer)
+    }
+
+    // Copies sectors starting at `sector` to the guest buffers, returns the number of bytes written
+    fn read_to_guest(&mut self, memory: &Memory, mut sector: u64, buffers: &[Descriptor]) -> Option<u32> {
+        let mut sector_data = vec![0; SECTOR_SIZE as usize];
+        let mut written = 0;
+
+        for buffer in buffers {
+            if !buffer.write || !(buffer.len as u64).is_multiple_of(SECTOR_SIZE) {
+                return None;
+            }
+
+            for i in 0..buffer.len as u64 / SECTOR_SIZE {
+                if sector >= self.sectors {
+                    return None;
+                }
+
+                self.read_sector(sector, &mut sector_data).ok()?;
+                if !memory.write(buffer.addr + i * SECTOR_SIZE, &sector_data) {
+                    return None;
+                }
+
+                sector += 1;
+                written += SECTOR_SIZE as u32;
+            }
+        }
+
+        Some(written)
+    }
+
+    fn write_from_guest(&mut self, memory: &Memory, mut sector: u64, buffers: &[Descriptor]) -> Option<()> {
+        let mut sector_data = vec![0; SECTOR_SIZE as usize];
+
+        for buffer in buffers {
+            if buffer.write || !(buffer.len as u64).is_multiple_of(SECTOR_SIZE) {
+                return None;
+            }
+
+            for i in 0..buffer.len as u64 / SECTOR_SIZE {
+                if sector >= self.sectors || !memory.read(buffer.addr + i * SECTOR_SIZE, &mut sector_data) {
+                    return None;
+                }
+
+                self.write_sector(sector, &sector_data).ok()?;
+                sector += 1;
+            }
+        }
+
+        Some(())
+    }
+}
+
+impl VirtioDevice for VirtioBlk {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        if self.mode == ImageMode::ReadOnly { VIRTIO_BLK_F_RO } else { 0 }
+    }
+
+    // Only the capacity, in 512-byte sectors
+    fn config(&self) -> Vec<u8> {
+        self.sectors.to_le_bytes().to_vec()
+    }
+
+    fn process(&mut self, memory: &Memory, chain: &[Descriptor]) -> u32 {
+        // At least a header and a status byte
+        let (header, status, buffers) = match chain {
+            [header, buffers @ .., status] if header.len >= 16 && status.write && status.len >= 1 =>
+                (header, status, buffers),
+            _ => return 0
+        };
+
+        let mut bytes = [0; 16];
+        if !memory.read(header.addr, &mut bytes) {
+            return 0;
+        }
+
+        let kind = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+
+        let (result, written) = match kind {
+            VIRTIO_BLK_T_IN => match self.read_to_guest(memory, sector, buffers) {
+                Some(written) => (VIRTIO_BLK_S_OK, written),
+                None => (VIRTIO_BLK_S_IOERR, 0)
+            },
+            VIRTIO_BLK_T_OUT if self.mode == ImageMode::ReadOnly => (VIRTIO_BLK_S_IOERR, 0),
+            VIRTIO_BLK_T_OUT => match self.write_from_guest(memory, sector, buffers) {
+                Some(()) => (VIRTIO_BLK_S_OK, 0),
+                None => (VIRTIO_BLK_S_IOERR, 0)
+            },
+            VIRTIO_BLK_T_FLUSH => match self.mode {
+                ImageMode::ReadWrite if self.file.sync_data().is_err() => (VIRTIO_BLK_S_IOERR, 0),
+                _ => (VIRTIO_BLK_S_OK, 0)
+            },
+            VIRTIO_BLK_T_GET_ID => match buffers.first() {
+                Some(buffer) if buffer.write => {
+                    let len = DEVICE_SERIAL.len().min(buffer.len as usize);
+                    if memory.write(buffer.addr, &DEVICE_SERIAL[..len]) {
+                        (VIRTIO_BLK_S_OK, len as u32)
+                    } else {
+                        (VIRTIO_BLK_S_IOERR, 0)
+                    }
+                }
+                _ => (VIRTIO_BLK_S_IOERR, 0)
+            },
+            _ => (VIRTIO_BLK_S_UNSUPP, 0)
+        };
+
+        memory.write(status.addr, &[result]);
+        written + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::dram::DRAM_BASE;
+
+    const HEADER: u64 = DRAM_BASE;
+    const DATA: u64 = DRAM_BASE + 0x1000;
+    const STATUS: u64 = DRAM_BASE + 0x2000;
+
+    // A 4-sector image where every byte of sector n is n
+    fn image(name: &str, mode: ImageMode) -> (VirtioBlk, String) {
+        let path = std::env::temp_dir().join(format!("riscvellina-{}-{}.img", name, std::process::id()));
+        let data: Vec<u8> = (0..4).flat_map(|sector| vec![sector; SECTOR_SIZE as usize]).collect();
+        std::fs::write(&path, data).unwrap();
+
+        let path = path.to_str().unwrap().to_string();
+        (VirtioBlk::open(&path, mode).unwrap(), path)
+    }
+
+    fn request(memory: &Memory, kind: u32, sector: u64, len: u32, write: bool) -> Vec<Descriptor> {
+        let mut header = [0; 16];
+        header[0..4].copy_from_slice(&kind.to_le_bytes());
+        header[8..16].copy_from_slice(&sector.to_le_bytes());
+        memory.write(HEADER, &header);
+        memory.write(STATUS, &[0xFF]);
+
+        vec![
+            Descriptor { addr: HEADER, len: 16, write: false },
+            Descriptor { addr: DATA, len, write },
+            Descriptor { addr: STATUS, len: 1, write: true }
+        ]
+    }
+
+    fn status(memory: &Memory) -> u8 {
+        let mut status = [0];
+        memory.read(STATUS, &mut status);
+        status[0]
+    }
+
+    fn data(memory: &Memory, len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        memory.read(DATA, &mut data);
+        data
+    }
+
+    #[test]
+    fn read() {
+        let (mut blk, path) = image("read", ImageMode::ReadOnly);
+        let memory = Memory::new(0x4000);
+        assert_eq!(blk.config(), 4u64.to_le_bytes());
+        assert_eq!(blk.features(), VIRTIO_BLK_F_RO);
+
+        let chain = request(&memory, VIRTIO_BLK_T_IN, 2, 1024, true);
+        assert_eq!(blk.process(&memory, &chain), 1025);
+        assert_eq!(status(&memory), VIRTIO_BLK_S_OK);
+        assert_eq!(data(&memory, 1024), [vec![2; 512], vec![3; 512]].concat());
+
+        // Past the end of the disk, or into a buffer the device can't write
+        let chain = request(&memory, VIRTIO_BLK_T_IN, 3, 1024, true);
+        assert_eq!(blk.process(&memory, &chain), 1);
+        assert_eq!(status(&memory), VIRTIO_BLK_S_IOERR);
+        let chain = request(&memory, VIRTIO_BLK_T_IN, 0, 512, false);
+        blk.process(&memory, &chain);
+        assert_eq!(status(&memory), VIRTIO_BLK_S_IOERR);
+
+        // Read-only means read-only
+        let chain = request(&memory, VIRTIO_BLK_T_OUT, 0, 512, false);
+        blk.process(&memory, &chain);
+        assert_eq!(status(&memory), VIRTIO_BLK_S_IOERR);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn write() {
+        let (mut blk, path) = image("write", ImageMode::ReadWrite);
+        let memory = Memory::new(0x4000);
+
+        memory.write(DATA, &[0xAB; 512]);
+        let chain = request(&memory, VIRTIO_BLK_T_OUT, 1, 512, false);
+        assert_eq!(blk.process(&memory, &chain), 1);
+        assert_eq!(status(&memory), VIRTIO_BLK_S_OK);
+
+        let chain = request(&memory, VIRTIO_BLK_T_FLUSH, 0, 0, false);
+        blk.process(&memory, &chain);
+        assert_eq!(status(&memory), VIRTIO_BLK_S_OK);
+        assert_eq!(std::fs::read(&path).unwrap()[512..1024], [0xAB; 512]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn copy_on_write() {
+        let (mut blk, path) = image("cow", ImageMode::CopyOnWrite);
+        let memory = Memory::new(0x4000);
+
+        memory.write(DATA, &[0xCD; 512]);
+        let chain = request(&memory, VIRTIO_BLK_T_OUT, 0, 512, false);
+        blk.process(&memory, &chain);
+        assert_eq!(status(&memory), VIRTIO_BLK_S_OK);
+
+        // The guest reads what it wrote, the image is untouched
+        memory.write(DATA, &[0; 1024]);
+        let chain = request(&memory, VIRTIO_BLK_T_IN, 0, 1024, true);
+        blk.process(&memory, &chain);
+        assert_eq!(data(&memory, 1024), [vec![0xCD; 512], vec![1; 512]].concat());
+        assert_eq!(std::fs::read(&path).unwrap()[..512], [0; 512]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn other_requests() {
+        let (mut blk, path) = image("other", ImageMode::ReadOnly);
+        let memory = Memory::new(0x4000);
+
+        let chain = request(&memory, VIRTIO_BLK_T_GET_ID, 0, 20, true);
+        assert_eq!(blk.process(&memory, &chain), DEVICE_SERIAL.len() as u32 + 1);
+        assert_eq!(data(&memory, DEVICE_SERIAL.len()), DEVICE_SERIAL);
+
+        let chain = request(&memory, 42, 0, 512, true);
+        blk.process(&memory, &chain);
+        assert_eq!(status(&memory), VIRTIO_BLK_S_UNSUPP);
+
+        // Without a status byte, or a whole header, there's nowhere to say it failed
+        let chain = request(&memory, VIRTIO_BLK_T_IN, 0, 512, true);
+        assert_eq!(blk.process(&memory, &chain[..1]), 0);
+        let short = [Descriptor { len: 8, ..chain[0] }, chain[2]];
+        assert_eq!(blk.process(&memory, &short), 0);
+        assert_eq!(status(&memory), 0xFF);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
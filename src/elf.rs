@@ -0,0 +1,379 @@
+// Just enough of ELF64 to load statically linked RISC-V programs: the header, the
+// program headers, and the symbol table for the debugger.
+
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind};
+
+const ELF_MAGIC: &[u8] = b"\x7FELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+
+const SHT_SYMTAB: u32 = 2;
+
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const SHN_UNDEF: u16 = 0;
+// Absolute values, common symbols... those aren't addresses in the program
+const SHN_LORESERVE: u16 = 0xFF00;
+
+// A PT_LOAD segment. Past the data from the file, up to mem_size, the segment is zeroed (BSS).
+pub struct Segment {
+    pub paddr: u64,
+    pub data: Vec<u8>,
+    pub mem_size: u64
+}
+
+// Functions and variables from .symtab
+pub struct Symbol {
+    pub name: String,
+    pub value: u64,
+    pub size: u64
+}
+
+pub struct Elf {
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+    pub symbols: Vec<Symbol>
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("ELF: {}", message))
+}
+
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(ELF_MAGIC)
+}
+
+// Little-endian fields, the header is checked before anything else is read
+fn field<const N: usize>(data: &[u8], offset: u64) -> Result<[u8; N], Error> {
+    let start: usize = offset.try_into().map_err(|_| invalid("truncated file"))?;
+
+    data.get(start..start.saturating_add(N))
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| invalid("truncated file"))
+}
+
+fn u16_at(data: &[u8], offset: u64) -> Result<u16, Error> {
+    field(data, offset).map(u16::from_le_bytes)
+}
+
+fn u32_at(data: &[u8], offset: u64) -> Result<u32, Error> {
+    field(data, offset).map(u32::from_le_bytes)
+}
+
+fn u64_at(data: &[u8], offset: u64) -> Result<u64, Error> {
+    field(data, offset).map(u64::from_le_bytes)
+}
+
+// Offset of an entry of a table. Where the table is and how big its entries are come from the
+// file, so this can overflow. Once the entry is known to start in the file, offsets of fields
+// inside it can't.
+fn table_entry(data: &[u8], base: u64, index: u64, size: u64) -> Result<u64, Error> {
+    index.checked_mul(size)
+        .and_then(|offset| offset.checked_add(base))
+        .filter(|&entry| entry < data.len() as u64)
+        .ok_or_else(|| invalid("table past the end of the file"))
+}
+
+impl Elf {
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        if !is_elf(data) {
+            return Err(invalid("not an ELF file"));
+        }
+
+        let [class, encoding] = field(data, 4)?;
+        if class != ELFCLASS64 {
+            return Err(invalid("not a 64-bit file"));
+        }
+        if encoding != ELFDATA2LSB {
+            return Err(invalid("not a little-endian file"));
+        }
+        if u16_at(data, 18)? != EM_RISCV {
+            return Err(invalid("not a RISC-V file"));
+        }
+
+        let entry = u64_at(data, 24)?;
+        let phoff = u64_at(data, 32)?;
+        let phentsize = u16_at(data, 54)? as u64;
+        let phnum = u16_at(data, 56)? as u64;
+
+        let mut segments = vec![];
+
+        for i in 0..phnum {
+            let header = table_entry(data, phoff, i, phentsize)?;
+            if u32_at(data, header)? != PT_LOAD {
+                continue;
+            }
+
+            let offset = u64_at(data, header + 8)?;
+            let paddr = u64_at(data, header + 24)?;
+            let file_size = u64_at(data, header + 32)?;
+            let mem_size = u64_at(data, header + 40)?;
+
+            if file_size > mem_size {
+                return Err(invalid("segment bigger in the file than in memory"));
+            }
+
+            let bytes = offset.checked_add(file_size)
+                .and_then(|end| data.get(offset as usize..end as usize))
+                .ok_or_else(|| invalid("segment past the end of the file"))?;
+
+            segments.push(Segment { paddr, data: bytes.to_vec(), mem_size });
+        }
+
+        let symbols = Self::parse_symbols(data)?;
+
+        Ok(Self { entry, segments, symbols })
+    }
+
+    // Stripped files don't have a symbol table, that's not an error
+    fn parse_symbols(data: &[u8]) -> Result<Vec<Symbol>, Error> {
+        let shoff = u64_at(data, 40)?;
+        let shentsize = u16_at(data, 58)? as u64;
+        let shnum = u16_at(data, 60)? as u64;
+
+        let section = |index: u64| table_entry(data, shoff, index, shentsize);
+
+        let symtab = (0..shnum).filter_map(|index| section(index).ok())
+            .find(|&header| u32_at(data, header + 4).ok() == Some(SHT_SYMTAB));
+
+        let symtab = match symtab {
+            Some(symtab) => symtab,
+            None => return Ok(vec![])
+        };
+
+        let offset = u64_at(data, symtab + 24)?;
+        let size = u64_at(data, symtab + 32)?;
+        let entsize = u64_at(data, symtab + 56)?.max(24);
+        // The names are in the string table the symbol table links to
+        let strtab = section(u32_at(data, symtab + 40)? as u64)?;
+        let strings = u64_at(data, strtab + 24)?;
+
+        let mut symbols = vec![];
+
+        for i in 0..size / entsize {
+            let entry = table_entry(data, offset, i, entsize)?;
+            let name = u32_at(data, entry)? as u64;
+            let [info] = field(data, entry + 4)?;
+            let shndx = u16_at(data, entry + 6)?;
+
+            // Labels without a type are kept, since that's all there is for assembly code
+            let kind = info & 0xF;
+            if !matches!(kind, STT_NOTYPE | STT_OBJECT | STT_FUNC) || shndx == SHN_UNDEF || shndx >= SHN_LORESERVE {
+                continue;
+            }
+
+            let name = strings.checked_add(name)
+                .and_then(|start| start.try_into().ok())
+                .and_then(|start: usize| data.get(start..))
+                .and_then(|bytes| bytes.split(|&byte| byte == 0).next())
+                .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+                .ok_or_else(|| invalid("symbol name past the end of the file"))?;
+
+            // Skip the mapping symbols ($x, $d) and the assembler's local labels
+            if name.is_empty() || name.starts_with('$') || name.starts_with(".L") {
+                continue;
+            }
+
+            symbols.push(Symbol { name, value: u64_at(data, entry + 8)?, size: u64_at(data, entry + 16)? });
+        }
+
+        Ok(symbols)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Program headers: type, offset, paddr, file size, memory size
+    type ProgramHeader = (u32, u64, u64, u64, u64);
+
+    // A RISC-V ELF64 file with its program headers right after the header, then `data`
+    fn elf(headers: &[ProgramHeader], data: &[u8]) -> Vec<u8> {
+        let mut file = vec![0; 64];
+        file[0..4].copy_from_slice(ELF_MAGIC);
+        file[4] = ELFCLASS64;
+        file[5] = ELFDATA2LSB;
+        file[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+        file[24..32].copy_from_slice(&0x80000000u64.to_le_bytes());
+        file[32..40].copy_from_slice(&64u64.to_le_bytes());
+        file[54..56].copy_from_slice(&56u16.to_le_bytes());
+        file[56..58].copy_from_slice(&(headers.len() as u16).to_le_bytes());
+
+        for &(kind, offset, paddr, file_size, mem_size) in headers {
+            let mut header = vec![0; 56];
+            header[0..4].copy_from_slice(&kind.to_le_bytes());
+            header[8..16].copy_from_slice(&offset.to_le_bytes());
+            header[24..32].copy_from_slice(&paddr.to_le_bytes());
+            header[32..40].copy_from_slice(&file_size.to_le_bytes());
+            header[40..48].copy_from_slice(&mem_size.to_le_bytes());
+            file.extend(header);
+        }
+
+        file.extend(data);
+        file
+    }
+
+    // Symbols: name, value, size, info, section index
+    type Sym = (&'static str, u64, u64, u8, u16);
+
+    // Appends a string table, a symbol table and the section headers (null, .symtab, .strtab)
+    fn with_symbols(mut file: Vec<u8>, symbols: &[Sym]) -> Vec<u8> {
+        let strtab = file.len() as u64;
+        let mut names = vec![0];
+        let mut entries = vec![0; 24];
+
+        for &(name, value, size, info, shndx) in symbols {
+            let mut entry = vec![0; 24];
+            entry[0..4].copy_from_slice(&(names.len() as u32).to_le_bytes());
+            entry[4] = info;
+            entry[6..8].copy_from_slice(&shndx.to_le_bytes());
+            entry[8..16].copy_from_slice(&value.to_le_bytes());
+            entry[16..24].copy_from_slice(&size.to_le_bytes());
+            entries.extend(entry);
+
+            names.extend(name.as_bytes());
+            names.push(0);
+        }
+
+        file.extend(&names);
+        let symtab = file.len() as u64;
+        file.extend(&entries);
+        let shoff = file.len() as u64;
+
+        file.extend(vec![0; 64]);
+        let mut section = vec![0; 64];
+        section[4..8].copy_from_slice(&SHT_SYMTAB.to_le_bytes());
+        section[24..32].copy_from_slice(&symtab.to_le_bytes());
+        section[32..40].copy_from_slice(&(entries.len() as u64).to_le_bytes());
+        section[40..44].copy_from_slice(&2u32.to_le_bytes());
+        section[56..64].copy_from_slice(&24u64.to_le_bytes());
+        file.extend(section);
+        let mut section = vec![0; 64];
+        section[4..8].copy_from_slice(&3u32.to_le_bytes());
+        section[24..32].copy_from_slice(&strtab.to_le_bytes());
+        file.extend(section);
+
+        file[40..48].copy_from_slice(&shoff.to_le_bytes());
+        file[58..60].copy_from_slice(&64u16.to_le_bytes());
+        file[60..62].copy_from_slice(&3u16.to_le_bytes());
+        file
+    }
+
+    fn error(data: &[u8]) -> String {
+        Elf::parse(data).err().expect("parsed a broken file").to_string()
+    }
+
+    #[test]
+    fn segments() {
+        // The data is right after two program headers
+        let file = elf(&[(PT_LOAD, 176, 0x80000000, 4, 0x10), (6, 0, 0, 0, 0)], b"abcd");
+        let elf = Elf::parse(&file).unwrap();
+
+        assert_eq!(elf.entry, 0x80000000);
+        assert_eq!(elf.segments.len(), 1);
+        assert_eq!((elf.segments[0].paddr, elf.segments[0].mem_size), (0x80000000, 0x10));
+        assert_eq!(elf.segments[0].data, b"abcd");
+    }
+
+    #[test]
+    fn wrong_files() {
+        let file = elf(&[], &[]);
+
+        assert!(error(b"#!/bin/sh").contains("not an ELF file"));
+        let mut other = file.clone();
+        other[4] = 1;
+        assert!(error(&other).contains("64-bit"));
+        let mut other = file.clone();
+        other[5] = 2;
+        assert!(error(&other).contains("little-endian"));
+        let mut other = file.clone();
+        other[18] = 62;
+        assert!(error(&other).contains("RISC-V"));
+    }
+
+    #[test]
+    fn malformed_files() {
+        let file = elf(&[(PT_LOAD, 120, 0x80000000, 4, 4)], b"abcd");
+        assert!(Elf::parse(&file).is_ok());
+
+        assert!(error(&file[..30]).contains("truncated"));
+        assert!(error(&file[..100]).contains("truncated"));
+        assert!(error(&elf(&[(PT_LOAD, 120, 0x80000000, 8, 8)], b"abcd")).contains("past the end"));
+        assert!(error(&elf(&[(PT_LOAD, u64::MAX, 0x80000000, 8, 8)], &[])).contains("past the end"));
+        assert!(error(&elf(&[(PT_LOAD, 120, 0x80000000, 4, 2)], b"abcd")).contains("bigger"));
+
+        // Program headers that would be past the end of the address space
+        let mut other = file.clone();
+        other[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(error(&other).contains("past the end"));
+        other[32..40].copy_from_slice(&64u64.to_le_bytes());
+        other[54..56].copy_from_slice(&u16::MAX.to_le_bytes());
+        other[56..58].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(Elf::parse(&other).is_err());
+    }
+
+    #[test]
+    fn symbols() {
+        let file = with_symbols(elf(&[], &[]), &[
+            ("main", 0x80000100, 0x40, STT_FUNC | 0x10, 1),
+            ("buffer", 0x80001000, 0x100, STT_OBJECT, 2),
+            ("loop", 0x80000120, 0, STT_NOTYPE, 1),
+            // Sections, undefined and absolute symbols, and what the assembler adds
+            (".text", 0x80000000, 0, 3, 1),
+            ("printf", 0, 0, STT_FUNC, SHN_UNDEF),
+            ("SIZE", 0x10, 0, STT_NOTYPE, 0xFFF1),
+            ("$x", 0x80000000, 0, STT_NOTYPE, 1),
+            (".L1", 0x80000104, 0, STT_NOTYPE, 1),
+            ("", 0x80000000, 0, STT_NOTYPE, 1)
+        ]);
+
+        let symbols: Vec<_> = Elf::parse(&file).unwrap().symbols.into_iter()
+            .map(|symbol| (symbol.name, symbol.value, symbol.size))
+            .collect();
+
+        assert_eq!(symbols, vec![
+            ("main".to_string(), 0x80000100, 0x40),
+            ("buffer".to_string(), 0x80001000, 0x100),
+            ("loop".to_string(), 0x80000120, 0)
+        ]);
+
+        // Stripped files are fine
+        assert!(Elf::parse(&elf(&[], &[])).unwrap().symbols.is_empty());
+    }
+
+    #[test]
+    fn malformed_symbols() {
+        let file = with_symbols(elf(&[], &[]), &[("main", 0x80000100, 0x40, STT_FUNC, 1)]);
+        let shoff = u64_at(&file, 40).unwrap() as usize;
+        let symtab = shoff + 64;
+
+        // A name past the end of the file
+        let mut other = file.clone();
+        let name = u64_at(&file, symtab as u64 + 24).unwrap() as usize + 24;
+        other[name..name + 4].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+        assert!(error(&other).contains("past the end"));
+
+        // A link to a string table that doesn't exist
+        let mut other = file.clone();
+        other[symtab + 40..symtab + 44].copy_from_slice(&7u32.to_le_bytes());
+        assert!(error(&other).contains("past the end"));
+
+        // Symbols past the end of the file, or past the end of the address space
+        let mut other = file.clone();
+        other[symtab + 32..symtab + 40].copy_from_slice(&0x1000u64.to_le_bytes());
+        assert!(error(&other).contains("past the end"));
+        other[symtab + 24..symtab + 32].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(error(&other).contains("past the end"));
+
+        // Section headers that aren't there are skipped
+        let mut other = file.clone();
+        other[40..48].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(Elf::parse(&other).unwrap().symbols.is_empty());
+    }
+}
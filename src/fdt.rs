@@ -0,0 +1,302 @@
+// Flattened device tree, describing the machine to the software running on it (OpenSBI, Linux).
+// It's generated from what's actually on the bus.
+
+use std::collections::HashMap;
+use crate::bus::Bus;
+use crate::devices::uart;
+
+const FDT_MAGIC: u32 = 0xD00DFEED;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+const HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+// Devices refer to each other through those
+pub const CPU_INTC_PHANDLE: u32 = 1;
+pub const PLIC_PHANDLE: u32 = 2;
+pub const SYSCON_PHANDLE: u32 = 3;
+
+// mtime goes up by one at each instruction, that's roughly QEMU's 10 MHz
+pub const TIMEBASE_FREQUENCY: u32 = 10000000;
+
+pub struct Fdt {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    // Offsets of the property names already in the strings block
+    names: HashMap<&'static str, u32>
+}
+
+impl Fdt {
+    pub fn new() -> Self {
+        Self { structure: vec![], strings: vec![], names: HashMap::new() }
+    }
+
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+
+    // Everything in the structure block is aligned on 4 bytes
+    fn pad(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.pad();
+    }
+
+    pub fn end_node(&mut self) {
+        self.token(FDT_END_NODE);
+    }
+
+    pub fn property(&mut self, name: &'static str, value: &[u8]) {
+        let strings = &mut self.strings;
+        let offset = *self.names.entry(name).or_insert_with(|| {
+            let offset = strings.len() as u32;
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+            offset
+        });
+
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(offset);
+        self.structure.extend_from_slice(value);
+        self.pad();
+    }
+
+    pub fn property_empty(&mut self, name: &'static str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &'static str, value: u32) {
+        self.property_cells(name, &[value]);
+    }
+
+    pub fn property_cells(&mut self, name: &'static str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    // A string list, like compatible
+    pub fn property_strings(&mut self, name: &'static str, strings: &[&str]) {
+        let value: Vec<u8> = strings.iter().flat_map(|string| string.bytes().chain(std::iter::once(0))).collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_string(&mut self, name: &'static str, string: &str) {
+        self.property_strings(name, &[string]);
+    }
+
+    // Addresses and sizes are two cells each
+    pub fn property_reg(&mut self, base: u64, size: u64) {
+        self.property_cells("reg", &[(base >> 32) as u32, base as u32, (size >> 32) as u32, size as u32]);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.token(FDT_END);
+
+        // Header, then an empty memory reservation map, the structure and the strings
+        let reservations = HEADER_SIZE;
+        let structure = reservations + 16;
+        let strings = structure + self.structure.len();
+        let total = strings + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total as u32,
+            structure as u32,
+            strings as u32,
+            reservations as u32,
+            FDT_VERSION,
+            FDT_LAST_COMPATIBLE_VERSION,
+            0, // Boot CPU
+            self.strings.len() as u32,
+            self.structure.len() as u32
+        ];
+
+        let mut blob: Vec<u8> = header.iter().flat_map(|field| field.to_be_bytes()).collect();
+        blob.extend_from_slice(&[0; 16]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+
+        blob
+    }
+}
+
+impl Default for Fdt {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The whole tree for the machine, with a single hart. initrd is where the initramfs is, if there's one.
+pub fn generate(bus: &Bus, isa: &str, initrd: Option<(u64, u64)>) -> Vec<u8> {
+    let mut fdt = Fdt::new();
+
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "riscv-virtio");
+    fdt.property_string("model", "riscvellina");
+
+    fdt.begin_node("chosen");
+    fdt.property_string("stdout-path", &format!("/soc/serial@{:x}", uart::UART_BASE));
+    if let Some((start, end)) = initrd {
+        fdt.property_cells("linux,initrd-start", &[(start >> 32) as u32, start as u32]);
+        fdt.property_cells("linux,initrd-end", &[(end >> 32) as u32, end as u32]);
+    }
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY);
+
+    fdt.begin_node("cpu@0");
+    fdt.property_string("device_type", "cpu");
+    fdt.property_u32("reg", 0);
+    fdt.property_string("status", "okay");
+    fdt.property_string("compatible", "riscv");
+    fdt.property_string("riscv,isa", isa);
+    fdt.property_string("mmu-type", "riscv,sv57");
+
+    fdt.begin_node("interrupt-controller");
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_empty("interrupt-controller");
+    fdt.property_string("compatible", "riscv,cpu-intc");
+    fdt.property_u32("phandle", CPU_INTC_PHANDLE);
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.end_node();
+
+    // Memory and devices
+    bus.device_tree(&mut fdt);
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn be32(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn c_string(bytes: &[u8]) -> String {
+        String::from_utf8(bytes.split(|&byte| byte == 0).next().unwrap().to_vec()).unwrap()
+    }
+
+    // Reads the blob back as a list of properties with the full path of their node
+    fn properties(blob: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let structure = be32(blob, 8) as usize;
+        let strings = be32(blob, 12) as usize;
+
+        let mut path: Vec<String> = vec![];
+        let mut properties = vec![];
+        let mut offset = structure;
+
+        loop {
+            let token = be32(blob, offset);
+            offset += 4;
+
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_string(&blob[offset..]);
+                    offset += (name.len() + 1).next_multiple_of(4);
+                    path.push(name);
+                }
+                FDT_END_NODE => { path.pop(); }
+                FDT_PROP => {
+                    let len = be32(blob, offset) as usize;
+                    let name = c_string(&blob[strings + be32(blob, offset + 4) as usize..]);
+                    let value = blob[offset + 8..offset + 8 + len].to_vec();
+                    offset += (8 + len).next_multiple_of(4);
+
+                    properties.push((format!("{}/{}", path.join("/"), name), value));
+                }
+                FDT_END => break,
+                _ => panic!("bad token {:x} at {:x}", token, offset - 4)
+            }
+        }
+
+        assert!(path.is_empty());
+        properties
+    }
+
+    fn property(properties: &[(String, Vec<u8>)], path: &str) -> Option<Vec<u8>> {
+        properties.iter().find(|(name, _)| name == path).map(|(_, value)| value.clone())
+    }
+
+    #[test]
+    fn blob() {
+        let mut fdt = Fdt::new();
+        fdt.begin_node("");
+        fdt.property_u32("#size-cells", 2);
+        fdt.begin_node("node@1");
+        fdt.property_string("compatible", "abc");
+        fdt.property_reg(0x1_2345_6789, 0x10);
+        fdt.property_u32("#size-cells", 1);
+        fdt.end_node();
+        fdt.end_node();
+        let blob = fdt.finish();
+
+        assert_eq!(be32(&blob, 0), FDT_MAGIC);
+        assert_eq!(be32(&blob, 4) as usize, blob.len());
+        assert_eq!((be32(&blob, 20), be32(&blob, 24)), (17, 16));
+        // An empty memory reservation map
+        assert_eq!(be32(&blob, 16), HEADER_SIZE as u32);
+        assert_eq!(blob[HEADER_SIZE..HEADER_SIZE + 16], [0; 16]);
+
+        // Names are only in the strings block once
+        let strings = be32(&blob, 12) as usize;
+        assert_eq!(&blob[strings..], b"#size-cells\0compatible\0reg\0");
+        assert_eq!(be32(&blob, 32) as usize, blob.len() - strings);
+
+        let properties = properties(&blob);
+        assert_eq!(property(&properties, "/#size-cells"), Some(vec![0, 0, 0, 2]));
+        assert_eq!(property(&properties, "/node@1/compatible"), Some(b"abc\0".to_vec()));
+        assert_eq!(property(&properties, "/node@1/reg"), Some(vec![0, 0, 0, 1, 0x23, 0x45, 0x67, 0x89, 0, 0, 0, 0, 0, 0, 0, 0x10]));
+        assert_eq!(property(&properties, "/node@1/#size-cells"), Some(vec![0, 0, 0, 1]));
+    }
+
+    #[test]
+    fn machine() {
+        let bus = Bus::new(0x100000);
+        let properties = properties(&generate(&bus, "rv64imac", None));
+
+        assert_eq!(property(&properties, "/cpus/cpu@0/riscv,isa"), Some(b"rv64imac\0".to_vec()));
+        assert_eq!(property(&properties, "/memory@80000000/reg"), Some(vec![0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0x10, 0, 0]));
+        assert_eq!(property(&properties, "/chosen/stdout-path"), Some(b"/soc/serial@10000000\0".to_vec()));
+
+        // Everything on the bus is there, with its interrupt
+        assert!(property(&properties, "/soc/clint@2000000/reg").is_some());
+        assert!(property(&properties, "/soc/plic@c000000/reg").is_some());
+        assert!(property(&properties, "/soc/test@100000/reg").is_some());
+        assert_eq!(property(&properties, "/soc/serial@10000000/interrupts"), Some(vec![0, 0, 0, 10]));
+        assert_eq!(property(&properties, "/soc/serial@10000000/interrupt-parent"),
+            Some(PLIC_PHANDLE.to_be_bytes().to_vec()));
+        assert_eq!(property(&properties, "/chosen/linux,initrd-start"), None);
+    }
+
+    #[test]
+    fn initrd() {
+        let bus = Bus::new(0x100000);
+        let properties = properties(&generate(&bus, "rv64imac", Some((0x8800_0000, 0x8812_3456))));
+
+        assert_eq!(property(&properties, "/chosen/linux,initrd-start"), Some(vec![0, 0, 0, 0, 0x88, 0, 0, 0]));
+        assert_eq!(property(&properties, "/chosen/linux,initrd-end"), Some(vec![0, 0, 0, 0, 0x88, 0x12, 0x34, 0x56]));
+    }
+}
@@ -1,28 +1,150 @@
 mod cpu;
 mod bus;
+mod trap;
 mod debug;
+mod devices;
+mod elf;
+mod fdt;
 
 use std::fs::File;
 use std::env::args;
+use std::path::Path;
 use crate::cpu::CPU;
+use crate::debug::gdb::GdbStub;
+use crate::debug::monitor::Monitor;
+use crate::debug::trace::{TraceFormat, TraceLevel, Tracer};
+use crate::devices::terminal::Terminal;
+use crate::devices::virtio_blk::{ImageMode, VirtioBlk};
 
+// Where raw kernels and initrds go by default. 0x80200000 is where OpenSBI's fw_jump jumps.
+const KERNEL_ADDR: u64 = 0x80200000;
+const INITRD_ADDR: u64 = 0x84000000;
+
+fn parse_addr(option: &str, value: Option<String>) -> u64 {
+    value.and_then(|value| u64::from_str_radix(value.trim_start_matches("0x"), 16).ok())
+        .unwrap_or_else(|| panic!("{} needs a hexadecimal address", option))
+}
+
+// A range of addresses, start:end in hexadecimal
+fn parse_range(option: &str, value: Option<String>) -> (u64, u64) {
+    let value = value.unwrap_or_else(|| panic!("{} needs a range, start:end", option));
+    let (start, end) = value.split_once(':').unwrap_or_else(|| panic!("{} needs a range, start:end", option));
+
+    (parse_addr(option, Some(start.to_string())), parse_addr(option, Some(end.to_string())))
+}
+
+// riscvellina [--bios firmware] [--kernel file] [--kernel-addr addr] [--initrd file] [--initrd-addr addr]
+//             [--drive image] [--drive-mode rw|ro|cow] [--virtio-legacy] [--dump-dtb file]
+//             [--gdb port|socket] [--monitor] [--trace off|instructions|registers|memory]
+//             [--trace-format text|spike] [--trace-range start:end] [--trace-file file] [bin-file]
 fn main() -> std::io::Result<()> {
-    let args: Vec<String> = args().collect();
+    let mut program = None;
+    let mut kernel = None;
+    let mut kernel_addr = KERNEL_ADDR;
+    let mut initrd = None;
+    let mut initrd_addr = INITRD_ADDR;
+    let mut drive = None;
+    let mut drive_mode = ImageMode::ReadWrite;
+    let mut legacy = false;
+    let mut dump_dtb = None;
+    let mut gdb = None;
+    let mut monitor = false;
+    let mut trace = None;
+    let mut trace_format = None;
+    let mut trace_range = None;
+    let mut trace_file = None;
+
+    let mut args = args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--drive" => drive = Some(args.next().expect("--drive needs an image file")),
+            "--drive-mode" => drive_mode = match args.next().as_deref() {
+                Some("rw") => ImageMode::ReadWrite,
+                Some("ro") => ImageMode::ReadOnly,
+                Some("cow") => ImageMode::CopyOnWrite,
+                _ => panic!("--drive-mode is rw, ro or cow")
+            },
+            "--virtio-legacy" => legacy = true,
+            // The firmware is just the program the ROM jumps to
+            "--bios" => program = Some(args.next().expect("--bios needs a firmware file")),
+            "--kernel" => kernel = Some(args.next().expect("--kernel needs a file")),
+            "--kernel-addr" => kernel_addr = parse_addr(&arg, args.next()),
+            "--initrd" => initrd = Some(args.next().expect("--initrd needs a file")),
+            "--initrd-addr" => initrd_addr = parse_addr(&arg, args.next()),
+            "--dump-dtb" => dump_dtb = Some(args.next().expect("--dump-dtb needs a file")),
+            "--gdb" => gdb = Some(args.next().expect("--gdb needs a port or a socket path")),
+            "--monitor" => monitor = true,
+            "--trace" => trace = Some(args.next().as_deref().and_then(TraceLevel::parse)
+                .expect("--trace is off, instructions, registers or memory")),
+            "--trace-format" => trace_format = Some(args.next().as_deref().and_then(TraceFormat::parse)
+                .expect("--trace-format is text or spike")),
+            "--trace-range" => trace_range = Some(parse_range(&arg, args.next())),
+            "--trace-file" => trace_file = Some(args.next().expect("--trace-file needs a file")),
+            _ => program = Some(arg)
+        }
+    }
 
-    if args.len() < 2 {
+    if program.is_none() && kernel.is_none() {
         panic!("A file is required for loading.");
     }
 
     let mut cpu = CPU::new();
-    let file = File::open(&args[1])?;
+    // Asking for a format is enough to turn the trace on
+    let trace = trace.unwrap_or(if trace_format.is_some() { TraceLevel::Instructions } else { TraceLevel::Off });
+    let trace_format = trace_format.unwrap_or(TraceFormat::Text);
+    cpu.set_tracer(Tracer::new(trace, trace_format, trace_range, trace_file.as_deref())?);
+
+    if let Some(program) = program {
+        cpu.load_code(File::open(&program)?)?;
+
+        // A raw binary doesn't have symbols, but the ELF file it was made from might be next to it
+        let elf = Path::new(&program).with_extension("elf");
+        if !cpu.has_symbols() && program.ends_with(".bin") && elf.exists() {
+            cpu.load_symbols(File::open(elf)?)?;
+        }
+    }
+
+    if let Some(kernel) = kernel {
+        cpu.load_kernel(File::open(kernel)?, kernel_addr)?;
+    }
+
+    if let Some(initrd) = initrd {
+        cpu.load_initrd(File::open(initrd)?, initrd_addr)?;
+    }
 
-    cpu.load_code(file)?;
+    if let Some(drive) = drive {
+        let disk = VirtioBlk::open(&drive, drive_mode)?;
+        cpu.attach_disk(disk, legacy).unwrap();
+    }
 
-    while !cpu.halt {
-        cpu.run_instr();
+    // Once every device is on the bus
+    let dtb = cpu.prepare_boot();
+    if let Some(dump_dtb) = dump_dtb {
+        std::fs::write(dump_dtb, dtb)?;
     }
 
+    // Before the first instruction runs
+    let gdb = match gdb {
+        Some(address) => Some(GdbStub::listen(&address)?),
+        None => None
+    };
+
+    // If GDB detaches, or goes away, the program keeps running
+    let exit_code = gdb.and_then(|mut gdb| {
+        let _terminal = Terminal::raw();
+
+        gdb.run(&mut cpu).unwrap_or_else(|error| {
+            eprintln!("GDB connection lost: {}", error);
+            None
+        })
+    });
+
+    // Runs until the program powers the machine off through the syscon device, the monitor
+    // stops it on Ctrl-C
+    let exit_code = exit_code.unwrap_or_else(|| Monitor::new(monitor).run(&mut cpu));
+
+    cpu.flush_trace()?;
     println!("{:?}", cpu);
 
-    Ok(())
+    std::process::exit(exit_code);
 }
@@ -0,0 +1,59 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionAddressMisaligned(u64),
+    InstructionAccessFault(u64),
+    IllegalInstruction(u32),
+    Breakpoint(u64),
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAddressMisaligned(u64),
+    StoreAccessFault(u64),
+    EnvironmentCallFromUMode,
+    EnvironmentCallFromSMode,
+    EnvironmentCallFromMMode,
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StorePageFault(u64)
+}
+
+impl Exception {
+    // Value written in xcause
+    pub fn code(&self) -> u64 {
+        match self {
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint(_) => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAddressMisaligned(_) => 6,
+            Exception::StoreAccessFault(_) => 7,
+            Exception::EnvironmentCallFromUMode => 8,
+            Exception::EnvironmentCallFromSMode => 9,
+            Exception::EnvironmentCallFromMMode => 11,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StorePageFault(_) => 15
+        }
+    }
+
+    // Value written in xtval
+    pub fn value(&self) -> u64 {
+        match *self {
+            Exception::InstructionAddressMisaligned(addr) |
+            Exception::InstructionAccessFault(addr) |
+            Exception::Breakpoint(addr) |
+            Exception::LoadAddressMisaligned(addr) |
+            Exception::LoadAccessFault(addr) |
+            Exception::StoreAddressMisaligned(addr) |
+            Exception::StoreAccessFault(addr) |
+            Exception::InstructionPageFault(addr) |
+            Exception::LoadPageFault(addr) |
+            Exception::StorePageFault(addr) => addr,
+            Exception::IllegalInstruction(instr) => instr as u64,
+            Exception::EnvironmentCallFromUMode |
+            Exception::EnvironmentCallFromSMode |
+            Exception::EnvironmentCallFromMMode => 0
+        }
+    }
+}